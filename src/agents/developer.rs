@@ -1,43 +1,59 @@
+use crate::config::AgentSettings;
+use crate::edits::EditSet;
+use crate::llm::{AgentRole, ChatRequest, LlmBackend};
 use crate::prompts::Prompts;
 use anyhow::{Context, Result};
-use async_openai::{
-    types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
-        ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessage,
-        ChatCompletionRequestUserMessageContent, CreateChatCompletionRequestArgs,
-    },
-    Client,
-};
+use std::path::{Path, PathBuf};
 
 pub struct DeveloperAgent<'a> {
-    pub model: &'a str,
-    pub client: Client<async_openai::config::OpenAIConfig>,
+    pub settings: &'a AgentSettings,
+    pub llm: &'a dyn LlmBackend,
+    pub system_prompt: &'a str,
 }
 
 impl<'a> DeveloperAgent<'a> {
-    pub fn new(model: &'a str) -> Self {
+    /// `system_prompt` replaces [`Prompts::developer`] when set (`[prompts] developer` in `mantra.toml`).
+    pub fn new(
+        llm: &'a dyn LlmBackend,
+        settings: &'a AgentSettings,
+        system_prompt: Option<&'a str>,
+    ) -> Self {
         Self {
-            model,
-            client: Client::new(),
+            settings,
+            llm,
+            system_prompt: system_prompt.unwrap_or(Prompts::developer()),
         }
     }
 
-    /// Produce **entire updated file** using the Dev prompt.
+    /// Produce the **entire updated content** of every file the refactor touches.
+    /// `related` holds the current text of other files that use the target's items;
+    /// only those can be edited besides the target.
     pub async fn generate(
         &self,
-        original_file_path: &str,
+        original_file_path: &Path,
         original_file_content: &str,
+        related: &[(PathBuf, String)],
         analysis: &str,
         fewshot: &[String],
         refactor_prompt: &str,
-    ) -> Result<String> {
-        let system = Prompts::developer();
+    ) -> Result<EditSet> {
+        let system = self.system_prompt;
         let fewshot_blob = if fewshot.is_empty() {
             String::new()
         } else {
             format!("\n\n### Few-shot examples:\n{}", fewshot.join("\n\n---\n"))
         };
 
+        let related_blob = if related.is_empty() {
+            "<none>".to_string()
+        } else {
+            related
+                .iter()
+                .map(|(path, text)| format!("```rust file={}\n{text}\n```", path.display()))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        };
+
         let user_input = format!(
             r#"### Context
 
@@ -51,47 +67,35 @@ impl<'a> DeveloperAgent<'a> {
 {code}
 ```
 
+### Other files using items of the target (current content)
+
+{related}
+
 ### Refactoring Request
 
 {req}
 
 {few}
 "#,
-            path = original_file_path,
+            path = original_file_path.display(),
             analysis = analysis,
             code = original_file_content,
+            related = related_blob,
             req = refactor_prompt,
             few = fewshot_blob
         );
 
-        let req = CreateChatCompletionRequestArgs::default()
-            .model(self.model)
-            .messages([
-                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                    content: ChatCompletionRequestSystemMessageContent::Text(system.to_string()),
-                    name: None,
-                }),
-                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                    content: ChatCompletionRequestUserMessageContent::Text(user_input),
-                    name: None,
-                }),
-            ])
-            .temperature(0.0)
-            .build()?;
-
-        let resp = self.client.chat().create(req).await?;
-        let content = resp.choices[0].message.content.clone().unwrap_or_default();
-        let file = crate::agents::extract_rust_block(&content)
-            .context("DeveloperAgent: no Rust code block found in response")?;
-        Ok(file)
+        let content = self
+            .llm
+            .complete(&ChatRequest {
+                role: AgentRole::Developer,
+                settings: self.settings.clone(),
+                system: system.to_string(),
+                user: user_input,
+                schema: None,
+            })
+            .await?;
+        EditSet::parse(&content, original_file_path)
+            .context("DeveloperAgent: could not read file edits from response")
     }
 }
-
-/// Utility shared by agents to pull the `rust ...` block.
-pub fn extract_rust_block(s: &str) -> Option<String> {
-    let fence = "`rust";
-    let start = s.find(fence)?;
-    let rest = &s[start + fence.len()..];
-    let end = rest.find("`")?;
-    Some(rest[..end].trim_start_matches('\n').trim().to_string())
-}
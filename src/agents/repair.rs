@@ -1,72 +1,291 @@
+use crate::config::AgentSettings;
+use crate::edits::{Changes, EditSet};
+use crate::llm::{AgentRole, ChatRequest, LlmBackend};
 use crate::prompts::Prompts;
+use crate::verification::BuildEvidence;
 use anyhow::{Context, Result};
-use async_openai::{
-    types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
-        ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessage,
-        ChatCompletionRequestUserMessageContent, CreateChatCompletionRequestArgs,
-    },
-    Client,
-};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{cmp::Reverse, collections::BTreeMap, fmt, path::{Path, PathBuf}};
+
+/// Lines of an attempt's diff kept in the memory shown to the agent.
+const MAX_DIFF_LINES: usize = 60;
+
+/// Edits proposed by the Repair agent, with its own account of them.
+pub struct RepairAnswer {
+    pub edits: EditSet,
+    /// What the agent says it found and changed (the prose before its first block).
+    pub reflection: String,
+}
+
+/// How close a failing candidate came to passing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AttemptScore {
+    pub builds: bool,
+    pub errors: usize,
+    /// Warnings in the touched files.
+    pub warnings: usize,
+    pub tests_passed: usize,
+    pub tests_failed: usize,
+    /// Refactored functions that behave differently from the original.
+    pub divergent: usize,
+}
+
+impl AttemptScore {
+    pub fn of(evidence: &BuildEvidence) -> Self {
+        let errors = evidence.diagnostics.iter().filter(|d| d.level == "error").count();
+        let tests = evidence.tests.as_ref();
+        Self {
+            builds: evidence.check_ok,
+            errors,
+            warnings: evidence.diagnostics.len() - errors,
+            tests_passed: tests.map_or(0, |t| t.num_passed),
+            tests_failed: tests.map_or(0, |t| t.num_failed),
+            divergent: evidence.divergences.len(),
+        }
+    }
+
+    /// Smaller is better: a build beats none, then fewer errors, fewer failing
+    /// tests, fewer divergent functions, more passing tests and fewer warnings.
+    fn key(&self) -> (bool, usize, usize, usize, Reverse<usize>, usize) {
+        (
+            !self.builds,
+            self.errors,
+            self.tests_failed,
+            self.divergent,
+            Reverse(self.tests_passed),
+            self.warnings,
+        )
+    }
+}
+
+impl fmt::Display for AttemptScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.builds {
+            write!(
+                f,
+                "builds, {} test(s) passed, {} failed, ",
+                self.tests_passed, self.tests_failed
+            )?;
+            if self.divergent > 0 {
+                write!(f, "{} divergent function(s), ", self.divergent)?;
+            }
+            write!(f, "{} warning(s)", self.warnings)
+        } else {
+            write!(
+                f,
+                "{} error(s), {} warning(s), tests not run",
+                self.errors, self.warnings
+            )
+        }
+    }
+}
+
+/// A candidate in the repair loop that did not pass: the one that entered the
+/// loop (number 0) or a repair attempt.
+#[derive(Debug, Clone, Serialize)]
+pub struct RepairAttempt {
+    pub number: u32,
+    pub reflection: String,
+    /// The attempt's edits against the candidate it started from.
+    pub diff: String,
+    /// Errors and test failures the attempt left behind.
+    pub failures: Vec<String>,
+    pub score: AttemptScore,
+    /// Content of the touched files, so the attempt can be restored.
+    #[serde(skip)]
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+/// What the repair loop has already tried. Every repair prompt includes it, and
+/// it notices when an attempt comes back to a candidate that was already built.
+#[derive(Debug, Default)]
+pub struct RepairMemory {
+    attempts: Vec<RepairAttempt>,
+    /// Fingerprints of the candidates built so far; the first is the one that
+    /// entered the loop, then one per attempt.
+    seen: Vec<[u8; 32]>,
+}
+
+impl RepairMemory {
+    /// Remember `files` as a built candidate. If an identical one was built
+    /// before, returns its position instead (0 = the candidate that entered the loop).
+    pub fn revisit(&mut self, files: &BTreeMap<PathBuf, String>) -> Option<usize> {
+        let mut hasher = Sha256::new();
+        for (path, content) in files {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(content.as_bytes());
+            hasher.update([0]);
+        }
+        let digest: [u8; 32] = hasher.finalize().into();
+        if let Some(i) = self.seen.iter().position(|d| *d == digest) {
+            return Some(i);
+        }
+        self.seen.push(digest);
+        None
+    }
+
+    pub fn record(&mut self, attempt: RepairAttempt) {
+        self.attempts.push(attempt);
+    }
+
+    pub fn attempts(&self) -> &[RepairAttempt] {
+        &self.attempts
+    }
+
+    /// The attempt that came closest to passing; the earliest on a tie.
+    pub fn best(&self) -> Option<&RepairAttempt> {
+        self.attempts.iter().min_by_key(|a| a.score.key())
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for a in self.attempts.iter().filter(|a| a.number > 0) {
+            out.push_str(&format!("#### Attempt {}\n\nYour reflection: {}\n\n", a.number, a.reflection));
+            let lines: Vec<&str> = a.diff.lines().collect();
+            out.push_str("Edits:\n```diff\n");
+            for line in lines.iter().take(MAX_DIFF_LINES) {
+                out.push_str(line);
+                out.push('\n');
+            }
+            if lines.len() > MAX_DIFF_LINES {
+                out.push_str(&format!("… {} more lines\n", lines.len() - MAX_DIFF_LINES));
+            }
+            out.push_str("```\n\nWhy it failed:\n");
+            for f in &a.failures {
+                out.push_str(&format!("- {f}\n"));
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl RepairAttempt {
+    /// Describe the attempt that turned `before` into `after` and still failed
+    /// with `evidence`.
+    pub fn new(
+        number: u32,
+        reflection: String,
+        before: &BTreeMap<PathBuf, String>,
+        after: &BTreeMap<PathBuf, String>,
+        evidence: &BuildEvidence,
+    ) -> Self {
+        let diff = Changes {
+            before: before.clone(),
+            after: after.clone(),
+        }
+        .diff();
+        Self {
+            number,
+            reflection,
+            diff,
+            failures: evidence.failures(),
+            score: AttemptScore::of(evidence),
+            files: after.clone(),
+        }
+    }
+}
 
 pub struct RepairAgent<'a> {
-    pub model: &'a str,
-    pub client: Client<async_openai::config::OpenAIConfig>,
+    pub settings: &'a AgentSettings,
+    pub llm: &'a dyn LlmBackend,
+    pub system_prompt: &'a str,
 }
 
 impl<'a> RepairAgent<'a> {
-    pub fn new(model: &'a str) -> Self {
+    pub fn new(
+        llm: &'a dyn LlmBackend,
+        settings: &'a AgentSettings,
+        system_prompt: Option<&'a str>,
+    ) -> Self {
         Self {
-            model,
-            client: Client::new(),
+            settings,
+            llm,
+            system_prompt: system_prompt.unwrap_or(Prompts::repair()),
         }
     }
 
+    /// `files` holds the current content of every touched file; `file_path` is the
+    /// file under refactor; `memory` holds the attempts that already failed.
     pub async fn repair(
         &self,
-        file_path: &str,
-        broken_candidate: &str,
+        file_path: &Path,
+        files: &BTreeMap<PathBuf, String>,
         compiler_or_test_logs: &str,
-    ) -> Result<String> {
-        let system = Prompts::repair();
+        memory: &RepairMemory,
+    ) -> Result<RepairAnswer> {
+        let system = self.system_prompt;
+        let code: String = files
+            .iter()
+            .map(|(path, content)| {
+                format!("```rust file={}\n{}\n```\n", path.display(), content.trim_end())
+            })
+            .collect();
+        let previous = if memory.attempts.iter().all(|a| a.number == 0) {
+            String::new()
+        } else {
+            format!(
+                "\n### Previous repair attempts (all failed; do not repeat them)\n\n{}",
+                memory.render()
+            )
+        };
         let user = format!(
             r#"File path: {file}
 
-Current file content:
+Current content of the touched files:
 
-```rust
 {code}
-```
-
 Compiler/Test errors:
 
 ````
 {logs}
-```"#,
-            file = file_path,
-            code = broken_candidate,
+````{previous}"#,
+            file = file_path.display(),
+            code = code,
             logs = compiler_or_test_logs,
+            previous = previous,
         );
 
-        let req = CreateChatCompletionRequestArgs::default()
-            .model(self.model)
-            .messages([
-                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                    content: ChatCompletionRequestSystemMessageContent::Text(system.to_string()),
-                    name: None,
-                }),
-                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                    content: ChatCompletionRequestUserMessageContent::Text(user),
-                    name: None,
-                }),
-            ])
-            .temperature(0.0)
-            .build()?;
-
-        let resp = self.client.chat().create(req).await?;
-        let content = resp.choices[0].message.content.clone().unwrap_or_default();
-        let file = crate::agents::extract_rust_block(&content)
-            .context("RepairAgent: no Rust code block found in response")?;
-        Ok(file)
+        let content = self
+            .llm
+            .complete(&ChatRequest {
+                role: AgentRole::Repair,
+                settings: self.settings.clone(),
+                system: system.to_string(),
+                user,
+                schema: None,
+            })
+            .await?;
+        let edits = EditSet::parse(&content, file_path)
+            .context("RepairAgent: could not read file edits from response")?;
+        Ok(RepairAnswer {
+            edits,
+            reflection: reflection(&content),
+        })
+    }
+}
+
+/// The prose before the first code block, without a `Reflection:` label.
+fn reflection(reply: &str) -> String {
+    let prose: Vec<&str> = reply
+        .lines()
+        .take_while(|l| {
+            let l = l.trim_start();
+            !l.starts_with("```") && !l.starts_with("~~~")
+        })
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    let text = prose.join(" ");
+    let text = text
+        .trim_start_matches(['#', '*', ' '])
+        .trim_start_matches("Reflection:")
+        .trim_start_matches(['*', ' ']);
+    if text.is_empty() {
+        "(none given)".to_string()
+    } else {
+        text.to_string()
     }
 }
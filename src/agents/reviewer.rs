@@ -1,52 +1,149 @@
-use anyhow::Result;
-use async_openai::{
-    types::{
-        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
-        ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessage,
-        ChatCompletionRequestUserMessageContent, CreateChatCompletionRequestArgs,
-    },
-    Client,
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fmt, path::Path};
+use tracing::warn;
+
+use crate::config::{AgentSettings, RefactorKind};
+use crate::edits::Changes;
+use crate::fence;
+use crate::llm::{AgentRole, ChatRequest, LlmBackend, ResponseSchema};
+use crate::{
+    prompts::Prompts,
+    verification::{verify_refactor, BuildEvidence},
 };
-use serde::Deserialize;
 
-use crate::config::RefactorKind;
-use crate::{prompts::Prompts, verification::verify_refactor_heuristic};
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Accept,
+    Revise,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Accept => "accept",
+            Verdict::Revise => "revise",
+        })
+    }
+}
+
+/// What was checked. The Reviewer fills it in; [`ReviewerAgent::review`] then
+/// replaces its claims with the measured results.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReviewChecklist {
+    pub refactor_verified: bool,
+    pub fmt_clean: bool,
+    pub clippy_clean: bool,
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReviewVerdict {
-    pub verdict: String,
-    pub reasons: Option<Vec<String>>,
+    pub verdict: Verdict,
+    #[serde(default)]
+    pub reasons: Vec<String>,
+    #[serde(default)]
     pub patch_guidance: Option<String>,
-    pub checklist: Option<serde_json::Value>,
+    pub checklist: ReviewChecklist,
+}
+
+impl ReviewVerdict {
+    /// The JSON schema of a verdict, in the strict subset structured outputs accept.
+    pub fn schema() -> ResponseSchema {
+        ResponseSchema {
+            name: "review_verdict".into(),
+            schema: json!({
+                "type": "object",
+                "properties": {
+                    "verdict": { "type": "string", "enum": ["accept", "revise"] },
+                    "reasons": { "type": "array", "items": { "type": "string" } },
+                    "patch_guidance": { "type": ["string", "null"] },
+                    "checklist": {
+                        "type": "object",
+                        "properties": {
+                            "refactor_verified": { "type": "boolean" },
+                            "fmt_clean": { "type": "boolean" },
+                            "clippy_clean": { "type": "boolean" }
+                        },
+                        "required": ["refactor_verified", "fmt_clean", "clippy_clean"],
+                        "additionalProperties": false
+                    }
+                },
+                "required": ["verdict", "reasons", "patch_guidance", "checklist"],
+                "additionalProperties": false
+            }),
+        }
+    }
+
+    /// Read a verdict from a reply that is bare JSON, a fenced `json` block, or
+    /// prose around a single JSON object.
+    pub fn parse(reply: &str) -> Result<Self, String> {
+        let fenced = fence::parse_blocks(reply).ok().and_then(|blocks| {
+            blocks
+                .into_iter()
+                .find(|b| matches!(b.lang.as_str(), "json" | ""))
+                .map(|b| b.body)
+        });
+        let text = match fenced {
+            Some(body) => body,
+            None => {
+                let start = reply.find('{');
+                let end = reply.rfind('}');
+                match (start, end) {
+                    (Some(s), Some(e)) if s < e => reply[s..=e].to_string(),
+                    _ => return Err("the reply contains no JSON object".into()),
+                }
+            }
+        };
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    }
 }
 
 pub struct ReviewerAgent<'a> {
-    pub model: &'a str,
-    pub client: Client<async_openai::config::OpenAIConfig>,
+    pub settings: &'a AgentSettings,
+    pub llm: &'a dyn LlmBackend,
+    pub system_prompt: &'a str,
+    /// Times a reply that isn't a valid verdict is sent back with the parse error.
+    pub max_retries: u32,
 }
 
 impl<'a> ReviewerAgent<'a> {
-    pub fn new(model: &'a str) -> Self {
+    pub fn new(
+        llm: &'a dyn LlmBackend,
+        settings: &'a AgentSettings,
+        system_prompt: Option<&'a str>,
+        max_retries: u32,
+    ) -> Self {
         Self {
-            model,
-            client: Client::new(),
+            settings,
+            llm,
+            system_prompt: system_prompt.unwrap_or(Prompts::reviewer()),
+            max_retries,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn review(
         &self,
         kind: RefactorKind,
-        original: &str,
-        candidate: &str,
+        primary: &Path,
+        changes: &Changes,
         analysis: &str,
         fmt_clean: bool,
         clippy_clean: bool,
-        compiler_logs: Option<&str>,
+        dangling_refs: &[String],
+        api_changes: &[String],
+        evidence: &BuildEvidence,
     ) -> Result<ReviewVerdict> {
         // quick local gate first
-        let verified = verify_refactor_heuristic(kind.clone(), original, candidate);
+        let check = verify_refactor(kind.clone(), &changes.before, &changes.after);
+        let verified = check.verified;
+        let original = changes.before.get(primary).map(String::as_str).unwrap_or_default();
+        let candidate = changes.after.get(primary).map(String::as_str).unwrap_or_default();
+        let others = changes.diff_except(primary);
 
-        let system = Prompts::reviewer();
+        let system = self.system_prompt;
         let user_input = format!(
             r#"### Inputs
 Original:
@@ -61,68 +158,117 @@ Candidate:
 {candidate}
 ```
 
+Other touched files (diff):
+{others}
+
 Static analysis (excerpt):
 {analysis}
 
 fmt_clean={fmt_clean}  clippy_clean={clippy_clean}
 
-Compiler/test logs (optional):
-{logs}
+References elsewhere in the crate still pointing at removed or renamed items:
+{dangling}
+
+Changes to the public API (not allowed without --allow-api-change):
+{api}
+
+Build, test and lint results of the candidate:
+{evidence}
 
 Refactor kind: {kind:?}
+Structural check: {structure}
 "#,
             original = original,
             candidate = candidate,
+            others = if others.is_empty() {
+                "<none>".to_string()
+            } else {
+                format!("```diff\n{others}```")
+            },
             analysis = analysis,
             fmt_clean = fmt_clean,
             clippy_clean = clippy_clean,
-            logs = compiler_logs.unwrap_or("<none>"),
-            kind = kind
+            dangling = if dangling_refs.is_empty() {
+                "<none>".to_string()
+            } else {
+                dangling_refs.join("\n")
+            },
+            api = if api_changes.is_empty() {
+                "<none>".to_string()
+            } else {
+                api_changes.join("\n")
+            },
+            evidence = evidence.render(),
+            kind = kind,
+            structure = if verified {
+                format!("passed ({})", check.detail)
+            } else {
+                format!("failed ({})", check.detail)
+            }
         );
 
-        let req = CreateChatCompletionRequestArgs::default()
-            .model(self.model)
-            .messages([
-                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                    content: ChatCompletionRequestSystemMessageContent::Text(system.to_string()),
-                    name: None,
-                }),
-                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                    content: ChatCompletionRequestUserMessageContent::Text(user_input),
-                    name: None,
-                }),
-            ])
-            .temperature(0.0)
-            .build()?;
-
-        let resp = self.client.chat().create(req).await?;
-        let content = resp.choices[0].message.content.clone().unwrap_or_default();
-        let json_text = content
-            .split("```json")
-            .nth(1)
-            .and_then(|s| s.split("```").next())
-            .map(|s| s.trim())
-            .unwrap_or(content.trim());
-
-        let mut verdict: ReviewVerdict = serde_json::from_str(json_text).or_else(|_: serde_json::Error| {
-            Ok::<ReviewVerdict, serde_json::Error>(ReviewVerdict {
-                verdict: if verified {
-                    "accept".into()
-                } else {
-                    "revise".into()
-                },
-                reasons: Some(vec!["LLM JSON parse fallback".into()]),
-                patch_guidance: None,
-                checklist: None,
-            })
-        })?;
-
-        if !verified && verdict.verdict == "accept" {
-            verdict.verdict = "revise".into();
+        let mut request = ChatRequest {
+            role: AgentRole::Reviewer,
+            settings: self.settings.clone(),
+            system: system.to_string(),
+            user: user_input.clone(),
+            schema: Some(ReviewVerdict::schema()),
+        };
+        let mut attempt = 0;
+        let mut verdict = loop {
+            let content = self.llm.complete(&request).await?;
+            match ReviewVerdict::parse(&content) {
+                Ok(v) => break v,
+                Err(e) if attempt < self.max_retries => {
+                    attempt += 1;
+                    warn!("Reviewer reply is not a valid verdict ({e}); asking again ({attempt})");
+                    request.user = format!(
+                        "{user_input}\nYour previous reply could not be parsed: {e}\nReply with only the JSON object described in the output contract.\n"
+                    );
+                }
+                Err(e) => bail!(
+                    "Reviewer reply is not a valid verdict after {} attempt(s): {e}",
+                    attempt + 1
+                ),
+            }
+        };
+
+        // Record what was measured, whatever the model claims.
+        verdict.checklist = ReviewChecklist {
+            refactor_verified: verified,
+            fmt_clean,
+            clippy_clean,
+        };
+
+        if !verified && verdict.verdict == Verdict::Accept {
+            verdict.verdict = Verdict::Revise;
             verdict
                 .reasons
-                .get_or_insert(vec![])
-                .push("Local heuristic failed to verify refactor".into());
+                .push(format!("Structural check failed: {}", check.detail));
+        }
+
+        if !dangling_refs.is_empty() && verdict.verdict == Verdict::Accept {
+            verdict.verdict = Verdict::Revise;
+            verdict.reasons.push(format!(
+                "{} reference(s) outside the candidate still use removed items",
+                dangling_refs.len()
+            ));
+            verdict
+                .patch_guidance
+                .get_or_insert_with(String::new)
+                .push_str(&format!("\nUpdate these call sites:\n{}", dangling_refs.join("\n")));
+        }
+
+        if !api_changes.is_empty() && verdict.verdict == Verdict::Accept {
+            verdict.verdict = Verdict::Revise;
+            verdict.reasons.push(format!(
+                "the candidate changes {} public API item(s)",
+                api_changes.len()
+            ));
+            verdict.patch_guidance.get_or_insert_with(String::new).push_str(&format!(
+                "\nKeep the public API as it was; undo these changes:\n{}",
+                api_changes.join("\n")
+            ));
         }
 
         Ok(verdict)
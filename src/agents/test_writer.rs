@@ -0,0 +1,128 @@
+use crate::config::AgentSettings;
+use crate::fence::{self, ExtractError};
+use crate::llm::{AgentRole, ChatRequest, LlmBackend};
+use crate::prompts::Prompts;
+use anyhow::{Context, Result};
+use std::path::Path;
+
+pub struct TestWriterAgent<'a> {
+    pub settings: &'a AgentSettings,
+    pub llm: &'a dyn LlmBackend,
+    pub system_prompt: &'a str,
+}
+
+impl<'a> TestWriterAgent<'a> {
+    /// `system_prompt` replaces [`Prompts::test_writer`] when set (`[prompts] test_writer` in `mantra.toml`).
+    pub fn new(
+        llm: &'a dyn LlmBackend,
+        settings: &'a AgentSettings,
+        system_prompt: Option<&'a str>,
+    ) -> Self {
+        Self {
+            settings,
+            llm,
+            system_prompt: system_prompt.unwrap_or(Prompts::test_writer()),
+        }
+    }
+
+    /// Characterization tests for the items `refactor_prompt` is about, as the body
+    /// of a test module. `feedback` explains what was wrong with the previous answer.
+    pub async fn write(
+        &self,
+        file_path: &Path,
+        content: &str,
+        analysis: &str,
+        refactor_prompt: &str,
+        feedback: &str,
+    ) -> Result<String> {
+        let user = format!(
+            r#"### Context
+
+* File path: {path}
+* Static analysis (excerpt):
+  {analysis}
+
+### Code to characterize
+
+```rust
+{code}
+```
+
+### Upcoming refactoring request
+
+{req}
+{feedback}"#,
+            path = file_path.display(),
+            analysis = analysis,
+            code = content,
+            req = refactor_prompt,
+            feedback = feedback,
+        );
+
+        let reply = self
+            .llm
+            .complete(&ChatRequest {
+                role: AgentRole::TestWriter,
+                settings: self.settings.clone(),
+                system: self.system_prompt.to_string(),
+                user,
+                schema: None,
+            })
+            .await?;
+        module_body(&reply).context("TestWriterAgent: could not read tests from response")
+    }
+}
+
+/// The Rust blocks of `reply`, joined, if they parse as module items.
+fn module_body(reply: &str) -> Result<String, ExtractError> {
+    let blocks = fence::parse_blocks(reply)?;
+    let rust: Vec<_> = blocks
+        .iter()
+        .filter(|b| b.lang == "rust" || b.lang.is_empty())
+        .collect();
+    let Some(first) = rust.first() else {
+        return Err(if blocks.is_empty() {
+            ExtractError::NoBlocks
+        } else {
+            ExtractError::NoEdits {
+                langs: blocks.iter().map(|b| b.lang.clone()).collect(),
+            }
+        });
+    };
+    let body = rust
+        .iter()
+        .map(|b| b.body.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    syn::parse_file(&body).map_err(|e| ExtractError::Invalid {
+        line: first.line,
+        reason: format!("the tests do not parse as Rust items: {e}"),
+    })?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn joins_rust_blocks_that_parse_as_items() {
+        let reply = "Tests:\n\n```rust\n#[test]\nfn a() {}\n```\n\n```\n#[test]\nfn b() {}\n```\n\n```toml\nx = 1\n```\n";
+        assert_eq!(
+            module_body(reply).unwrap(),
+            "#[test]\nfn a() {}\n\n#[test]\nfn b() {}"
+        );
+    }
+
+    #[test]
+    fn rejects_replies_without_usable_tests() {
+        assert!(matches!(module_body("no code"), Err(ExtractError::NoBlocks)));
+        assert!(matches!(
+            module_body("```toml\nx = 1\n```\n"),
+            Err(ExtractError::NoEdits { .. })
+        ));
+        let err = module_body("text\n```rust\nfn a( {\n```\n").unwrap_err();
+        assert!(matches!(err, ExtractError::Invalid { line: 2, .. }), "{err}");
+    }
+}
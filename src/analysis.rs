@@ -0,0 +1,305 @@
+use anyhow::Result;
+use quote::ToTokens;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+use syn::{ImplItem, Item, TraitItem, Visibility};
+
+use crate::{
+    ast::{call_names, compact, impl_header},
+    io_utils::rust_sources,
+};
+
+/// An inline `mod` or a source file, with one rendered line per item.
+struct Module {
+    /// `crate`, `crate::config`, …
+    path: String,
+    /// Repo-relative source file.
+    file: String,
+    lines: Vec<Line>,
+}
+
+struct Line {
+    depth: usize,
+    text: String,
+    /// Index into [`Crate::fns`] when the line is a function.
+    fn_idx: Option<usize>,
+}
+
+struct FnInfo {
+    /// Short name such as `config::Cli::validate`.
+    id: String,
+    name: String,
+    calls: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct Crate {
+    modules: Vec<Module>,
+    fns: Vec<FnInfo>,
+}
+
+/// Summarise the crate at `repo` for the agents: module layout, items with their
+/// visibility and signatures, impl blocks, trait impls and intra-crate call sites.
+/// Modules of `focus` come first; the rest is cut off once `max_chars` is reached.
+pub fn summarize_crate(repo: &Path, focus: &Path, max_chars: usize) -> Result<String> {
+    let mut krate = Crate::default();
+    for path in rust_sources(repo)? {
+        let rel = path.strip_prefix(repo).unwrap_or(&path);
+        let is_rs = path.extension().is_some_and(|e| e == "rs");
+        if !is_rs || !(rel.starts_with("src") || path == focus) {
+            continue;
+        }
+        let file = rel.to_string_lossy().replace('\\', "/");
+        let module = module_path(&file);
+        let src = fs::read_to_string(&path)?;
+        match syn::parse_file(&src) {
+            Ok(parsed) => krate.walk(&parsed.items, &module, &file),
+            Err(e) => krate.modules.push(Module {
+                path: module,
+                file,
+                lines: vec![Line {
+                    depth: 1,
+                    text: format!("(does not parse: {e})"),
+                    fn_idx: None,
+                }],
+            }),
+        }
+    }
+    let focus_rel = focus
+        .strip_prefix(repo)
+        .unwrap_or(focus)
+        .to_string_lossy()
+        .replace('\\', "/");
+    Ok(krate.render(&focus_rel, max_chars))
+}
+
+/// `src/main.rs` → `crate`, `src/a/mod.rs` → `crate::a`, `src/a/b.rs` → `crate::a::b`.
+fn module_path(rel: &str) -> String {
+    let Some(inner) = rel.strip_prefix("src/") else {
+        return rel.to_string();
+    };
+    let inner = inner.trim_end_matches(".rs");
+    if matches!(inner, "main" | "lib") {
+        return "crate".into();
+    }
+    let inner = inner.strip_suffix("/mod").unwrap_or(inner);
+    format!("crate::{}", inner.replace('/', "::"))
+}
+
+fn vis(v: &Visibility) -> String {
+    match v {
+        Visibility::Inherited => String::new(),
+        v => format!("{} ", compact(&v.to_token_stream())),
+    }
+}
+
+impl Crate {
+    fn walk(&mut self, items: &[Item], module: &str, file: &str) {
+        let mut lines = vec![];
+        let mut push = |depth: usize, text: String, fn_idx: Option<usize>| {
+            lines.push(Line {
+                depth,
+                text,
+                fn_idx,
+            })
+        };
+        let mut inline_mods = vec![];
+        for item in items {
+            match item {
+                Item::Fn(f) => {
+                    let idx = self.add_fn(module, None, &f.sig.ident, f.block.to_token_stream());
+                    let sig = compact(&f.sig.to_token_stream());
+                    push(1, format!("{}{sig}", vis(&f.vis)), Some(idx));
+                }
+                Item::Struct(s) => {
+                    let generics = compact(&s.generics.to_token_stream());
+                    let fields = s.fields.len();
+                    push(
+                        1,
+                        format!(
+                            "{}struct {}{generics} ({fields} fields)",
+                            vis(&s.vis),
+                            s.ident
+                        ),
+                        None,
+                    );
+                }
+                Item::Enum(e) => {
+                    let generics = compact(&e.generics.to_token_stream());
+                    let variants = e.variants.len();
+                    push(
+                        1,
+                        format!(
+                            "{}enum {}{generics} ({variants} variants)",
+                            vis(&e.vis),
+                            e.ident
+                        ),
+                        None,
+                    );
+                }
+                Item::Type(t) => push(1, format!("{}type {}", vis(&t.vis), t.ident), None),
+                Item::Const(c) => {
+                    let ty = compact(&c.ty.to_token_stream());
+                    push(1, format!("{}const {}: {ty}", vis(&c.vis), c.ident), None);
+                }
+                Item::Static(s) => {
+                    let ty = compact(&s.ty.to_token_stream());
+                    push(1, format!("{}static {}: {ty}", vis(&s.vis), s.ident), None);
+                }
+                Item::Trait(t) => {
+                    push(1, format!("{}trait {}", vis(&t.vis), t.ident), None);
+                    for it in &t.items {
+                        if let TraitItem::Fn(m) = it {
+                            push(2, compact(&m.sig.to_token_stream()), None);
+                        }
+                    }
+                }
+                Item::Impl(imp) => {
+                    push(1, impl_header(imp), None);
+                    let ty = compact(&imp.self_ty.to_token_stream());
+                    for it in &imp.items {
+                        if let ImplItem::Fn(m) = it {
+                            let idx = self.add_fn(
+                                module,
+                                Some(&ty),
+                                &m.sig.ident,
+                                m.block.to_token_stream(),
+                            );
+                            let sig = compact(&m.sig.to_token_stream());
+                            push(2, format!("{}{sig}", vis(&m.vis)), Some(idx));
+                        }
+                    }
+                }
+                Item::Mod(m) => {
+                    let semi = if m.content.is_some() { "" } else { ";" };
+                    push(1, format!("{}mod {}{semi}", vis(&m.vis), m.ident), None);
+                    if let Some((_, items)) = &m.content {
+                        inline_mods.push((format!("{module}::{}", m.ident), items));
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.modules.push(Module {
+            path: module.to_string(),
+            file: file.to_string(),
+            lines,
+        });
+        for (path, items) in inline_mods {
+            self.walk(items, &path, file);
+        }
+    }
+
+    fn add_fn(
+        &mut self,
+        module: &str,
+        ty: Option<&str>,
+        ident: &syn::Ident,
+        body: proc_macro2::TokenStream,
+    ) -> usize {
+        let name = ident.to_string();
+        let mut id = module
+            .strip_prefix("crate")
+            .unwrap_or(module)
+            .trim_start_matches("::")
+            .to_string();
+        for part in ty.into_iter().chain([name.as_str()]) {
+            if !id.is_empty() {
+                id.push_str("::");
+            }
+            id.push_str(part);
+        }
+        self.fns.push(FnInfo {
+            id,
+            name,
+            calls: call_names(body),
+        });
+        self.fns.len() - 1
+    }
+
+    /// Callees and callers of every fn, resolved by name within the crate.
+    fn call_graph(&self) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let mut by_name: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (i, f) in self.fns.iter().enumerate() {
+            by_name.entry(&f.name).or_default().push(i);
+        }
+        let mut callees = vec![vec![]; self.fns.len()];
+        let mut callers = vec![vec![]; self.fns.len()];
+        for (i, f) in self.fns.iter().enumerate() {
+            for name in &f.calls {
+                for &j in by_name.get(name.as_str()).into_iter().flatten() {
+                    if i != j {
+                        callees[i].push(j);
+                        callers[j].push(i);
+                    }
+                }
+            }
+        }
+        (callees, callers)
+    }
+
+    fn render(&self, focus: &str, max_chars: usize) -> String {
+        let (callees, callers) = self.call_graph();
+        let ids = |idxs: &[usize]| {
+            idxs.iter()
+                .map(|&i| self.fns[i].id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let mut order: Vec<&Module> = self.modules.iter().collect();
+        order.sort_by_key(|m| (m.file != focus, m.path.clone()));
+
+        let mut out = format!(
+            "Crate: {} module(s), {} fn(s). Call sites are matched by name.\n",
+            self.modules.len(),
+            self.fns.len()
+        );
+        let mut omitted = 0;
+        for module in order {
+            let is_focus = module.file == focus;
+            let mut text = format!(
+                "\nmod {} ({}){}\n",
+                module.path,
+                module.file,
+                if is_focus {
+                    " [file under refactor]"
+                } else {
+                    ""
+                }
+            );
+            for line in &module.lines {
+                let mut entry = format!("{}{}", "  ".repeat(line.depth), line.text);
+                if let Some(i) = line.fn_idx {
+                    if !callees[i].is_empty() {
+                        entry.push_str(&format!("  calls: {}", ids(&callees[i])));
+                    }
+                    if !callers[i].is_empty() {
+                        entry.push_str(&format!("  called by: {}", ids(&callers[i])));
+                    }
+                }
+                entry.push('\n');
+                // The file under refactor is kept line by line as far as the budget allows.
+                if is_focus && out.len() + text.len() + entry.len() > max_chars {
+                    omitted += 1;
+                    continue;
+                }
+                text.push_str(&entry);
+            }
+            if out.len() + text.len() > max_chars && !is_focus {
+                omitted += 1;
+                continue;
+            }
+            out.push_str(&text);
+        }
+        if omitted > 0 {
+            out.push_str(&format!(
+                "\n… {omitted} more module(s)/item(s) omitted to stay within {max_chars} characters\n"
+            ));
+        }
+        out
+    }
+}
@@ -0,0 +1,557 @@
+use anyhow::Result;
+use quote::ToTokens;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+use syn::{Fields, ImplItem, Item, TraitItem, Type, UseTree, Visibility};
+use tracing::warn;
+
+use crate::{index::package_name, io_utils::rust_sources};
+
+/// The public API of every library crate under a repo: one entry per public
+/// item, method, field, variant, trait impl and re-export, keyed by its path.
+#[derive(Debug, Default)]
+pub struct ApiSurface {
+    /// Item path → its signature, without bodies, docs or formatting.
+    items: BTreeMap<String, String>,
+}
+
+/// A difference between two [`ApiSurface`]s.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum ApiChange {
+    Removed {
+        path: String,
+        item: String,
+    },
+    Added {
+        path: String,
+        item: String,
+    },
+    Changed {
+        path: String,
+        before: String,
+        after: String,
+    },
+}
+
+impl fmt::Display for ApiChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiChange::Removed { path, item } => write!(f, "removed {path}: `{item}`"),
+            ApiChange::Added { path, item } => write!(f, "added {path}: `{item}`"),
+            ApiChange::Changed {
+                path,
+                before,
+                after,
+            } => write!(f, "changed {path}: `{before}` became `{after}`"),
+        }
+    }
+}
+
+impl ApiSurface {
+    /// Read the public API of the library target of every package under `repo`.
+    /// Binary-only packages have none.
+    pub fn extract(repo: &Path) -> Result<Self> {
+        let mut surface = Self::default();
+        for manifest in rust_sources(repo)? {
+            if manifest.file_name().is_none_or(|n| n != "Cargo.toml") {
+                continue;
+            }
+            let Some((name, lib)) = library(&manifest) else {
+                continue;
+            };
+            let mut walker = Walker::default();
+            walker.file(&lib, &name, true);
+            walker.finish(&mut surface.items);
+        }
+        Ok(surface)
+    }
+
+    /// Item-level changes from `self` to `after`, removals and changes first.
+    pub fn diff(&self, after: &Self) -> Vec<ApiChange> {
+        let mut changes = vec![];
+        for (path, before) in &self.items {
+            match after.items.get(path) {
+                None => changes.push(ApiChange::Removed {
+                    path: path.clone(),
+                    item: before.clone(),
+                }),
+                Some(now) if now != before => changes.push(ApiChange::Changed {
+                    path: path.clone(),
+                    before: before.clone(),
+                    after: now.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for (path, item) in &after.items {
+            if !self.items.contains_key(path) {
+                changes.push(ApiChange::Added {
+                    path: path.clone(),
+                    item: item.clone(),
+                });
+            }
+        }
+        changes
+    }
+}
+
+/// Crate name and library root of the package described by `manifest`.
+fn library(manifest: &Path) -> Option<(String, PathBuf)> {
+    let name = package_name(manifest)?;
+    let dir = manifest.parent()?;
+    let table: toml::Table = fs::read_to_string(manifest).ok()?.parse().ok()?;
+    let lib = table
+        .get("lib")
+        .and_then(|lib| lib.get("path"))
+        .and_then(|p| p.as_str())
+        .map_or_else(|| dir.join("src/lib.rs"), |p| dir.join(p));
+    lib.is_file().then_some((name, lib))
+}
+
+/// Walks the module tree of one crate. Impls are collected everywhere and kept
+/// at the end if their type is public.
+#[derive(Default)]
+struct Walker {
+    items: BTreeMap<String, String>,
+    /// Public type name → its path, for attaching impls.
+    types: BTreeMap<String, String>,
+    /// Impls anywhere in the crate; where they are does not matter to users.
+    impls: Vec<syn::ItemImpl>,
+}
+
+impl Walker {
+    fn file(&mut self, path: &Path, module: &str, public: bool) {
+        let parsed = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|src| syn::parse_file(&src).map_err(|e| e.to_string()));
+        let file = match parsed {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("api: skipping {} ({e})", path.display());
+                return;
+            }
+        };
+        let is_root = path
+            .file_name()
+            .is_some_and(|n| n == "lib.rs" || n == "mod.rs" || n == "main.rs");
+        let dir = match (is_root, path.parent()) {
+            (true, Some(parent)) => parent.to_path_buf(),
+            (false, Some(parent)) => parent.join(path.file_stem().unwrap_or_default()),
+            (_, None) => PathBuf::new(),
+        };
+        self.items(&file.items, module, &dir, public);
+    }
+
+    fn items(&mut self, items: &[Item], module: &str, dir: &Path, public: bool) {
+        for item in items {
+            if let Item::Impl(imp) = item {
+                self.impls.push(imp.clone());
+            }
+            if let Item::Mod(m) = item {
+                let path = format!("{module}::{}", m.ident);
+                let public = public && is_pub(&m.vis);
+                match &m.content {
+                    Some((_, items)) => {
+                        self.items(items, &path, &dir.join(m.ident.to_string()), public)
+                    }
+                    None => {
+                        let candidates = [
+                            dir.join(format!("{}.rs", m.ident)),
+                            dir.join(m.ident.to_string()).join("mod.rs"),
+                        ];
+                        if let Some(file) = candidates.iter().find(|f| f.is_file()) {
+                            self.file(file, &path, public);
+                        }
+                    }
+                }
+                continue;
+            }
+            if public {
+                self.item(item, module);
+            }
+        }
+    }
+
+    /// Record `item` if it is `pub` in the public `module`.
+    fn item(&mut self, item: &Item, module: &str) {
+        match item {
+            Item::Fn(f) if is_pub(&f.vis) => {
+                let sig = format!("{} {}", tokens(&f.vis), tokens(&f.sig));
+                self.items
+                    .insert(format!("{module}::{}", f.sig.ident), tidy(&sig));
+            }
+            Item::Struct(s) if is_pub(&s.vis) => {
+                let path = format!("{module}::{}", s.ident);
+                let private = s.fields.iter().any(|f| !is_pub(&f.vis));
+                let mut header = format!(
+                    "{}{} struct {}{}",
+                    kept_attrs(&s.attrs),
+                    tokens(&s.vis),
+                    s.ident,
+                    tokens(&s.generics)
+                );
+                if private {
+                    header.push_str(" /* private fields */");
+                }
+                self.items.insert(path.clone(), tidy(&header));
+                for (i, field) in s.fields.iter().enumerate() {
+                    if !is_pub(&field.vis) {
+                        continue;
+                    }
+                    let name = field
+                        .ident
+                        .as_ref()
+                        .map_or_else(|| i.to_string(), ToString::to_string);
+                    let text = format!("{} {name}: {}", tokens(&field.vis), tokens(&field.ty));
+                    self.items.insert(format!("{path}::{name}"), tidy(&text));
+                }
+                self.types.entry(s.ident.to_string()).or_insert(path);
+            }
+            Item::Enum(e) if is_pub(&e.vis) => {
+                let path = format!("{module}::{}", e.ident);
+                let header = format!(
+                    "{}{} enum {}{}",
+                    kept_attrs(&e.attrs),
+                    tokens(&e.vis),
+                    e.ident,
+                    tokens(&e.generics)
+                );
+                self.items.insert(path.clone(), tidy(&header));
+                for v in &e.variants {
+                    let mut v = v.clone();
+                    v.attrs.clear();
+                    if let Fields::Named(named) = &mut v.fields {
+                        named.named.iter_mut().for_each(|f| f.attrs.clear());
+                    }
+                    self.items
+                        .insert(format!("{path}::{}", v.ident), tidy(&tokens(&v)));
+                }
+                self.types.entry(e.ident.to_string()).or_insert(path);
+            }
+            Item::Union(u) if is_pub(&u.vis) => {
+                let mut u = u.clone();
+                u.attrs.retain(keep_attr);
+                let path = format!("{module}::{}", u.ident);
+                self.items.insert(path.clone(), tidy(&tokens(&u)));
+                self.types.entry(u.ident.to_string()).or_insert(path);
+            }
+            Item::Trait(t) if is_pub(&t.vis) => {
+                let path = format!("{module}::{}", t.ident);
+                let mut header = t.clone();
+                header.attrs.retain(keep_attr);
+                header.items.clear();
+                let header = tidy(&tokens(&header));
+                let header = header.trim_end_matches("{ }").trim_end();
+                self.items.insert(path.clone(), header.to_string());
+                for it in &t.items {
+                    let (name, text) = match it {
+                        TraitItem::Fn(m) => {
+                            let provided = if m.default.is_some() { " { .. }" } else { "" };
+                            (
+                                m.sig.ident.to_string(),
+                                format!("{}{provided}", tokens(&m.sig)),
+                            )
+                        }
+                        TraitItem::Type(ty) => {
+                            let mut ty = ty.clone();
+                            ty.attrs.clear();
+                            (ty.ident.to_string(), tokens(&ty))
+                        }
+                        TraitItem::Const(c) => {
+                            let mut c = c.clone();
+                            c.attrs.clear();
+                            c.default = None;
+                            (c.ident.to_string(), tokens(&c))
+                        }
+                        _ => continue,
+                    };
+                    self.items.insert(format!("{path}::{name}"), tidy(&text));
+                }
+                self.types.entry(t.ident.to_string()).or_insert(path);
+            }
+            Item::Type(t) if is_pub(&t.vis) => {
+                let mut t = t.clone();
+                t.attrs.clear();
+                self.items
+                    .insert(format!("{module}::{}", t.ident), tidy(&tokens(&t)));
+            }
+            Item::Const(c) if is_pub(&c.vis) => {
+                let text = format!("{} const {}: {}", tokens(&c.vis), c.ident, tokens(&c.ty));
+                self.items
+                    .insert(format!("{module}::{}", c.ident), tidy(&text));
+            }
+            Item::Static(s) if is_pub(&s.vis) => {
+                let text = format!(
+                    "{} static {}{}: {}",
+                    tokens(&s.vis),
+                    tokens(&s.mutability),
+                    s.ident,
+                    tokens(&s.ty)
+                );
+                self.items
+                    .insert(format!("{module}::{}", s.ident), tidy(&text));
+            }
+            Item::Macro(m) if m.attrs.iter().any(|a| a.path().is_ident("macro_export")) => {
+                if let Some(ident) = &m.ident {
+                    let root = module.split("::").next().unwrap_or(module);
+                    self.items
+                        .insert(format!("{root}::{ident}!"), format!("macro_rules! {ident}"));
+                }
+            }
+            Item::Use(u) if is_pub(&u.vis) => {
+                let vis = tokens(&u.vis);
+                reexports(&u.tree, vec![], &mut |name, target| {
+                    self.items.insert(
+                        format!("{module}::{name}"),
+                        tidy(&format!("{vis} use {target}")),
+                    );
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Add the public methods and trait impls of public types, then hand over.
+    fn finish(mut self, out: &mut BTreeMap<String, String>) {
+        for imp in std::mem::take(&mut self.impls) {
+            let ty_path = type_name(&imp.self_ty).and_then(|name| self.types.get(&name).cloned());
+            if let Some((bang, trait_path, _)) = &imp.trait_ {
+                // `impl Display for Circle` belongs to `Circle`, `impl Shape for f64` to `Shape`.
+                let trait_name = trait_path.segments.last().map(|s| s.ident.to_string());
+                let Some(owner) =
+                    ty_path.or_else(|| trait_name.and_then(|name| self.types.get(&name).cloned()))
+                else {
+                    continue;
+                };
+                let header = format!(
+                    "impl{} {}{} for {}",
+                    tokens(&imp.generics),
+                    if bang.is_some() { "!" } else { "" },
+                    tokens(trait_path),
+                    tokens(&imp.self_ty)
+                );
+                let header = tidy(&header);
+                self.items.insert(format!("{owner}::{{{header}}}"), header);
+                continue;
+            }
+            let Some(ty_path) = ty_path else {
+                continue;
+            };
+            for it in &imp.items {
+                if let ImplItem::Fn(m) = it {
+                    if is_pub(&m.vis) {
+                        let sig = format!("{} {}", tokens(&m.vis), tokens(&m.sig));
+                        self.items
+                            .insert(format!("{ty_path}::{}", m.sig.ident), tidy(&sig));
+                    }
+                }
+            }
+        }
+        out.extend(self.items);
+    }
+}
+
+/// Only plain `pub` is part of the API; `pub(crate)` and the like are not.
+fn is_pub(vis: &Visibility) -> bool {
+    matches!(vis, Visibility::Public(_))
+}
+
+/// Attributes that change what users of a type can do with it.
+fn keep_attr(attr: &syn::Attribute) -> bool {
+    ["derive", "non_exhaustive", "repr"]
+        .iter()
+        .any(|name| attr.path().is_ident(name))
+}
+
+fn kept_attrs(attrs: &[syn::Attribute]) -> String {
+    attrs
+        .iter()
+        .filter(|a| keep_attr(a))
+        .map(|a| format!("{} ", tokens(a)))
+        .collect()
+}
+
+/// Call `add(name, target)` for every name a `pub use` brings in.
+fn reexports(tree: &UseTree, mut prefix: Vec<String>, add: &mut impl FnMut(String, String)) {
+    match tree {
+        UseTree::Path(p) => {
+            prefix.push(p.ident.to_string());
+            reexports(&p.tree, prefix, add);
+        }
+        UseTree::Name(n) => {
+            let name = n.ident.to_string();
+            let name = match (name.as_str(), prefix.last()) {
+                ("self", Some(last)) => last.clone(),
+                _ => {
+                    prefix.push(name.clone());
+                    name
+                }
+            };
+            add(name, prefix.join("::"));
+        }
+        UseTree::Rename(r) => {
+            prefix.push(r.ident.to_string());
+            add(
+                r.rename.to_string(),
+                format!("{} as {}", prefix.join("::"), r.rename),
+            );
+        }
+        UseTree::Glob(_) => {
+            prefix.push("*".into());
+            let target = prefix.join("::");
+            add(target.clone(), target);
+        }
+        UseTree::Group(g) => {
+            for t in &g.items {
+                reexports(t, prefix.clone(), add);
+            }
+        }
+    }
+}
+
+/// The type an impl is for, by name: `Foo` for `Foo<T>`, `&Foo` or `a::Foo`.
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(p) => p.path.segments.last().map(|s| s.ident.to_string()),
+        Type::Reference(r) => type_name(&r.elem),
+        Type::Paren(p) => type_name(&p.elem),
+        _ => None,
+    }
+}
+
+fn tokens(t: &impl ToTokens) -> String {
+    t.to_token_stream().to_string()
+}
+
+/// Token text with the spacing people write: `fn f(x: &[u8]) -> Vec<u8>`.
+fn tidy(text: &str) -> String {
+    let mut out = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    for (from, to) in [
+        (" :: ", "::"),
+        (":: ", "::"),
+        (" ::", "::"),
+        (" (", "("),
+        ("( ", "("),
+        (" )", ")"),
+        (" [", "["),
+        ("[ ", "["),
+        (" ]", "]"),
+        (" ,", ","),
+        (" ;", ";"),
+        (" :", ":"),
+        (" < ", "<"),
+        (" <", "<"),
+        ("< ", "<"),
+        (" >", ">"),
+        ("& ", "&"),
+        ("# ", "#"),
+        (" !", "!"),
+    ] {
+        out = out.replace(from, to);
+    }
+    // `->` and `=>` lose their leading space to the `>` rule above.
+    out.replace("->", " -> ")
+        .replace("  ", " ")
+        .replace("=>", " => ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const LIB: &str = "
+/// Docs are not part of the API.
+pub fn total(items: &[u32]) -> u32 {
+    items.iter().sum()
+}
+
+fn private() {}
+
+pub struct Calc {
+    pub base: u32,
+    cache: Vec<u32>,
+}
+
+impl Calc {
+    pub fn run(&self, x: u32) -> u32 {
+        x + self.base
+    }
+    fn helper(&self) {}
+}
+
+pub mod util {
+    pub use super::total as sum;
+}
+";
+
+    fn surface(lib: &str) -> ApiSurface {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("src")).unwrap();
+        fs::write(
+            tmp.path().join("Cargo.toml"),
+            "[package]\nname = \"demo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::write(tmp.path().join("src/lib.rs"), lib).unwrap();
+        ApiSurface::extract(tmp.path()).unwrap()
+    }
+
+    #[test]
+    fn extracts_public_items_without_bodies() {
+        let items: Vec<String> = surface(LIB)
+            .items
+            .iter()
+            .map(|(path, item)| format!("{path}: {item}"))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                "demo::Calc: pub struct Calc /* private fields */",
+                "demo::Calc::base: pub base: u32",
+                "demo::Calc::run: pub fn run(&self, x: u32) -> u32",
+                "demo::total: pub fn total(items: &[u32]) -> u32",
+                "demo::util::sum: pub use super::total as sum",
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_lists_removed_changed_and_added_items() {
+        let after = LIB
+            .replace("pub fn run(&self, x: u32)", "pub fn run(&self, x: u64)")
+            .replace("pub base: u32,", "base: u32,")
+            .replace("fn private() {}", "pub fn fresh() {}")
+            .replace("items.iter().sum()", "items.iter().copied().sum()");
+        let changes: Vec<String> = surface(LIB)
+            .diff(&surface(&after))
+            .iter()
+            .map(ApiChange::to_string)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "removed demo::Calc::base: `pub base: u32`",
+                "changed demo::Calc::run: `pub fn run(&self, x: u32) -> u32` became \
+                 `pub fn run(&self, x: u64) -> u32`",
+                "added demo::fresh: `pub fn fresh()`",
+            ]
+        );
+    }
+
+    #[test]
+    fn tidy_spaces_tokens_like_source() {
+        let sig: syn::Signature =
+            syn::parse_str("fn f<T: Clone>(x: &[T], y: Option<Vec<T>>) -> Result<(), String>")
+                .unwrap();
+        assert_eq!(
+            tidy(&tokens(&sig)),
+            "fn f<T: Clone>(x: &[T], y: Option<Vec<T>>) -> Result<(), String>"
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use quote::ToTokens;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::PathBuf,
+};
+use syn::{
+    visit::{self, Visit},
+    ImplItem, Item, TraitItem,
+};
+
+/// Identifies a function by where it is defined: the module path plus the
+/// `impl`/`trait` block it belongs to (e.g. `parser::impl Lexer`), and its name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FnKey {
+    pub container: String,
+    pub name: String,
+}
+
+impl fmt::Display for FnKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.container.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}::{}", self.container, self.name)
+        }
+    }
+}
+
+/// A free function, method or default trait method.
+#[derive(Debug, Clone)]
+pub struct FnItem {
+    pub key: FnKey,
+    /// Non-keyword identifiers used in the body (macro arguments included).
+    pub idents: BTreeSet<String>,
+    /// Names called from the body, as `name(..)` or `.name(..)`.
+    pub calls: BTreeSet<String>,
+    /// Every call as written, once per call site: `name`, `a::b::name`,
+    /// `Self::name` or `.name` for a method call.
+    pub call_paths: Vec<String>,
+    /// Statements anywhere in the body, nested ones included, as token text.
+    /// Statements of three tokens or fewer (`sum`, `i += 1`) are too common to tell
+    /// code apart and are left out.
+    pub stmts: Vec<String>,
+    /// The body as token text, free of comments and formatting.
+    pub body: String,
+}
+
+/// The functions of a set of source files and their full token sequence. Comments,
+/// whitespace and string contents never count as code.
+#[derive(Debug, Clone)]
+pub struct ItemGraph {
+    pub fns: BTreeMap<FnKey, FnItem>,
+    tokens: Vec<String>,
+}
+
+impl ItemGraph {
+    /// Parse several files into one graph. Containers are prefixed with the file
+    /// path (`src/a.rs::impl Foo`), so moves between files show up as container changes.
+    pub fn parse_files(files: &BTreeMap<PathBuf, String>) -> Result<Self, (PathBuf, syn::Error)> {
+        let mut fns = BTreeMap::new();
+        let mut tokens = vec![];
+        for (path, src) in files {
+            let file = syn::parse_file(src).map_err(|e| (path.clone(), e))?;
+            let prefix = path.to_string_lossy().replace('\\', "/");
+            collect_items(&file.items, &prefix, &mut fns);
+            tokens.extend(flatten(file.to_token_stream()));
+        }
+        Ok(Self { fns, tokens })
+    }
+
+    /// Functions with this name, in any container.
+    pub fn fns_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a FnItem> + 'a {
+        self.fns.values().filter(move |f| f.key.name == name)
+    }
+
+    /// Occurrences of identifier `name` anywhere in the file.
+    pub fn ident_count(&self, name: &str) -> usize {
+        self.tokens.iter().filter(|t| *t == name).count()
+    }
+
+    /// The file's tokens with identifiers renamed per `renames`, ignoring commas
+    /// (rustfmt adds and drops trailing ones).
+    pub fn tokens_renamed<'a>(&'a self, renames: &'a BTreeMap<String, String>) -> Vec<&'a str> {
+        self.tokens
+            .iter()
+            .filter(|t| *t != ",")
+            .map(|t| renames.get(t).unwrap_or(t).as_str())
+            .collect()
+    }
+
+    pub fn tokens(&self) -> Vec<&str> {
+        self.tokens
+            .iter()
+            .filter(|t| *t != ",")
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+fn collect_items(items: &[Item], module: &str, out: &mut BTreeMap<FnKey, FnItem>) {
+    let within = |block: &str| {
+        if module.is_empty() {
+            block.to_string()
+        } else {
+            format!("{module}::{block}")
+        }
+    };
+    for item in items {
+        match item {
+            Item::Fn(f) => add_fn(out, module.to_string(), &f.sig.ident, Some(&f.block)),
+            Item::Impl(imp) => {
+                let container = within(&impl_header(imp));
+                for it in &imp.items {
+                    if let ImplItem::Fn(m) = it {
+                        add_fn(out, container.clone(), &m.sig.ident, Some(&m.block));
+                    }
+                }
+            }
+            Item::Trait(t) => {
+                let container = within(&format!("trait {}", t.ident));
+                for it in &t.items {
+                    if let TraitItem::Fn(m) = it {
+                        add_fn(out, container.clone(), &m.sig.ident, m.default.as_ref());
+                    }
+                }
+            }
+            Item::Mod(m) => {
+                if let Some((_, items)) = &m.content {
+                    collect_items(items, &within(&m.ident.to_string()), out);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn add_fn(
+    out: &mut BTreeMap<FnKey, FnItem>,
+    container: String,
+    ident: &syn::Ident,
+    block: Option<&syn::Block>,
+) {
+    let key = FnKey {
+        container,
+        name: ident.to_string(),
+    };
+    let body = block.map(ToTokens::to_token_stream).unwrap_or_default();
+    let call_paths = call_paths(body.clone());
+    let calls = names_of(&call_paths);
+    let tokens = flatten(body);
+    let idents = tokens
+        .iter()
+        .filter(|t| is_ident(t) && !KEYWORDS.contains(&t.as_str()))
+        .cloned()
+        .collect();
+    let mut stmts = Statements(vec![]);
+    if let Some(block) = block {
+        stmts.visit_block(block);
+    }
+    out.insert(
+        key.clone(),
+        FnItem {
+            key,
+            idents,
+            calls,
+            call_paths,
+            stmts: stmts.0,
+            body: tokens.join(" "),
+        },
+    );
+}
+
+struct Statements(Vec<String>);
+
+impl<'ast> Visit<'ast> for Statements {
+    fn visit_stmt(&mut self, stmt: &'ast syn::Stmt) {
+        let tokens = flatten(stmt.to_token_stream());
+        if tokens.len() > 3 {
+            self.0.push(tokens.join(" "));
+        }
+        visit::visit_stmt(self, stmt);
+    }
+}
+
+/// `impl Type` or `impl Trait for Type`.
+pub fn impl_header(imp: &syn::ItemImpl) -> String {
+    let ty = compact(&imp.self_ty.to_token_stream());
+    match &imp.trait_ {
+        Some((_, path, _)) => format!("impl {} for {ty}", compact(&path.to_token_stream())),
+        None => format!("impl {ty}"),
+    }
+}
+
+/// Names called as `name(..)` or `.name(..)` in `ts`, including inside macro invocations.
+pub fn call_names(ts: TokenStream) -> BTreeSet<String> {
+    names_of(&call_paths(ts))
+}
+
+fn names_of(paths: &[String]) -> BTreeSet<String> {
+    paths
+        .iter()
+        .map(|p| p.rsplit("::").next().unwrap_or(p).trim_start_matches('.').to_string())
+        .collect()
+}
+
+/// Every call in `ts` as written (see [`FnItem::call_paths`]), including inside
+/// macro invocations.
+pub fn call_paths(ts: TokenStream) -> Vec<String> {
+    let mut calls = vec![];
+    collect_calls(ts, &mut calls);
+    calls
+}
+
+fn collect_calls(ts: TokenStream, calls: &mut Vec<String>) {
+    let tokens: Vec<TokenTree> = ts.into_iter().collect();
+    let punct = |i: usize, c: char| matches!(&tokens[i], TokenTree::Punct(p) if p.as_char() == c);
+    for (i, tt) in tokens.iter().enumerate() {
+        let TokenTree::Group(g) = tt else {
+            continue;
+        };
+        if let (Delimiter::Parenthesis, Some(TokenTree::Ident(name))) =
+            (g.delimiter(), i.checked_sub(1).map(|j| &tokens[j]))
+        {
+            let name = name.to_string();
+            let is_fn = i >= 2 && matches!(&tokens[i - 2], TokenTree::Ident(f) if f == "fn");
+            if !is_fn && !KEYWORDS.contains(&name.as_str()) {
+                // Walk back over `a::b::` to the start of the path.
+                let mut segments = vec![name];
+                let mut start = i - 1;
+                while start >= 3 && punct(start - 1, ':') && punct(start - 2, ':') {
+                    let TokenTree::Ident(seg) = &tokens[start - 3] else {
+                        break;
+                    };
+                    segments.push(seg.to_string());
+                    start -= 3;
+                }
+                segments.reverse();
+                let path = segments.join("::");
+                let method = start >= 1 && punct(start - 1, '.') && segments.len() == 1;
+                calls.push(if method { format!(".{path}") } else { path });
+            }
+        }
+        collect_calls(g.stream(), calls);
+    }
+}
+
+fn flatten(ts: TokenStream) -> Vec<String> {
+    let mut out = vec![];
+    flatten_into(ts, &mut out);
+    out
+}
+
+fn flatten_into(ts: TokenStream, out: &mut Vec<String>) {
+    for tt in ts {
+        match tt {
+            TokenTree::Group(g) => {
+                let (open, close) = match g.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                if !open.is_empty() {
+                    out.push(open.into());
+                }
+                flatten_into(g.stream(), out);
+                if !close.is_empty() {
+                    out.push(close.into());
+                }
+            }
+            other => out.push(other.to_string()),
+        }
+    }
+}
+
+fn is_ident(token: &str) -> bool {
+    token
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+}
+
+/// Signature, type or path tokens without most of the spaces
+/// `TokenStream::to_string` puts between every token.
+pub fn compact(ts: &TokenStream) -> String {
+    ts.to_string()
+        .replace(" :: ", "::")
+        .replace(":: ", "::")
+        .replace(" < ", "<")
+        .replace(" <", "<")
+        .replace("< ", "<")
+        .replace(" >", ">")
+        .replace("& ", "&")
+        .replace(" (", "(")
+        .replace("( ", "(")
+        .replace(" )", ")")
+        .replace("[ ", "[")
+        .replace(" ]", "]")
+        .replace(" ,", ",")
+        .replace(" :", ":")
+        .replace(" ;", ";")
+        .replace("->(", "-> (")
+}
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+];
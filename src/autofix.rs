@@ -0,0 +1,345 @@
+use anyhow::{Context, Result};
+use similar::{DiffOp, TextDiff};
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+use crate::{
+    config::VerificationConfig,
+    edits::{EditAction, EditSet, FileEdit},
+    exec::{run_cmdline, run_cmdline_with},
+    io_utils::rust_sources,
+    report::{AppliedFix, RevertedChange},
+    verification::{Diagnostic, Replacement},
+};
+
+/// Per file, the 0-based line ranges touched by the refactor.
+pub type Regions = BTreeMap<PathBuf, Vec<Range<usize>>>;
+
+/// Line ranges of `after` that differ from `before`.
+pub fn changed_regions(before: &str, after: &str) -> Vec<Range<usize>> {
+    TextDiff::from_lines(before, after)
+        .ops()
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Equal { .. }))
+        .map(|op| op.new_range())
+        .collect()
+}
+
+/// Contents of every Rust source in a tree, used to undo collateral tool edits.
+struct Snapshot(BTreeMap<PathBuf, Vec<u8>>);
+
+impl Snapshot {
+    fn take(root: &Path) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for path in rust_sources(root)? {
+            let bytes = fs::read(&path)?;
+            files.insert(path, bytes);
+        }
+        Ok(Self(files))
+    }
+
+    /// Restore every file that changed since the snapshot, except those in `keep`.
+    fn revert_except(&self, keep: &[&Path]) -> Result<Vec<PathBuf>> {
+        let mut reverted = vec![];
+        for (path, bytes) in &self.0 {
+            if keep.contains(&path.as_path()) {
+                continue;
+            }
+            if fs::read(path).ok().as_ref() != Some(bytes) {
+                fs::write(path, bytes).with_context(|| format!("revert {}", path.display()))?;
+                reverted.push(path.clone());
+            }
+        }
+        Ok(reverted)
+    }
+}
+
+/// Format only `files`. Anything else the formatter touches (e.g. child modules
+/// rustfmt follows) is reverted and returned.
+pub async fn format_scoped(
+    repo: &Path,
+    files: &[&Path],
+    cfg: &VerificationConfig,
+) -> Result<Vec<RevertedChange>> {
+    let snapshot = Snapshot::take(repo)?;
+    let args = formatter_args(repo, &cfg.fmt, files);
+    let out = run_cmdline_with(repo, &cfg.fmt, &args).await?;
+    if out.status != 0 {
+        info!(
+            "formatter exited with {}: {}",
+            out.status,
+            out.stderr.trim()
+        );
+    }
+    let reverted = snapshot.revert_except(files)?;
+    Ok(reverted
+        .into_iter()
+        .map(|p| RevertedChange {
+            path: p.strip_prefix(repo).unwrap_or(&p).to_path_buf(),
+            tool: "rustfmt".into(),
+            detail: "file is not part of the refactor".into(),
+        })
+        .collect())
+}
+
+/// Run `clippy --fix` over the crate but keep only the edits that land inside the
+/// refactored `regions`; everything else is reverted and returned.
+pub async fn clippy_fix_scoped(
+    repo: &Path,
+    regions: &Regions,
+    cfg: &VerificationConfig,
+) -> Result<Vec<RevertedChange>> {
+    let snapshot = Snapshot::take(repo)?;
+    let _ = run_cmdline(repo, &cfg.clippy_fix).await?;
+
+    let rel = |p: &Path| p.strip_prefix(repo).unwrap_or(p).to_path_buf();
+    let scoped: Vec<&Path> = regions.keys().map(PathBuf::as_path).collect();
+    let mut reverted: Vec<RevertedChange> = snapshot
+        .revert_except(&scoped)?
+        .into_iter()
+        .map(|p| RevertedChange {
+            path: rel(&p),
+            tool: "clippy --fix".into(),
+            detail: "file is not part of the refactor".into(),
+        })
+        .collect();
+
+    for (path, ranges) in regions {
+        let Some(before) = snapshot.0.get(path) else {
+            continue;
+        };
+        let before = String::from_utf8_lossy(before);
+        let after = fs::read_to_string(path)?;
+        let (merged, dropped) = keep_hunks_in(&before, &after, ranges);
+        if dropped > 0 {
+            fs::write(path, merged).with_context(|| format!("write {}", path.display()))?;
+            reverted.push(RevertedChange {
+                path: rel(path),
+                tool: "clippy --fix".into(),
+                detail: format!("{dropped} hunk(s) outside the refactored lines"),
+            });
+        }
+    }
+    Ok(reverted)
+}
+
+/// Apply the `MachineApplicable` suggestions of `diagnostics` to `files` (the
+/// current content of the touched files, by repo-relative path). A suggestion is
+/// taken only if all its edits land in `files` and none overlaps an edit already
+/// taken. Returns the edits to apply and what was fixed.
+pub fn machine_fixes(
+    diagnostics: &[Diagnostic],
+    files: &BTreeMap<PathBuf, String>,
+) -> (EditSet, Vec<AppliedFix>) {
+    let mut taken: Vec<&Replacement> = vec![];
+    let mut applied = vec![];
+    for d in diagnostics {
+        for s in &d.suggestions {
+            if s.applicability.as_deref() != Some("MachineApplicable") {
+                continue;
+            }
+            let fits = s.edits.iter().all(|e| {
+                files.get(&e.file).is_some_and(|c| {
+                    e.byte_start <= e.byte_end
+                        && c.is_char_boundary(e.byte_start)
+                        && c.is_char_boundary(e.byte_end)
+                })
+            });
+            let clashes = s.edits.iter().any(|e| {
+                taken.iter().any(|t| {
+                    t.file == e.file
+                        && (t.byte_start == e.byte_start
+                            || t.byte_start < e.byte_end && e.byte_start < t.byte_end)
+                })
+            });
+            if s.edits.is_empty() || !fits || clashes {
+                continue;
+            }
+            taken.extend(&s.edits);
+            let first = &s.edits[0];
+            applied.push(AppliedFix {
+                path: first.file.clone(),
+                line: files[&first.file][..first.byte_start].matches('\n').count() + 1,
+                diagnostic: d.headline(),
+                fix: s.message.clone(),
+            });
+        }
+    }
+
+    // Splice from the end of each file so earlier offsets stay valid.
+    taken.sort_by(|a, b| (&a.file, b.byte_start).cmp(&(&b.file, a.byte_start)));
+    let mut patched: BTreeMap<&PathBuf, String> = BTreeMap::new();
+    for e in taken {
+        let content = patched
+            .entry(&e.file)
+            .or_insert_with(|| files[&e.file].clone());
+        content.replace_range(e.byte_start..e.byte_end, &e.text);
+    }
+    let edits = patched
+        .into_iter()
+        .map(|(path, content)| FileEdit {
+            path: path.clone(),
+            action: EditAction::Modify,
+            content,
+            hunks: vec![],
+        })
+        .collect();
+    (EditSet { edits }, applied)
+}
+
+/// Rebuild `after` from `before`, taking only hunks whose old lines overlap `ranges`.
+/// Returns the merged text and the number of hunks left out.
+fn keep_hunks_in(before: &str, after: &str, ranges: &[Range<usize>]) -> (String, usize) {
+    let diff = TextDiff::from_lines(before, after);
+    let old: Vec<&str> = diff.old_slices().to_vec();
+    let new: Vec<&str> = diff.new_slices().to_vec();
+    let inside = |r: Range<usize>| {
+        ranges.iter().any(|reg| {
+            if r.is_empty() {
+                reg.start <= r.start && r.start <= reg.end
+            } else {
+                r.start < reg.end && reg.start < r.end
+            }
+        })
+    };
+
+    let mut out = String::with_capacity(after.len());
+    let mut dropped = 0;
+    for op in diff.ops() {
+        let (old_range, new_range) = (op.old_range(), op.new_range());
+        let take_new = match op {
+            DiffOp::Equal { .. } => false,
+            _ if inside(old_range.clone()) => true,
+            _ => {
+                dropped += 1;
+                false
+            }
+        };
+        if take_new {
+            new[new_range].iter().for_each(|l| out.push_str(l));
+        } else {
+            old[old_range].iter().for_each(|l| out.push_str(l));
+        }
+    }
+    (out, dropped)
+}
+
+/// Files to format, plus `--edition` from the crate manifest when invoking rustfmt directly.
+pub fn formatter_args(repo: &Path, cmdline: &str, files: &[&Path]) -> Vec<String> {
+    let mut args = vec![];
+    let is_rustfmt = cmdline.split_whitespace().next() == Some("rustfmt");
+    if is_rustfmt && !cmdline.contains("--edition") {
+        args.push("--edition".to_string());
+        args.push(crate_edition(repo));
+    }
+    args.extend(files.iter().map(|f| f.to_string_lossy().to_string()));
+    args
+}
+
+/// `package.edition` from `<repo>/Cargo.toml`; 2021 when it is inherited or unreadable.
+fn crate_edition(repo: &Path) -> String {
+    let manifest = fs::read_to_string(repo.join("Cargo.toml")).unwrap_or_default();
+    let Ok(value) = manifest.parse::<toml::Table>() else {
+        return "2021".into();
+    };
+    match value.get("package").and_then(|p| p.get("edition")) {
+        Some(toml::Value::String(e)) => e.clone(),
+        Some(_) => "2021".into(),
+        None if value.contains_key("package") => "2015".into(),
+        None => "2021".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verification::{Span, Suggestion};
+    use pretty_assertions::assert_eq;
+
+    const MAIN: &str = "fn main() {\n    let x = 1;\n    let y = 2;\n}\n";
+
+    fn unused(name: &str, applicability: &str, file: &str) -> Diagnostic {
+        let byte_start = MAIN.find(&format!("let {name}")).unwrap() + 4;
+        Diagnostic {
+            level: "warning".into(),
+            code: Some("unused_variables".into()),
+            message: format!("unused variable: `{name}`"),
+            span: Some(Span {
+                file: PathBuf::from(file),
+                line: MAIN[..byte_start].matches('\n').count() + 1,
+                column: 9,
+            }),
+            suggestions: vec![Suggestion {
+                message: "prefix it with an underscore".into(),
+                applicability: Some(applicability.into()),
+                edits: vec![Replacement {
+                    file: PathBuf::from(file),
+                    byte_start,
+                    byte_end: byte_start + 1,
+                    text: format!("_{name}"),
+                }],
+            }],
+            rendered: String::new(),
+        }
+    }
+
+    #[test]
+    fn machine_fixes_take_only_applicable_edits_in_the_touched_files() {
+        let files = BTreeMap::from([(PathBuf::from("src/main.rs"), MAIN.to_string())]);
+        let diagnostics = [
+            unused("x", "MachineApplicable", "src/main.rs"),
+            unused("y", "MaybeIncorrect", "src/main.rs"),
+            unused("x", "MachineApplicable", "src/other.rs"),
+            // The same fix again overlaps the first one.
+            unused("x", "MachineApplicable", "src/main.rs"),
+        ];
+        let (edits, applied) = machine_fixes(&diagnostics, &files);
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].path, PathBuf::from("src/main.rs"));
+        assert_eq!(applied[0].line, 2);
+        assert_eq!(edits.edits.len(), 1);
+        assert_eq!(
+            edits.edits[0].content,
+            "fn main() {\n    let _x = 1;\n    let y = 2;\n}\n"
+        );
+    }
+
+    #[test]
+    fn keeps_only_hunks_inside_the_regions() {
+        let before = "a\nb\nc\nd\ne\n";
+        let after = "A\nb\nc\nd\nE\n";
+        assert_eq!(changed_regions(before, after), vec![0..1, 4..5]);
+        let last_lines = 3..5;
+        let (merged, dropped) = keep_hunks_in(before, after, &[last_lines]);
+        assert_eq!(merged, "a\nb\nc\nd\nE\n");
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn rustfmt_gets_the_crate_edition() {
+        let tmp = tempfile::tempdir().unwrap();
+        let files = [Path::new("src/main.rs")];
+        fs::write(
+            tmp.path().join("Cargo.toml"),
+            "[package]\nname = \"demo\"\nedition = \"2018\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            formatter_args(tmp.path(), "rustfmt", &files),
+            vec!["--edition", "2018", "src/main.rs"]
+        );
+        assert_eq!(
+            formatter_args(tmp.path(), "rustfmt --edition 2021", &files),
+            vec!["src/main.rs"]
+        );
+        assert_eq!(formatter_args(tmp.path(), "cargo fmt --", &files), vec!["src/main.rs"]);
+
+        fs::write(tmp.path().join("Cargo.toml"), "[package]\nname = \"demo\"\n").unwrap();
+        assert_eq!(crate_edition(tmp.path()), "2015");
+    }
+}
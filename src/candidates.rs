@@ -0,0 +1,218 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::{fmt, path::Path};
+
+use crate::{
+    ast::ItemGraph,
+    config::{AgentSettings, PathsConfig, RefactorKind, VerificationConfig},
+    edits::{EditSet, Workspace},
+    sandbox::{Sandbox, SandboxMode},
+    txn::Transaction,
+    verification::{cargo_check, verify_refactor},
+};
+
+/// Temperature added per extra candidate, so later samples explore more.
+const TEMPERATURE_STEP: f64 = 0.2;
+
+/// Sampling settings for candidate `index`: the first uses the Developer's own
+/// settings, later ones a higher temperature and a different seed.
+pub fn sampling(base: &AgentSettings, index: u32) -> AgentSettings {
+    let mut settings = base.clone();
+    if index > 0 {
+        settings.temperature = (base.temperature + TEMPERATURE_STEP * f64::from(index)).min(1.0);
+        settings.seed = Some(base.seed.unwrap_or(0) + i64::from(index));
+    }
+    settings
+}
+
+/// How a candidate fared on the cheap checks, best first when sorted by [`Self::rank`].
+#[derive(Debug, Clone, Serialize)]
+pub struct CandidateScore {
+    /// 1-based, in sampling order.
+    pub index: u32,
+    pub temperature: f64,
+    pub seed: Option<i64>,
+    /// The candidate was generated and went through the checks below.
+    pub checked: bool,
+    /// Every touched Rust file parses.
+    pub parses: bool,
+    /// The structural check for the refactor kind passed.
+    pub refactor_verified: bool,
+    /// `verification.check` passed.
+    pub builds: bool,
+    pub errors: usize,
+    /// Why the candidate lost points, or why it could not be generated at all.
+    pub notes: Vec<String>,
+}
+
+impl CandidateScore {
+    /// A candidate that never reached the checks, e.g. because the Developer
+    /// produced no usable edits for it.
+    pub fn failed(index: u32, settings: &AgentSettings, why: String) -> Self {
+        Self {
+            index,
+            temperature: settings.temperature,
+            seed: settings.seed,
+            checked: false,
+            parses: false,
+            refactor_verified: false,
+            builds: false,
+            errors: 0,
+            notes: vec![why],
+        }
+    }
+
+    /// Points for the checks passed. The refactor counts most, since the Repair
+    /// loop can fix a build but not a missing refactor; parsing counts least.
+    pub fn points(&self) -> u32 {
+        4 * u32::from(self.refactor_verified) + 2 * u32::from(self.builds) + u32::from(self.parses)
+    }
+
+    /// Ordering key: more points, then fewer compile errors, then sampled earlier.
+    pub fn rank(&self) -> (u32, std::cmp::Reverse<usize>, std::cmp::Reverse<u32>) {
+        (
+            self.points(),
+            std::cmp::Reverse(self.errors),
+            std::cmp::Reverse(self.index),
+        )
+    }
+}
+
+impl fmt::Display for CandidateScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.checked {
+            return write!(
+                f,
+                "candidate {} (temperature={}): not checked; {}",
+                self.index,
+                self.temperature,
+                self.notes.join("; ")
+            );
+        }
+        let mark = |ok: bool| if ok { "ok" } else { "failed" };
+        write!(
+            f,
+            "candidate {} (temperature={}, {} point(s)): parse {}, refactor {}, check {}",
+            self.index,
+            self.temperature,
+            self.points(),
+            mark(self.parses),
+            mark(self.refactor_verified),
+            mark(self.builds),
+        )?;
+        if self.errors > 0 {
+            write!(f, " with {} error(s)", self.errors)?;
+        }
+        if !self.notes.is_empty() {
+            write!(f, "; {}", self.notes.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply `edits` to a scratch copy of `repo` and run the cheap checks on it: do
+/// the touched files parse, did the refactor happen, does `verification.check` pass.
+#[allow(clippy::too_many_arguments)]
+pub async fn score(
+    index: u32,
+    settings: &AgentSettings,
+    repo: &Path,
+    rel_file: &Path,
+    edits: &EditSet,
+    kind: RefactorKind,
+    paths: &PathsConfig,
+    cfg: &VerificationConfig,
+) -> Result<CandidateScore> {
+    let sandbox = Sandbox::create(repo, SandboxMode::Copy, false).await?;
+    let mut workspace = Workspace::new(sandbox.root(), &[rel_file]);
+    // `develop` already held the edits to the files the Developer was shown.
+    for e in &edits.edits {
+        workspace.allow(&e.path);
+    }
+    // Writes to an isolated sandbox never go through the transaction.
+    workspace.apply(edits, paths, &sandbox, &Transaction::new())?;
+    let changes = workspace.changes()?;
+
+    let mut notes = vec![];
+    let parses = match ItemGraph::parse_files(&changes.after) {
+        Ok(_) => true,
+        Err((path, e)) => {
+            notes.push(format!("{} does not parse: {e}", path.display()));
+            false
+        }
+    };
+    let check = verify_refactor(kind, &changes.before, &changes.after);
+    if !check.verified {
+        notes.push(check.detail);
+    }
+    // Candidates are checked side by side, so each builds in its own sandbox's directory.
+    let (builds, diagnostics) = cargo_check(sandbox.root(), cfg, sandbox.target_dir()).await?;
+    let errors = diagnostics.iter().filter(|d| d.level == "error").count();
+    if let Some(first) = diagnostics.iter().find(|d| d.level == "error") {
+        notes.push(first.headline());
+    }
+    Ok(CandidateScore {
+        index,
+        temperature: settings.temperature,
+        seed: settings.seed,
+        checked: true,
+        parses,
+        refactor_verified: check.verified,
+        builds,
+        errors,
+        notes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn settings() -> AgentSettings {
+        AgentSettings {
+            model: "m".into(),
+            temperature: 0.1,
+            top_p: None,
+            max_tokens: None,
+            seed: None,
+        }
+    }
+
+    fn scored(index: u32, refactor_verified: bool, builds: bool, errors: usize) -> CandidateScore {
+        CandidateScore {
+            checked: true,
+            parses: true,
+            refactor_verified,
+            builds,
+            errors,
+            notes: vec![],
+            ..CandidateScore::failed(index, &settings(), String::new())
+        }
+    }
+
+    #[test]
+    fn later_candidates_sample_hotter_with_other_seeds() {
+        let base = settings();
+        assert_eq!(sampling(&base, 0), base);
+        let third = sampling(&base, 2);
+        assert!((third.temperature - 0.5).abs() < 1e-9, "{}", third.temperature);
+        assert_eq!(third.seed, Some(2));
+        assert_eq!(sampling(&base, 9).temperature, 1.0);
+    }
+
+    #[test]
+    fn ranks_refactor_over_build_then_fewer_errors_then_order() {
+        let mut scores = [
+            scored(1, false, true, 0),
+            scored(2, true, false, 3),
+            scored(3, true, false, 1),
+            scored(4, true, false, 1),
+        ];
+        scores.sort_by_key(|s| std::cmp::Reverse(s.rank()));
+        let order: Vec<u32> = scores.iter().map(|s| s.index).collect();
+        assert_eq!(order, vec![3, 4, 2, 1]);
+        assert_eq!(scores[0].points(), 5);
+        assert_eq!(scores[3].points(), 3);
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::info;
+
+use crate::llm::{ChatRequest, LlmBackend};
+
+/// One recorded prompt/response pair, stored as `<dir>/<role>-<hash>-<n>.json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cassette {
+    pub key: String,
+    pub request: ChatRequest,
+    pub response: String,
+}
+
+/// Stable key for a request: SHA-256 over its JSON form (role, model, prompts, sampling).
+pub fn request_key(req: &ChatRequest) -> Result<String> {
+    let json = serde_json::to_vec(req)?;
+    let digest = Sha256::digest(&json);
+    Ok(digest.iter().take(8).map(|b| format!("{b:02x}")).collect())
+}
+
+/// Tracks how many times each key was seen so identical requests made more
+/// than once in a run map to distinct cassettes.
+struct Occurrences(Mutex<HashMap<String, usize>>);
+
+impl Occurrences {
+    fn new() -> Self {
+        Self(Mutex::new(HashMap::new()))
+    }
+
+    fn next(&self, key: &str) -> usize {
+        let mut seen = self.0.lock().unwrap();
+        let n = seen.entry(key.to_string()).or_insert(0);
+        *n += 1;
+        *n
+    }
+}
+
+fn cassette_path(dir: &Path, req: &ChatRequest, key: &str, n: usize) -> PathBuf {
+    dir.join(format!("{}-{}-{}.json", req.role, key, n))
+}
+
+/// Forwards to an inner backend and saves every exchange to disk.
+pub struct RecordingBackend {
+    inner: Box<dyn LlmBackend>,
+    dir: PathBuf,
+    seen: Occurrences,
+}
+
+impl RecordingBackend {
+    pub fn new(inner: Box<dyn LlmBackend>, dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("create cassette dir {}", dir.display()))?;
+        Ok(Self {
+            inner,
+            dir: dir.to_path_buf(),
+            seen: Occurrences::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmBackend for RecordingBackend {
+    async fn complete(&self, req: &ChatRequest) -> Result<String> {
+        let response = self.inner.complete(req).await?;
+        let key = request_key(req)?;
+        let n = self.seen.next(&key);
+        let path = cassette_path(&self.dir, req, &key, n);
+        let cassette = Cassette {
+            key,
+            request: req.clone(),
+            response: response.clone(),
+        };
+        fs::write(&path, serde_json::to_string_pretty(&cassette)?)
+            .with_context(|| format!("write cassette {}", path.display()))?;
+        info!("Recorded {} exchange to {}", req.role, path.display());
+        Ok(response)
+    }
+}
+
+/// Serves responses from cassettes written by [`RecordingBackend`]; never touches the network.
+pub struct ReplayBackend {
+    dir: PathBuf,
+    seen: Occurrences,
+}
+
+impl ReplayBackend {
+    pub fn new(dir: &Path) -> Result<Self> {
+        anyhow::ensure!(
+            dir.is_dir(),
+            "cassette dir {} does not exist",
+            dir.display()
+        );
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            seen: Occurrences::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmBackend for ReplayBackend {
+    async fn complete(&self, req: &ChatRequest) -> Result<String> {
+        let key = request_key(req)?;
+        let n = self.seen.next(&key);
+        let path = cassette_path(&self.dir, req, &key, n);
+        let raw = fs::read_to_string(&path).with_context(|| {
+            format!(
+                "replay: no cassette for {} request {} (occurrence {}); the prompt differs from the recorded run",
+                req.role, key, n
+            )
+        })?;
+        let cassette: Cassette = serde_json::from_str(&raw)
+            .with_context(|| format!("replay: malformed cassette {}", path.display()))?;
+        Ok(cassette.response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::AgentSettings, llm::AgentRole};
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers `answer 1`, `answer 2`, … whatever it is asked.
+    struct Counter(AtomicUsize);
+
+    #[async_trait]
+    impl LlmBackend for Counter {
+        async fn complete(&self, _req: &ChatRequest) -> Result<String> {
+            Ok(format!("answer {}", self.0.fetch_add(1, Ordering::SeqCst) + 1))
+        }
+    }
+
+    fn request(user: &str) -> ChatRequest {
+        ChatRequest {
+            role: AgentRole::Developer,
+            settings: AgentSettings {
+                model: "m".into(),
+                temperature: 0.0,
+                top_p: None,
+                max_tokens: None,
+                seed: None,
+            },
+            system: "system".into(),
+            user: user.into(),
+            schema: None,
+        }
+    }
+
+    #[test]
+    fn key_covers_the_whole_request() {
+        let key = request_key(&request("a")).unwrap();
+        assert_eq!(key, request_key(&request("a")).unwrap());
+        assert_ne!(key, request_key(&request("b")).unwrap());
+        let mut hotter = request("a");
+        hotter.settings.temperature = 0.5;
+        assert_ne!(key, request_key(&hotter).unwrap());
+    }
+
+    #[tokio::test]
+    async fn replay_serves_repeated_requests_in_recorded_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = RecordingBackend::new(Box::new(Counter(AtomicUsize::new(0))), dir.path())
+            .unwrap();
+        let mut recorded = vec![];
+        for user in ["a", "a", "b"] {
+            recorded.push(recorder.complete(&request(user)).await.unwrap());
+        }
+        assert_eq!(recorded, vec!["answer 1", "answer 2", "answer 3"]);
+
+        let replay = ReplayBackend::new(dir.path()).unwrap();
+        let mut replayed = vec![];
+        for user in ["b", "a", "a"] {
+            replayed.push(replay.complete(&request(user)).await.unwrap());
+        }
+        assert_eq!(replayed, vec!["answer 3", "answer 1", "answer 2"]);
+
+        // A third identical request, or a new prompt, was never recorded.
+        assert!(replay.complete(&request("a")).await.is_err());
+        let err = replay.complete(&request("c")).await.unwrap_err();
+        assert!(format!("{err:#}").contains("the prompt differs"), "{err:#}");
+    }
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::{
+    sandbox::{Appended, Sandbox},
+    txn::Transaction,
+};
+
+/// Name of the module the tests are appended in, as it shows in test names.
+pub const MODULE: &str = "mantra_characterization";
+
+/// Tests that pin the behavior of the original code. They are never part of the
+/// candidate: the module is appended to the target file only while it is tested.
+#[derive(Debug, Clone, Serialize)]
+pub struct CharacterizationTests {
+    /// Repo-relative file the module goes into.
+    pub file: PathBuf,
+    /// Body of the module, as the TestWriter wrote it.
+    pub code: String,
+    /// `#[test]` functions in `code`.
+    pub count: usize,
+}
+
+impl CharacterizationTests {
+    pub fn new(file: &Path, code: String) -> Self {
+        let count = syn::parse_file(&code)
+            .map(|f| f.items.iter().filter(|item| is_test(item)).count())
+            .unwrap_or(0);
+        Self {
+            file: file.to_path_buf(),
+            code,
+            count,
+        }
+    }
+
+    fn module(&self) -> String {
+        format!(
+            "\n#[cfg(test)]\n#[allow(unused_imports)]\nmod {MODULE} {{\n    use super::*;\n\n{}\n}}\n",
+            self.code.trim_end()
+        )
+    }
+
+    /// Append the tests to the target file under `repo`; they stay there until the
+    /// returned guard is dropped. `None` if the file no longer exists.
+    pub fn install(
+        &self,
+        repo: &Path,
+        sandbox: &Sandbox,
+        txn: &Transaction,
+    ) -> Result<Option<Appended>> {
+        let path = repo.join(&self.file);
+        if !path.is_file() {
+            warn!(
+                "{} no longer exists; characterization tests not run",
+                self.file.display()
+            );
+            return Ok(None);
+        }
+        Ok(Some(sandbox.append_temporarily(&path, &self.module(), txn)?))
+    }
+
+    /// What the Repair agent needs to know about test failures under [`MODULE`].
+    pub fn explain(&self) -> String {
+        format!(
+            "Characterization tests (appended to {} as `mod {MODULE}` while testing). They \
+             pass on the original code, so a failure means the candidate changed behavior; \
+             fix the code, not the tests:\n```rust\n{}\n```\n",
+            self.file.display(),
+            self.code.trim_end()
+        )
+    }
+}
+
+/// `#[test]`, `#[tokio::test]` and the like.
+fn is_test(item: &syn::Item) -> bool {
+    let syn::Item::Fn(f) = item else {
+        return false;
+    };
+    f.attrs
+        .iter()
+        .any(|a| a.path().segments.last().is_some_and(|s| s.ident == "test"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::SandboxMode;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    const TESTS: &str = "\
+fn input() -> Vec<u32> {
+    vec![1, 2]
+}
+
+#[test]
+fn sums() {
+    assert_eq!(total(&input()), 3);
+}
+
+#[tokio::test]
+async fn sums_empty() {
+    assert_eq!(total(&[]), 0);
+}
+";
+
+    #[test]
+    fn counts_only_test_functions() {
+        let tests = CharacterizationTests::new(Path::new("src/lib.rs"), TESTS.into());
+        assert_eq!(tests.count, 2);
+        let broken = CharacterizationTests::new(Path::new("src/lib.rs"), "fn (".into());
+        assert_eq!(broken.count, 0);
+    }
+
+    #[tokio::test]
+    async fn install_appends_the_module_until_dropped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let original = "pub fn total(xs: &[u32]) -> u32 {\n    xs.iter().sum()\n}\n";
+        fs::create_dir_all(tmp.path().join("src")).unwrap();
+        fs::write(tmp.path().join("src/lib.rs"), original).unwrap();
+        let sandbox = Sandbox::create(tmp.path(), SandboxMode::None, false)
+            .await
+            .unwrap();
+        let txn = Transaction::new();
+
+        let tests = CharacterizationTests::new(Path::new("src/lib.rs"), TESTS.into());
+        let guard = tests.install(tmp.path(), &sandbox, &txn).unwrap();
+        assert!(guard.is_some());
+        let installed = fs::read_to_string(tmp.path().join("src/lib.rs")).unwrap();
+        assert!(installed.starts_with(original));
+        assert!(
+            installed.contains(&format!("mod {MODULE} {{\n    use super::*;\n\nfn input()")),
+            "{installed}"
+        );
+        syn::parse_file(&installed).unwrap();
+        drop(guard);
+        assert_eq!(fs::read_to_string(tmp.path().join("src/lib.rs")).unwrap(), original);
+
+        let gone = CharacterizationTests::new(Path::new("src/gone.rs"), TESTS.into());
+        assert!(gone.install(tmp.path(), &sandbox, &txn).unwrap().is_none());
+    }
+}
@@ -1,9 +1,22 @@
-use anyhow::{ensure, Result};
-use clap::{Parser, ValueEnum};
-use std::path::PathBuf;
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
 
-/// Refactoring kinds we “verify” heuristically for Rust.
+use crate::{llm::AgentRole, sandbox::SandboxMode};
+
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+
+/// Name of the project config file, looked up at the repo root and in `~/.config/mantra/`.
+pub const CONFIG_FILE_NAME: &str = "mantra.toml";
+
+/// Refactoring kinds the Reviewer verifies structurally on the parsed file.
 #[derive(Debug, Clone, ValueEnum)]
+#[allow(clippy::enum_variant_names)]
 pub enum RefactorKind {
     ExtractMethod,
     InlineMethod,
@@ -11,53 +24,873 @@ pub enum RefactorKind {
     RenameMethod,
 }
 
+/// What to leave behind when the Repair loop runs out of attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnRepairFailure {
+    /// Ask which to keep (the original with `--yes`)
+    Ask,
+    /// Restore the original files
+    Original,
+    /// Keep the best-scoring attempt
+    Best,
+}
+
+/// Where model completions come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LlmProvider {
+    /// OpenAI chat completions API
+    Openai,
+    /// Local Ollama server (OpenAI-compatible `/v1` endpoint)
+    Ollama,
+    /// llama.cpp `llama-server`
+    LlamaCpp,
+    /// vLLM OpenAI-compatible server
+    Vllm,
+    /// Any other OpenAI-compatible server; requires `--api-base`
+    Compatible,
+    /// Canned responses read from `--mock-dir` (no network)
+    Mock,
+}
+
+impl LlmProvider {
+    /// Base URL used when `--api-base` is not given.
+    pub fn default_api_base(self) -> Option<&'static str> {
+        match self {
+            LlmProvider::Openai => Some("https://api.openai.com/v1"),
+            LlmProvider::Ollama => Some("http://localhost:11434/v1"),
+            LlmProvider::LlamaCpp => Some("http://localhost:8080/v1"),
+            LlmProvider::Vllm => Some("http://localhost:8000/v1"),
+            LlmProvider::Compatible | LlmProvider::Mock => None,
+        }
+    }
+
+    /// Env var holding the API key when `--api-key-env` is not given.
+    /// Local servers usually accept any key, so they don't read one by default.
+    pub fn default_api_key_env(self) -> Option<&'static str> {
+        match self {
+            LlmProvider::Openai => Some("OPENAI_API_KEY"),
+            _ => None,
+        }
+    }
+}
+
+/// Resolved endpoint settings for an OpenAI-compatible backend.
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    pub api_base: String,
+    pub api_key: String,
+    /// Send JSON schemas as `response_format` (structured outputs).
+    pub structured_output: bool,
+}
+
+/// Model and sampling settings one agent sends with every request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentSettings {
+    pub model: String,
+    pub temperature: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+impl fmt::Display for AgentSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "model={} temperature={}", self.model, self.temperature)?;
+        if let Some(p) = self.top_p {
+            write!(f, " top_p={p}")?;
+        }
+        if let Some(n) = self.max_tokens {
+            write!(f, " max_tokens={n}")?;
+        }
+        if let Some(s) = self.seed {
+            write!(f, " seed={s}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Partial [`AgentSettings`]; unset fields fall through to the next layer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentOverrides {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub seed: Option<i64>,
+}
+
+impl AgentOverrides {
+    /// Overlay `other` on top of `self`; fields set in `other` win.
+    pub fn merge(&mut self, other: &AgentOverrides) {
+        if other.model.is_some() {
+            self.model.clone_from(&other.model);
+        }
+        self.temperature = other.temperature.or(self.temperature);
+        self.top_p = other.top_p.or(self.top_p);
+        self.max_tokens = other.max_tokens.or(self.max_tokens);
+        self.seed = other.seed.or(self.seed);
+    }
+
+    /// Apply a single `key=value` assignment (as used by `--agent-set`).
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "model" => self.model = Some(value.to_string()),
+            "temperature" => self.temperature = Some(value.parse()?),
+            "top_p" => self.top_p = Some(value.parse()?),
+            "max_tokens" => self.max_tokens = Some(value.parse()?),
+            "seed" => self.seed = Some(value.parse()?),
+            other => bail!("unknown agent setting `{other}`"),
+        }
+        Ok(())
+    }
+
+    fn resolve(&self) -> AgentSettings {
+        AgentSettings {
+            model: self
+                .model
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            temperature: self.temperature.unwrap_or(0.0),
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+        }
+    }
+}
+
+/// The `[agents]` table: shared defaults plus `[agents.developer]`, `[agents.reviewer]`,
+/// `[agents.repair]` and `[agents.test_writer]` sections.
+///
+/// The shared defaults are spelled out rather than a flattened [`AgentOverrides`]:
+/// serde can't deny unknown fields next to `flatten`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentsConfig {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub seed: Option<i64>,
+    pub developer: AgentOverrides,
+    pub reviewer: AgentOverrides,
+    pub repair: AgentOverrides,
+    pub test_writer: AgentOverrides,
+}
+
+impl AgentsConfig {
+    /// The shared `[agents]` values, which every agent starts from.
+    fn defaults(&self) -> AgentOverrides {
+        AgentOverrides {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+        }
+    }
+
+    fn for_role(&self, role: AgentRole) -> &AgentOverrides {
+        match role {
+            AgentRole::Developer => &self.developer,
+            AgentRole::Reviewer => &self.reviewer,
+            AgentRole::Repair => &self.repair,
+            AgentRole::TestWriter => &self.test_writer,
+        }
+    }
+
+    fn for_role_mut(&mut self, role: AgentRole) -> &mut AgentOverrides {
+        match role {
+            AgentRole::Developer => &mut self.developer,
+            AgentRole::Reviewer => &mut self.reviewer,
+            AgentRole::Repair => &mut self.repair,
+            AgentRole::TestWriter => &mut self.test_writer,
+        }
+    }
+}
+
+/// `[llm]` section of `mantra.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmSection {
+    pub provider: Option<LlmProvider>,
+    pub api_base: Option<String>,
+    pub api_key_env: Option<String>,
+    pub structured_output: Option<bool>,
+}
+
+/// `[rag]` section of `mantra.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RagSection {
+    pub dir: Option<PathBuf>,
+    pub max_examples: Option<usize>,
+}
+
+/// `[analysis]` section of `mantra.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalysisSection {
+    /// Size budget, in characters, of the crate summary given to the agents.
+    pub max_chars: Option<usize>,
+}
+
+/// `[verification]` section of `mantra.toml`. Commands are split on whitespace
+/// (no shell quoting) and run from the repo root. `fmt` and `fmt_check` get the
+/// refactored files appended.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerificationSection {
+    pub check: Option<String>,
+    pub test: Option<String>,
+    pub fmt: Option<String>,
+    pub fmt_check: Option<String>,
+    pub clippy_fix: Option<String>,
+    pub clippy_check: Option<String>,
+    pub max_repairs: Option<u32>,
+    pub max_revisions: Option<u32>,
+    pub max_edit_retries: Option<u32>,
+    pub candidates: Option<u32>,
+    pub on_repair_failure: Option<OnRepairFailure>,
+    pub characterize: Option<bool>,
+    pub differential: Option<bool>,
+    pub allow_api_change: Option<bool>,
+}
+
+/// `[sandbox]` section of `mantra.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxSection {
+    pub mode: Option<SandboxMode>,
+}
+
+/// `[paths]` section of `mantra.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathsSection {
+    /// Globs, relative to the repo root, of files the agents may modify.
+    pub allowed: Option<Vec<String>>,
+}
+
+/// `[prompts]` section of `mantra.toml`: replacement system prompts per agent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptsSection {
+    pub developer: Option<String>,
+    pub reviewer: Option<String>,
+    pub repair: Option<String>,
+    pub test_writer: Option<String>,
+}
+
+/// One layer of settings, as read from a `mantra.toml` or built from CLI flags.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub llm: LlmSection,
+    pub agents: AgentsConfig,
+    pub rag: RagSection,
+    pub analysis: AnalysisSection,
+    pub verification: VerificationSection,
+    pub paths: PathsSection,
+    pub prompts: PromptsSection,
+    pub sandbox: SandboxSection,
+}
+
+impl FileConfig {
+    /// Parse a config file. Relative paths inside it are taken relative to the file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("read config {}", path.display()))?;
+        let mut config: FileConfig =
+            toml::from_str(&text).with_context(|| format!("parse config {}", path.display()))?;
+        if let (Some(dir), Some(base)) = (config.rag.dir.as_mut(), path.parent()) {
+            if dir.is_relative() {
+                *dir = base.join(&*dir);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overlay `other` on top of `self`; everything set in `other` wins.
+    /// Agent settings are layered separately, see [`Config::resolve`].
+    fn merge(&mut self, other: &FileConfig) {
+        fn pick<T: Clone>(dst: &mut Option<T>, src: &Option<T>) {
+            if src.is_some() {
+                dst.clone_from(src);
+            }
+        }
+        pick(&mut self.llm.provider, &other.llm.provider);
+        pick(&mut self.llm.api_base, &other.llm.api_base);
+        pick(&mut self.llm.api_key_env, &other.llm.api_key_env);
+        pick(&mut self.llm.structured_output, &other.llm.structured_output);
+        pick(&mut self.rag.dir, &other.rag.dir);
+        pick(&mut self.rag.max_examples, &other.rag.max_examples);
+        pick(&mut self.analysis.max_chars, &other.analysis.max_chars);
+        let (v, o) = (&mut self.verification, &other.verification);
+        pick(&mut v.check, &o.check);
+        pick(&mut v.test, &o.test);
+        pick(&mut v.fmt, &o.fmt);
+        pick(&mut v.fmt_check, &o.fmt_check);
+        pick(&mut v.clippy_fix, &o.clippy_fix);
+        pick(&mut v.clippy_check, &o.clippy_check);
+        pick(&mut v.max_repairs, &o.max_repairs);
+        pick(&mut v.max_revisions, &o.max_revisions);
+        pick(&mut v.max_edit_retries, &o.max_edit_retries);
+        pick(&mut v.candidates, &o.candidates);
+        pick(&mut v.on_repair_failure, &o.on_repair_failure);
+        pick(&mut v.characterize, &o.characterize);
+        pick(&mut v.differential, &o.differential);
+        pick(&mut v.allow_api_change, &o.allow_api_change);
+        pick(&mut self.paths.allowed, &other.paths.allowed);
+        pick(&mut self.prompts.developer, &other.prompts.developer);
+        pick(&mut self.prompts.reviewer, &other.prompts.reviewer);
+        pick(&mut self.prompts.repair, &other.prompts.repair);
+        pick(&mut self.prompts.test_writer, &other.prompts.test_writer);
+        pick(&mut self.sandbox.mode, &other.sandbox.mode);
+    }
+}
+
+/// `~/.config/mantra/mantra.toml` (honours `XDG_CONFIG_HOME`).
+pub fn user_config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(base.join("mantra").join(CONFIG_FILE_NAME))
+}
+
+/// Resolved endpoint selection for the LLM backend.
+#[derive(Debug, Clone, Serialize)]
+pub struct LlmConfig {
+    pub provider: LlmProvider,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_base: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    /// Ask for JSON-schema constrained answers where an agent expects JSON.
+    /// Turn off for servers that reject `response_format`.
+    pub structured_output: bool,
+}
+
+impl LlmConfig {
+    /// Endpoint and key for the selected provider, with overrides applied.
+    pub fn api_config(&self) -> Result<ApiConfig> {
+        let api_base = self
+            .api_base
+            .clone()
+            .or_else(|| self.provider.default_api_base().map(str::to_string))
+            .with_context(|| format!("no API base URL for provider {:?}", self.provider))?;
+        let api_key = match self.api_key_env.as_deref() {
+            Some(var) => std::env::var(var)
+                .with_context(|| format!("api_key_env: environment variable {var} is not set"))?,
+            None => self
+                .provider
+                .default_api_key_env()
+                .and_then(|var| std::env::var(var).ok())
+                .unwrap_or_default(),
+        };
+        Ok(ApiConfig {
+            api_base,
+            api_key,
+            structured_output: self.structured_output,
+        })
+    }
+}
+
+/// Resolved settings for every agent.
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentProfiles {
+    pub developer: AgentSettings,
+    pub reviewer: AgentSettings,
+    pub repair: AgentSettings,
+    pub test_writer: AgentSettings,
+}
+
+/// Few-shot example lookup.
+#[derive(Debug, Clone, Serialize)]
+pub struct RagConfig {
+    pub dir: PathBuf,
+    pub max_examples: usize,
+}
+
+/// In-process static analysis of the target crate.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalysisConfig {
+    pub max_chars: usize,
+}
+
+/// Commands used to format, lint, build and test the target repo.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerificationConfig {
+    pub check: String,
+    pub test: String,
+    pub fmt: String,
+    pub fmt_check: String,
+    pub clippy_fix: String,
+    pub clippy_check: String,
+    pub max_repairs: u32,
+    /// Reviewer "revise" rounds before the candidate goes to the build anyway.
+    pub max_revisions: u32,
+    /// Times an agent is re-asked when its answer can't be used: no usable edits
+    /// (missing or unterminated code blocks), hunks that don't apply, or a Reviewer
+    /// verdict that isn't valid JSON.
+    pub max_edit_retries: u32,
+    /// Developer candidates sampled per round; the best after the cheap checks is reviewed.
+    pub candidates: u32,
+    /// What to keep when `max_repairs` runs out or the repairs go in circles.
+    pub on_repair_failure: OnRepairFailure,
+    /// Have the TestWriter pin the original behavior in tests before Develop.
+    pub characterize: bool,
+    /// Compare refactored functions with the originals on generated inputs before
+    /// accepting an extract or inline refactor.
+    pub differential: bool,
+    /// Accept candidates that change the public API of a library crate.
+    pub allow_api_change: bool,
+}
+
+/// Files the pipeline is allowed to touch.
+#[derive(Debug, Clone, Serialize)]
+pub struct PathsConfig {
+    pub allowed: Vec<String>,
+    #[serde(skip)]
+    matcher: GlobSet,
+}
+
+impl PathsConfig {
+    pub fn new(allowed: Vec<String>) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &allowed {
+            builder.add(Glob::new(pattern).with_context(|| format!("bad path glob `{pattern}`"))?);
+        }
+        Ok(Self {
+            matcher: builder.build()?,
+            allowed,
+        })
+    }
+
+    /// Whether a repo-relative path may be modified.
+    pub fn is_allowed(&self, rel: &Path) -> bool {
+        self.matcher.is_match(rel)
+    }
+}
+
+/// Where the pipeline runs; see [`SandboxMode`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SandboxConfig {
+    pub mode: SandboxMode,
+}
+
+/// Effective configuration: built-in defaults < user config < project `mantra.toml`
+/// < `--config` < CLI flags.
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    pub llm: LlmConfig,
+    pub agents: AgentProfiles,
+    pub rag: RagConfig,
+    pub analysis: AnalysisConfig,
+    pub verification: VerificationConfig,
+    pub paths: PathsConfig,
+    pub prompts: PromptsSection,
+    pub sandbox: SandboxConfig,
+    /// Config files that contributed, lowest precedence first.
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+impl Config {
+    /// Discover config files for `repo` and merge them with the CLI flags.
+    pub fn load(cli: &Cli, repo: &Path) -> Result<Self> {
+        let mut candidates: Vec<PathBuf> = user_config_path().into_iter().collect();
+        candidates.push(repo.join(CONFIG_FILE_NAME));
+        let mut layers = vec![];
+        let mut sources = vec![];
+        for path in candidates {
+            if path.is_file() {
+                layers.push(FileConfig::load(&path)?);
+                sources.push(path);
+            }
+        }
+        if let Some(path) = &cli.config {
+            layers.push(FileConfig::load(path)?);
+            sources.push(path.clone());
+        }
+        layers.push(cli.overrides()?);
+        let mut config = Self::resolve(&layers)?;
+        config.sources = sources;
+        Ok(config)
+    }
+
+    /// Fold layers (lowest precedence first) into the effective config.
+    fn resolve(layers: &[FileConfig]) -> Result<Self> {
+        let mut merged = FileConfig::default();
+        for layer in layers {
+            merged.merge(layer);
+        }
+
+        // Within each layer a per-agent section beats the shared `[agents]` values,
+        // and any later layer beats both.
+        let agent = |role: AgentRole| {
+            let mut acc = AgentOverrides::default();
+            for layer in layers {
+                acc.merge(&layer.agents.defaults());
+                acc.merge(layer.agents.for_role(role));
+            }
+            acc.resolve()
+        };
+
+        let v = merged.verification;
+        Ok(Self {
+            llm: LlmConfig {
+                provider: merged.llm.provider.unwrap_or(LlmProvider::Openai),
+                api_base: merged.llm.api_base,
+                api_key_env: merged.llm.api_key_env,
+                structured_output: merged.llm.structured_output.unwrap_or(true),
+            },
+            agents: AgentProfiles {
+                developer: agent(AgentRole::Developer),
+                reviewer: agent(AgentRole::Reviewer),
+                repair: agent(AgentRole::Repair),
+                test_writer: agent(AgentRole::TestWriter),
+            },
+            rag: RagConfig {
+                dir: merged
+                    .rag
+                    .dir
+                    .unwrap_or_else(|| PathBuf::from("rag_examples")),
+                max_examples: merged.rag.max_examples.unwrap_or(3),
+            },
+            analysis: AnalysisConfig {
+                max_chars: merged.analysis.max_chars.unwrap_or(8000),
+            },
+            verification: VerificationConfig {
+                check: v.check.unwrap_or_else(|| "cargo check".into()),
+                test: v.test.unwrap_or_else(|| "cargo test --all --quiet".into()),
+                fmt: v.fmt.unwrap_or_else(|| "rustfmt".into()),
+                fmt_check: v.fmt_check.unwrap_or_else(|| "rustfmt --check".into()),
+                clippy_fix: v
+                    .clippy_fix
+                    .unwrap_or_else(|| "cargo clippy --fix --allow-dirty --allow-staged --allow-no-vcs".into()),
+                clippy_check: v
+                    .clippy_check
+                    .unwrap_or_else(|| "cargo clippy -q -- -D warnings".into()),
+                max_repairs: v.max_repairs.unwrap_or(10),
+                max_revisions: v.max_revisions.unwrap_or(3),
+                max_edit_retries: v.max_edit_retries.unwrap_or(2),
+                candidates: v.candidates.unwrap_or(1).max(1),
+                on_repair_failure: v.on_repair_failure.unwrap_or(OnRepairFailure::Ask),
+                characterize: v.characterize.unwrap_or(false),
+                differential: v.differential.unwrap_or(false),
+                allow_api_change: v.allow_api_change.unwrap_or(false),
+            },
+            paths: PathsConfig::new(
+                merged
+                    .paths
+                    .allowed
+                    .unwrap_or_else(|| vec!["**/*.rs".into()]),
+            )?,
+            prompts: merged.prompts,
+            sandbox: SandboxConfig {
+                mode: merged.sandbox.mode.unwrap_or(SandboxMode::Auto),
+            },
+            sources: vec![],
+        })
+    }
+
+    /// Pretty TOML for `mantra-rs config show`.
+    pub fn to_toml(&self) -> Result<String> {
+        let mut out = String::new();
+        if self.sources.is_empty() {
+            out.push_str("# sources: built-in defaults + CLI flags\n");
+        } else {
+            for src in &self.sources {
+                out.push_str(&format!("# source: {}\n", src.display()));
+            }
+        }
+        out.push_str(&toml::to_string_pretty(self)?);
+        Ok(out)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Inspect the layered configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Print the effective merged config for `--repo` (default: current directory)
+    Show,
+}
+
 #[derive(Parser, Debug)]
 #[command(
     name = "mantra-rs",
     version,
-    about = "MANTRA-style multi-agent refactoring for Rust"
+    about = "MANTRA-style multi-agent refactoring for Rust",
+    subcommand_negates_reqs = true
 )]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Path to the Rust repository root (must contain Cargo.toml)
-    #[arg(long)]
-    pub repo: PathBuf,
+    #[arg(long, required = true)]
+    pub repo: Option<PathBuf>,
 
     /// Path to the Rust file to refactor (relative or absolute)
-    #[arg(long)]
-    pub file: PathBuf,
+    #[arg(long, required = true)]
+    pub file: Option<PathBuf>,
 
     /// Free-form human instruction paired with the refactor type (the “what/why”)
+    #[arg(long, required = true)]
+    pub refactor_prompt: Option<String>,
+
+    /// Refactoring type (used by Reviewer for structural verification)
+    #[arg(long, value_enum, required = true)]
+    pub refactor_type: Option<RefactorKind>,
+
+    /// Model for all agents unless overridden per agent (default: gpt-4o-mini)
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Model for the Developer agent
+    #[arg(long)]
+    pub developer_model: Option<String>,
+
+    /// Model for the Reviewer agent
+    #[arg(long)]
+    pub reviewer_model: Option<String>,
+
+    /// Model for the Repair agent
+    #[arg(long)]
+    pub repair_model: Option<String>,
+
+    /// Model for the TestWriter agent
+    #[arg(long)]
+    pub test_writer_model: Option<String>,
+
+    /// Sampling temperature for all agents (default: 0.0)
+    #[arg(long)]
+    pub temperature: Option<f64>,
+
+    /// Nucleus sampling `top_p` for all agents
+    #[arg(long)]
+    pub top_p: Option<f64>,
+
+    /// Completion token limit for all agents
+    #[arg(long)]
+    pub max_tokens: Option<u32>,
+
+    /// Sampling seed for all agents (best-effort determinism)
+    #[arg(long)]
+    pub seed: Option<i64>,
+
+    /// Per-agent setting, e.g. `reviewer.temperature=0.2` or `developer.seed=7` (repeatable)
+    #[arg(long = "agent-set", value_name = "AGENT.KEY=VALUE")]
+    pub agent_set: Vec<String>,
+
+    /// Extra config file layered above `mantra.toml` (below CLI flags)
     #[arg(long)]
-    pub refactor_prompt: String,
+    pub config: Option<PathBuf>,
 
-    /// Refactoring type (used by Reviewer for verification heuristics)
+    /// LLM backend shared by all agents (default: openai)
     #[arg(long, value_enum)]
-    pub refactor_type: RefactorKind,
+    pub llm: Option<LlmProvider>,
 
-    /// Optional model (default: gpt-4o-mini)
-    #[arg(long, default_value = "gpt-4o-mini")]
-    pub model: String,
+    /// Base URL of an OpenAI-compatible API (overrides the provider default)
+    #[arg(long, value_name = "URL")]
+    pub api_base: Option<String>,
 
-    /// Optional path holding few-shot examples for RAG
-    #[arg(long, default_value = "rag_examples")]
-    pub rag_dir: PathBuf,
+    /// Name of the environment variable holding the API key (overrides the provider default)
+    #[arg(long, value_name = "VAR")]
+    pub api_key_env: Option<String>,
 
-    /// Max repair attempts
-    #[arg(long, default_value_t = 10)]
-    pub max_repairs: u32,
+    /// Directory with canned responses for `--llm mock` (`developer_1.md`, `reviewer.md`, …)
+    #[arg(long)]
+    pub mock_dir: Option<PathBuf>,
+
+    /// Save every agent prompt/response pair as cassettes in this directory
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Serve agent responses from cassettes recorded with `--record` (no network)
+    #[arg(long, value_name = "DIR")]
+    pub replay: Option<PathBuf>,
+
+    /// Optional path holding few-shot examples for RAG (default: rag_examples)
+    #[arg(long)]
+    pub rag_dir: Option<PathBuf>,
+
+    /// Max repair attempts (default: 10)
+    #[arg(long)]
+    pub max_repairs: Option<u32>,
+
+    /// Developer candidates to sample in parallel and rank before review (default: 1)
+    #[arg(long, value_name = "N")]
+    pub candidates: Option<u32>,
+
+    /// What to keep when the repairs fail: the original files or the best attempt (default: ask)
+    #[arg(long, value_enum)]
+    pub on_repair_failure: Option<OnRepairFailure>,
+
+    /// Generate characterization tests for the target before refactoring and keep them passing
+    #[arg(long, default_value_t = false)]
+    pub characterize: bool,
+
+    /// Check that functions changed by an extract/inline refactor return what the originals did on generated inputs
+    #[arg(long, default_value_t = false)]
+    pub differential: bool,
+
+    /// Accept refactors that add, remove or change public items of a library crate
+    #[arg(long, default_value_t = false)]
+    pub allow_api_change: bool,
+
+    /// Write a JSON report of the run (settings, reverted tool edits, …) to this path
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<PathBuf>,
+
+    /// Run in a scratch copy or git worktree of `--repo` (default: auto)
+    #[arg(long, value_enum)]
+    pub sandbox: Option<SandboxMode>,
+
+    /// Run the whole pipeline in a sandbox and print a unified diff instead of touching `--file`
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Like `--dry-run`, but write the `git apply`-able patch to this path
+    #[arg(long, value_name = "PATH")]
+    pub output_patch: Option<PathBuf>,
 
     /// Non-interactive (auto-apply without VS Code diff prompt)
     #[arg(long, default_value_t = false)]
     pub yes: bool,
+
+    /// On failure or interrupt, leave modified files (or the sandbox) in place instead of cleaning up
+    #[arg(long, default_value_t = false)]
+    pub keep_failed: bool,
 }
 
 impl Cli {
-    pub fn validate(&self) -> Result<()> {
+    /// `--dry-run`, or implied by `--output-patch`.
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run || self.output_patch.is_some()
+    }
+
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        let (Some(repo), Some(file)) = (&self.repo, &self.file) else {
+            bail!("--repo and --file are required");
+        };
         ensure!(
-            self.repo.join("Cargo.toml").exists(),
+            repo.join("Cargo.toml").exists(),
             "No Cargo.toml found in --repo"
         );
-        ensure!(self.file.exists(), "--file does not exist");
+        ensure!(file.exists(), "--file does not exist");
+        let provider = config.llm.provider;
+        ensure!(
+            provider != LlmProvider::Mock || self.mock_dir.is_some() || self.replay.is_some(),
+            "--llm mock requires --mock-dir"
+        );
+        ensure!(
+            provider != LlmProvider::Compatible || config.llm.api_base.is_some(),
+            "--llm compatible requires --api-base"
+        );
         Ok(())
     }
+
+    /// The CLI flags as the topmost config layer.
+    fn overrides(&self) -> Result<FileConfig> {
+        let mut agents = AgentsConfig {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+            ..Default::default()
+        };
+        agents.developer.model.clone_from(&self.developer_model);
+        agents.reviewer.model.clone_from(&self.reviewer_model);
+        agents.repair.model.clone_from(&self.repair_model);
+        agents.test_writer.model.clone_from(&self.test_writer_model);
+        for assignment in &self.agent_set {
+            let (agent, key, value) = assignment
+                .split_once('=')
+                .and_then(|(lhs, value)| {
+                    lhs.split_once('.').map(|(agent, key)| (agent, key, value))
+                })
+                .with_context(|| format!("--agent-set `{assignment}`: expected AGENT.KEY=VALUE"))?;
+            let role = match agent {
+                "developer" => AgentRole::Developer,
+                "reviewer" => AgentRole::Reviewer,
+                "repair" => AgentRole::Repair,
+                "test_writer" => AgentRole::TestWriter,
+                other => bail!("--agent-set: unknown agent `{other}`"),
+            };
+            agents
+                .for_role_mut(role)
+                .set(key, value)
+                .with_context(|| format!("--agent-set `{assignment}`"))?;
+        }
+
+        Ok(FileConfig {
+            llm: LlmSection {
+                provider: self.llm,
+                api_base: self.api_base.clone(),
+                api_key_env: self.api_key_env.clone(),
+                structured_output: None,
+            },
+            agents,
+            rag: RagSection {
+                dir: self.rag_dir.clone(),
+                max_examples: None,
+            },
+            verification: VerificationSection {
+                max_repairs: self.max_repairs,
+                candidates: self.candidates,
+                on_repair_failure: self.on_repair_failure,
+                characterize: self.characterize.then_some(true),
+                differential: self.differential.then_some(true),
+                allow_api_change: self.allow_api_change.then_some(true),
+                ..Default::default()
+            },
+            sandbox: SandboxSection { mode: self.sandbox },
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn agents_table_reads_shared_and_per_agent_values() {
+        let cfg: FileConfig = toml::from_str(
+            "[agents]\nmodel = \"m\"\ntemperature = 0.2\n\n[agents.reviewer]\nmodel = \"r\"\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.agents.defaults().model.as_deref(), Some("m"));
+        assert_eq!(cfg.agents.temperature, Some(0.2));
+        assert_eq!(cfg.agents.reviewer.model.as_deref(), Some("r"));
+    }
+
+    #[test]
+    fn agents_table_rejects_unknown_keys() {
+        for text in [
+            "[agents]\ntemprature = 0.2\n",
+            "[agents]\ndevloper = { model = \"m\" }\n",
+            "[agents.developer]\nmodle = \"m\"\n",
+        ] {
+            let err = toml::from_str::<FileConfig>(text).unwrap_err();
+            assert!(err.to_string().contains("unknown field"), "{text}: {err}");
+        }
+    }
 }
@@ -0,0 +1,702 @@
+use anyhow::Result;
+use quote::ToTokens;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use syn::{
+    visit::{self, Visit},
+    FnArg, GenericArgument, GenericParam, ImplItem, Item, PathArguments, ReturnType, Signature,
+    Type,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::{RefactorKind, VerificationConfig},
+    io_utils::read_to_string,
+    sandbox::Sandbox,
+    txn::Transaction,
+    verification::filtered_tests,
+};
+
+/// Name of the module holding the harness, as it shows in test names.
+pub const MODULE: &str = "mantra_differential";
+
+/// Generated inputs per function.
+const CASES: usize = 256;
+
+/// Divergent inputs reported per function.
+const MAX_REPORTED: usize = 3;
+
+/// Time after which the harness stops generating inputs for a function.
+const FUNCTION_BUDGET_SECS: u64 = 10;
+
+/// Limit on the whole harness run, for a function that never returns on some input.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Prefix of the harness's panic message, so divergences can be told apart from
+/// other failures in the test output.
+const MARKER: &str = "mantra-differential: ";
+
+/// Compares refactored free functions of the target file with their original
+/// versions on generated inputs. The originals are compiled next to the candidate
+/// in a shadow module that exists only while the check runs.
+pub struct DifferentialCheck {
+    /// Repo-relative target file.
+    file: PathBuf,
+    original: syn::File,
+}
+
+impl DifferentialCheck {
+    /// `None` unless `kind` must leave every function's behavior unchanged (extract
+    /// or inline method) and the original parses.
+    pub fn new(kind: &RefactorKind, file: &Path, original: &str) -> Option<Self> {
+        if !matches!(
+            kind,
+            RefactorKind::ExtractMethod | RefactorKind::InlineMethod
+        ) {
+            return None;
+        }
+        let original = syn::parse_file(original).ok()?;
+        Some(Self {
+            file: file.to_path_buf(),
+            original,
+        })
+    }
+
+    /// Run the refactored functions of the candidate under `repo` and their
+    /// originals on the same inputs. Functions that could not be compared are
+    /// listed as such, never counted as agreeing.
+    pub async fn run(
+        &self,
+        repo: &Path,
+        cfg: &VerificationConfig,
+        sandbox: &Sandbox,
+        txn: &Transaction,
+    ) -> Result<Comparison> {
+        let path = repo.join(&self.file);
+        let candidate = match path.is_file().then(|| read_to_string(&path)) {
+            Some(content) => syn::parse_file(&content?).ok(),
+            None => None,
+        };
+        let Some(candidate) = candidate else {
+            warn!(
+                "Differential check skipped: {} is gone or does not parse",
+                self.file.display()
+            );
+            return Ok(Comparison {
+                not_compared: vec![format!("{} (does not parse)", self.file.display())],
+                ..Default::default()
+            });
+        };
+        let (targets, mut not_compared) = refactored(&self.original, &candidate);
+        for f in &not_compared {
+            warn!("Differential check: not comparing {f}");
+        }
+        if targets.is_empty() {
+            info!("Differential check: no refactored function the harness can call");
+            return Ok(Comparison {
+                not_compared,
+                ..Default::default()
+            });
+        }
+        let names: Vec<String> = targets.iter().map(Target::path).collect();
+        info!(
+            "Differential check: {} against the original on {CASES} inputs each",
+            names.join(", ")
+        );
+        // Everything the harness was meant to compare, when it gives no verdict.
+        let mut skipped = |why: &str| {
+            warn!("Differential check skipped: {why}");
+            not_compared.extend(names.iter().map(|n| format!("{n} ({why})")));
+            Ok(Comparison {
+                divergences: vec![],
+                not_compared: std::mem::take(&mut not_compared),
+            })
+        };
+
+        let harness = harness(&self.original, &targets);
+        let run = {
+            let _appended = sandbox.append_temporarily(&path, &harness, txn)?;
+            tokio::time::timeout(TIMEOUT, filtered_tests(repo, cfg, MODULE)).await
+        };
+        let Ok(run) = run else {
+            return skipped(&format!("no result after {}s", TIMEOUT.as_secs()));
+        };
+        let (diagnostics, outcome) = run?;
+        if let Some(error) = diagnostics.iter().find(|d| d.level == "error") {
+            return skipped(&format!("the harness does not compile: {}", error.headline()));
+        }
+        if outcome.passed {
+            info!(
+                "Differential check: no divergence in {} function(s)",
+                targets.len()
+            );
+            return Ok(Comparison {
+                divergences: vec![],
+                not_compared,
+            });
+        }
+        let divergences: Vec<String> = outcome
+            .failures
+            .iter()
+            .filter_map(|f| f.split_once(MARKER).map(|(_, d)| d.trim().to_string()))
+            .flat_map(|d| d.split(" || ").map(str::to_string).collect::<Vec<_>>())
+            .collect();
+        if divergences.is_empty() {
+            return skipped(&format!(
+                "the harness failed without a divergence: {}",
+                outcome.failures.join("; ")
+            ));
+        }
+        for d in &divergences {
+            warn!("Divergence: {d}");
+        }
+        Ok(Comparison {
+            divergences,
+            not_compared,
+        })
+    }
+}
+
+/// What the differential check found.
+#[derive(Debug, Clone, Default)]
+pub struct Comparison {
+    /// One line per function whose results differ from the original's.
+    pub divergences: Vec<String>,
+    /// Refactored functions that were not compared, with the reason.
+    pub not_compared: Vec<String>,
+}
+
+/// A value the harness knows how to generate, compare and print.
+#[derive(Debug, Clone)]
+enum Simple {
+    /// Integer type name and whether it is signed.
+    Int(String, bool),
+    Bool,
+    Char,
+    Str,
+    List(Box<Simple>),
+    Option(Box<Simple>),
+    Result(Box<Simple>, Box<Simple>),
+    Tuple(Vec<Simple>),
+}
+
+impl Simple {
+    /// The owned type holding a generated value.
+    fn owned(&self) -> String {
+        match self {
+            Simple::Int(name, _) => name.clone(),
+            Simple::Bool => "bool".into(),
+            Simple::Char => "char".into(),
+            Simple::Str => "String".into(),
+            Simple::List(t) => format!("Vec<{}>", t.owned()),
+            Simple::Option(t) => format!("Option<{}>", t.owned()),
+            Simple::Result(t, e) => format!("Result<{}, {}>", t.owned(), e.owned()),
+            Simple::Tuple(ts) => {
+                let ts: Vec<String> = ts.iter().map(Simple::owned).collect();
+                format!("({},)", ts.join(", "))
+            }
+        }
+    }
+
+    /// Expression drawing a value from `rng`.
+    fn generate(&self) -> String {
+        match self {
+            Simple::Int(name, signed) => format!(
+                "match rng.below(8) {{ 0 => {name}::MIN, 1 => {name}::MAX, 2..=5 => rng.small({signed}) as {name}, _ => rng.next() as {name} }}"
+            ),
+            Simple::Bool => "rng.below(2) == 0".into(),
+            Simple::Char => "rng.char()".into(),
+            Simple::Str => "rng.string()".into(),
+            Simple::List(t) => format!(
+                "(0..rng.below(8)).map(|_| {}).collect::<Vec<_>>()",
+                t.generate()
+            ),
+            Simple::Option(t) => {
+                format!("if rng.below(4) == 0 {{ None }} else {{ Some({}) }}", t.generate())
+            }
+            Simple::Result(t, e) => format!(
+                "if rng.below(2) == 0 {{ Ok({}) }} else {{ Err({}) }}",
+                t.generate(),
+                e.generate()
+            ),
+            Simple::Tuple(ts) => {
+                let ts: Vec<String> = ts.iter().map(Simple::generate).collect();
+                format!("({},)", ts.join(", "))
+            }
+        }
+    }
+}
+
+/// A function of the target file: free, or associated with the type of an
+/// inherent impl.
+struct Func<'a> {
+    /// Self type of the impl, for associated functions.
+    owner: Option<String>,
+    /// Why the harness can't name the function, e.g. it is in a generic impl.
+    unsupported: Option<&'static str>,
+    attrs: &'a [syn::Attribute],
+    sig: &'a Signature,
+    block: &'a syn::Block,
+}
+
+impl Func<'_> {
+    fn path(&self) -> String {
+        match &self.owner {
+            Some(owner) => format!("{owner}::{}", self.sig.ident),
+            None => self.sig.ident.to_string(),
+        }
+    }
+
+    fn is(&self, owner: Option<&str>, name: &str) -> bool {
+        self.owner.as_deref() == owner && self.sig.ident == name
+    }
+
+    /// The function as an item of the shadow module.
+    fn shadow(&self) -> String {
+        // Keep what decides whether it compiles; drop `#[no_mangle]` and the like.
+        let attrs: String = self
+            .attrs
+            .iter()
+            .filter(|a| is_attr(a, "cfg") || is_attr(a, "allow"))
+            .map(|a| format!("{} ", a.to_token_stream()))
+            .collect();
+        format!(
+            "{attrs}pub(super) {} {}",
+            self.sig.to_token_stream(),
+            self.block.to_token_stream()
+        )
+    }
+}
+
+/// Free functions and functions of impl blocks, tests excepted.
+fn functions(file: &syn::File) -> Vec<Func<'_>> {
+    let mut out = vec![];
+    for item in &file.items {
+        match item {
+            Item::Fn(f) if !f.attrs.iter().any(|a| is_attr(a, "test")) => out.push(Func {
+                owner: None,
+                unsupported: None,
+                attrs: &f.attrs,
+                sig: &f.sig,
+                block: &f.block,
+            }),
+            Item::Impl(imp) => {
+                let Type::Path(ty) = &*imp.self_ty else {
+                    continue;
+                };
+                let Some(last) = ty.path.segments.last() else {
+                    continue;
+                };
+                let unsupported = if imp.trait_.is_some() {
+                    Some("in a trait impl")
+                } else if !imp.generics.params.is_empty() || !last.arguments.is_empty() {
+                    Some("in a generic impl")
+                } else {
+                    None
+                };
+                for it in &imp.items {
+                    if let ImplItem::Fn(m) = it {
+                        out.push(Func {
+                            owner: Some(last.ident.to_string()),
+                            unsupported,
+                            attrs: &m.attrs,
+                            sig: &m.sig,
+                            block: &m.block,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+/// A refactored function the harness can call.
+struct Target {
+    /// Self type, for an associated function.
+    owner: Option<String>,
+    name: String,
+    /// Per argument: its value type and whether it is passed by reference.
+    args: Vec<(Simple, bool)>,
+}
+
+impl Target {
+    fn path(&self) -> String {
+        match &self.owner {
+            Some(owner) => format!("{owner}::{}", self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Functions of `candidate` that kept the name and signature of the original but
+/// not its body, and whose arguments and result are [`Simple`]. Changed functions
+/// the harness can't call come second, with the reason.
+fn refactored(original: &syn::File, candidate: &syn::File) -> (Vec<Target>, Vec<String>) {
+    let before = functions(original);
+    let mut targets = vec![];
+    let mut not_compared = vec![];
+    for f in functions(candidate) {
+        if f.owner.is_none() && f.sig.ident == "main" {
+            continue;
+        }
+        let name = f.sig.ident.to_string();
+        let Some(old) = before.iter().find(|o| o.is(f.owner.as_deref(), &name)) else {
+            continue;
+        };
+        let same_sig =
+            old.sig.to_token_stream().to_string() == f.sig.to_token_stream().to_string();
+        let same_body =
+            old.block.to_token_stream().to_string() == f.block.to_token_stream().to_string();
+        if same_sig && same_body {
+            continue;
+        }
+        let why = if !same_sig {
+            Err("its signature changed")
+        } else if let Some(why) = f.unsupported {
+            Err(why)
+        } else {
+            comparable(&f)
+        };
+        match why {
+            Ok(target) => targets.push(target),
+            Err(why) => not_compared.push(format!("{} ({why})", f.path())),
+        }
+    }
+    (targets, not_compared)
+}
+
+fn is_attr(attr: &syn::Attribute, name: &str) -> bool {
+    attr.path().segments.last().is_some_and(|s| s.ident == name)
+}
+
+/// `f` as a [`Target`], if the harness can call it: no receiver, not async, unsafe
+/// or generic over types, and with simple arguments and result.
+fn comparable(f: &Func) -> Result<Target, &'static str> {
+    let sig = f.sig;
+    if sig.receiver().is_some() {
+        return Err("a method with a receiver");
+    }
+    let lifetimes_only = sig
+        .generics
+        .params
+        .iter()
+        .all(|p| matches!(p, GenericParam::Lifetime(_)));
+    if sig.asyncness.is_some()
+        || sig.unsafety.is_some()
+        || sig.variadic.is_some()
+        || !lifetimes_only
+        || sig.generics.where_clause.is_some()
+    {
+        return Err("async, unsafe or generic");
+    }
+    let unsupported = "an argument or result type the harness can't generate or compare";
+    if let ReturnType::Type(_, ty) = &sig.output {
+        if !matches!(&**ty, Type::Tuple(t) if t.elems.is_empty()) {
+            argument(ty).ok_or(unsupported)?;
+        }
+    }
+    let args = sig
+        .inputs
+        .iter()
+        .map(|arg| match arg {
+            FnArg::Typed(arg) => argument(&arg.ty),
+            FnArg::Receiver(_) => None,
+        })
+        .collect::<Option<_>>()
+        .ok_or(unsupported)?;
+    Ok(Target {
+        owner: f.owner.clone(),
+        name: sig.ident.to_string(),
+        args,
+    })
+}
+
+/// A by-value or shared-reference argument of a simple type.
+fn argument(ty: &Type) -> Option<(Simple, bool)> {
+    match ty {
+        Type::Reference(r) if r.mutability.is_none() => match &*r.elem {
+            Type::Path(p) if p.path.is_ident("str") => Some((Simple::Str, true)),
+            Type::Slice(s) => Some((Simple::List(Box::new(simple(&s.elem)?)), true)),
+            elem => Some((simple(elem)?, true)),
+        },
+        ty => Some((simple(ty)?, false)),
+    }
+}
+
+fn simple(ty: &Type) -> Option<Simple> {
+    match ty {
+        Type::Paren(p) => simple(&p.elem),
+        Type::Tuple(t) if !t.elems.is_empty() => Some(Simple::Tuple(
+            t.elems.iter().map(simple).collect::<Option<_>>()?,
+        )),
+        Type::Path(p) if p.qself.is_none() => {
+            let last = p.path.segments.last()?;
+            let params: Vec<&Type> = match &last.arguments {
+                PathArguments::None => vec![],
+                PathArguments::AngleBracketed(a) => a
+                    .args
+                    .iter()
+                    .map(|a| match a {
+                        GenericArgument::Type(t) => Some(t),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+                PathArguments::Parenthesized(_) => return None,
+            };
+            let name = last.ident.to_string();
+            match (name.as_str(), params.as_slice()) {
+                ("i8" | "i16" | "i32" | "i64" | "i128" | "isize", []) => {
+                    Some(Simple::Int(name, true))
+                }
+                ("u8" | "u16" | "u32" | "u64" | "u128" | "usize", []) => {
+                    Some(Simple::Int(name, false))
+                }
+                ("bool", []) => Some(Simple::Bool),
+                ("char", []) => Some(Simple::Char),
+                ("String", []) => Some(Simple::Str),
+                ("Vec", [t]) => Some(Simple::List(Box::new(simple(t)?))),
+                ("Option", [t]) => Some(Simple::Option(Box::new(simple(t)?))),
+                ("Result", [t, e]) => {
+                    Some(Simple::Result(Box::new(simple(t)?), Box::new(simple(e)?)))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The original functions the targets need: the targets themselves and every
+/// free function they call, directly or not, plus the associated functions of a
+/// target's type they call as `Self::f` or `Type::f`. Other items come from the
+/// candidate, which may have dropped what the rest of the original used.
+fn shadowed<'f, 'a>(functions: &'f [Func<'a>], targets: &[Target]) -> Vec<&'f Func<'a>> {
+    /// Called paths: `f` as `(None, f)`, `Self::f` and `Type::f` as `(Some(Type), f)`.
+    struct Names<'o> {
+        owner: Option<&'o str>,
+        found: BTreeSet<(Option<String>, String)>,
+    }
+    impl<'ast> Visit<'ast> for Names<'_> {
+        fn visit_path(&mut self, path: &'ast syn::Path) {
+            let segments: Vec<String> = path.segments.iter().map(|s| s.ident.to_string()).collect();
+            match segments.as_slice() {
+                [name] => {
+                    self.found.insert((None, name.clone()));
+                }
+                [ty, name] if ty == "Self" => {
+                    let owner = self.owner.map(str::to_string);
+                    self.found.insert((owner, name.clone()));
+                }
+                [ty, name] => {
+                    self.found.insert((Some(ty.clone()), name.clone()));
+                }
+                _ => {}
+            }
+            visit::visit_path(self, path);
+        }
+    }
+    let owners: BTreeSet<Option<String>> = targets.iter().map(|t| t.owner.clone()).collect();
+    let mut wanted: Vec<(Option<String>, String)> =
+        targets.iter().map(|t| (t.owner.clone(), t.name.clone())).collect();
+    let mut kept: Vec<&Func> = vec![];
+    while let Some((owner, name)) = wanted.pop() {
+        // Only types with a target get a shadow; other types are the candidate's.
+        if owner.is_some() && !owners.contains(&owner) {
+            continue;
+        }
+        let Some(f) = functions
+            .iter()
+            .find(|f| f.unsupported.is_none() && f.is(owner.as_deref(), &name))
+        else {
+            continue;
+        };
+        if kept.iter().any(|k| k.is(owner.as_deref(), &name)) {
+            continue;
+        }
+        kept.push(f);
+        let mut names = Names {
+            owner: f.owner.as_deref(),
+            found: BTreeSet::new(),
+        };
+        names.visit_block(f.block);
+        wanted.extend(names.found);
+    }
+    kept
+}
+
+/// Test module with the original versions of the targets in a shadow module and
+/// one test that calls both versions of every target on the same generated inputs.
+fn harness(original: &syn::File, targets: &[Target]) -> String {
+    let functions = functions(original);
+    let kept = shadowed(&functions, targets);
+    // Free functions as they are; associated ones in an impl of a stand-in type of
+    // the same name, so `Self::f` and `Type::f` reach the originals.
+    let mut shadow = String::new();
+    let owners: BTreeSet<Option<&str>> = kept.iter().map(|f| f.owner.as_deref()).collect();
+    for owner in owners {
+        let items: String = kept
+            .iter()
+            .filter(|f| f.owner.as_deref() == owner)
+            .map(|f| format!("        {}\n", f.shadow()))
+            .collect();
+        match owner {
+            None => shadow.push_str(&items),
+            Some(owner) => shadow.push_str(&format!(
+                "        pub(super) struct {owner};\n        impl {owner} {{\n{items}        }}\n"
+            )),
+        }
+    }
+    let checks: String = targets.iter().map(check).collect();
+    format!(
+        r#"
+#[cfg(test)]
+#[allow(warnings, clippy::all)]
+mod {MODULE} {{
+    use super::*;
+
+    /// The functions as they were before the refactor.
+    mod original {{
+        use super::super::*;
+{shadow}    }}
+
+    struct Rng(u64);
+
+    impl Rng {{
+        fn next(&mut self) -> u64 {{
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }}
+        fn below(&mut self, n: u64) -> u64 {{
+            self.next() % n
+        }}
+        fn small(&mut self, signed: bool) -> i64 {{
+            if signed {{ self.below(33) as i64 - 16 }} else {{ self.below(17) as i64 }}
+        }}
+        fn char(&mut self) -> char {{
+            const ODD: [char; 6] = [' ', '\n', '0', 'Z', 'é', '😀'];
+            match self.below(4) {{
+                0 => ODD[self.below(6) as usize],
+                _ => (b'a' + self.below(26) as u8) as char,
+            }}
+        }}
+        fn string(&mut self) -> String {{
+            (0..self.below(8)).map(|_| self.char()).collect()
+        }}
+    }}
+
+    fn show<T: std::fmt::Debug>(r: &std::thread::Result<T>) -> String {{
+        match r {{
+            Ok(v) => format!("{{v:?}}"),
+            Err(_) => "a panic".to_string(),
+        }}
+    }}
+
+    #[test]
+    fn equivalence() {{
+        // Panics are compared, not printed.
+        std::panic::set_hook(Box::new(|_| {{}}));
+        let mut divergences: Vec<String> = Vec::new();
+{checks}        let _ = std::panic::take_hook();
+        if !divergences.is_empty() {{
+            panic!("{MARKER}{{}}", divergences.join(" || "));
+        }}
+    }}
+}}
+"#
+    )
+}
+
+/// The part of the harness test that compares one target.
+fn check(target: &Target) -> String {
+    let name = target.path();
+    let mut draw = String::new();
+    let mut call = vec![];
+    let mut shown = vec![];
+    for (i, (ty, by_ref)) in target.args.iter().enumerate() {
+        draw.push_str(&format!(
+            "            let a{i}: {} = {};\n",
+            ty.owned(),
+            ty.generate()
+        ));
+        call.push(if *by_ref {
+            format!("&a{i}")
+        } else {
+            format!("a{i}.clone()")
+        });
+        shown.push(format!("format!(\"{{:?}}\", a{i})"));
+    }
+    let call = call.join(", ");
+    let shown = shown.join(", ");
+    format!(
+        r#"        {{
+            let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+            let start = std::time::Instant::now();
+            let mut found: Vec<String> = Vec::new();
+            for _ in 0..{CASES} {{
+                if start.elapsed().as_secs() >= {FUNCTION_BUDGET_SECS} || found.len() >= {MAX_REPORTED} {{
+                    break;
+                }}
+{draw}                let before = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| original::{name}({call})));
+                let after = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| super::{name}({call})));
+                let same = match (&before, &after) {{
+                    (Ok(b), Ok(a)) => b == a,
+                    (Err(_), Err(_)) => true,
+                    _ => false,
+                }};
+                if !same {{
+                    let args: Vec<String> = vec![{shown}];
+                    found.push(format!("{name}({{}}) was {{}}, is now {{}}", args.join(", "), show(&before), show(&after)));
+                }}
+            }}
+            if !found.is_empty() {{
+                divergences.push(found.join("; "));
+            }}
+        }}
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn compares_associated_functions_and_lists_methods() {
+        let original = syn::parse_quote! {
+            struct Calc { base: u32 }
+            impl Calc {
+                fn double(x: u32) -> u32 { x * 2 }
+                fn add(&self, x: u32) -> u32 { self.base + x }
+            }
+            fn free(x: &[u32]) -> usize { x.len() }
+            fn main() {}
+        };
+        let candidate = syn::parse_quote! {
+            struct Calc { base: u32 }
+            impl Calc {
+                fn double(x: u32) -> u32 { Self::times(x, 2) }
+                fn times(x: u32, n: u32) -> u32 { x * n }
+                fn add(&self, x: u32) -> u32 { x + self.base }
+            }
+            fn free(x: &[u32]) -> usize { x.iter().count() }
+            fn main() { let _ = 1; }
+        };
+        let (targets, not_compared) = refactored(&original, &candidate);
+        let paths: Vec<String> = targets.iter().map(Target::path).collect();
+        assert_eq!(paths, vec!["Calc::double", "free"]);
+        assert_eq!(not_compared, vec!["Calc::add (a method with a receiver)"]);
+
+        let harness = harness(&original, &targets);
+        assert!(harness.contains("pub(super) struct Calc;"), "{harness}");
+        assert!(harness.contains("original::Calc::double("), "{harness}");
+        assert!(harness.contains("super::free("), "{harness}");
+    }
+}
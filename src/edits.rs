@@ -0,0 +1,539 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    config::PathsConfig,
+    fence::{self, ExtractError},
+    hunks::{self, FailedHunk, Hunk, HunkFailures},
+    patch::unified_diff,
+    sandbox::Sandbox,
+    txn::Transaction,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditAction {
+    Create,
+    Modify,
+    Delete,
+    /// Search/replace or diff hunks against the existing file; see [`Workspace::resolve`].
+    Patch,
+}
+
+/// One file in an agent's answer. `content` is the full new file (empty for
+/// deletes and unresolved patches).
+#[derive(Debug, Clone)]
+pub struct FileEdit {
+    /// Relative to the repo root.
+    pub path: PathBuf,
+    pub action: EditAction,
+    pub content: String,
+    pub hunks: Vec<Hunk>,
+}
+
+/// The files an agent wants to create, rewrite or delete, in the order given.
+///
+/// Agents answer with one fenced block per file; the info string names the file
+/// and, optionally, the action:
+///
+/// ````text
+/// ```rust file=src/lib.rs
+/// <entire new file>
+/// ```
+/// ```rust file=src/util.rs action=create
+/// ...
+/// ```
+/// ```delete file=src/old.rs
+/// ```
+/// ```rust file=src/big.rs
+/// <<<<<<< SEARCH
+/// old lines
+/// =======
+/// new lines
+/// >>>>>>> REPLACE
+/// ```
+/// ````
+///
+/// Blocks with search/replace markers, `diff` blocks of unified-diff hunks and
+/// `action=patch` are patches of the existing file. A single `rust` block without
+/// a path stands for the file under refactor. See [`fence::parse_blocks`] for the
+/// fences and path headers that are understood.
+#[derive(Debug, Clone, Default)]
+pub struct EditSet {
+    pub edits: Vec<FileEdit>,
+}
+
+impl EditSet {
+    pub fn parse(response: &str, default_path: &Path) -> Result<Self, ExtractError> {
+        let blocks = fence::parse_blocks(response)?;
+        if blocks.is_empty() {
+            return Err(ExtractError::NoBlocks);
+        }
+        let unlabelled_rust = blocks
+            .iter()
+            .filter(|b| b.path.is_none() && b.lang == "rust")
+            .count();
+        let labels_default = blocks
+            .iter()
+            .any(|b| b.path.as_deref() == Some(default_path));
+
+        let mut edits: Vec<FileEdit> = vec![];
+        for block in &blocks {
+            let invalid = |reason: String| ExtractError::Invalid {
+                line: block.line,
+                reason,
+            };
+            let raw = format!("{}\n", block.body);
+            let action = match (block.lang.as_str(), block.attr("action")) {
+                ("delete", _) | (_, Some("delete")) => EditAction::Delete,
+                ("diff", _) | (_, Some("patch")) => EditAction::Patch,
+                (_, Some("modify") | None) if hunks::is_search_replace(&raw) => EditAction::Patch,
+                (_, Some("create")) => EditAction::Create,
+                (_, Some("modify") | None) => EditAction::Modify,
+                (_, Some(other)) => return Err(invalid(format!("unknown edit action `{other}`"))),
+            };
+            let is_patch = action == EditAction::Patch;
+            // A unified diff may patch several files; anything else is one edit.
+            let groups: Vec<(Option<PathBuf>, Vec<Hunk>)> = match action {
+                EditAction::Patch if hunks::is_search_replace(&raw) => vec![(
+                    None,
+                    hunks::parse_search_replace(&raw).map_err(|e| invalid(e.to_string()))?,
+                )],
+                EditAction::Patch => hunks::parse_unified(&raw)
+                    .map_err(|e| invalid(e.to_string()))?
+                    .into_iter()
+                    .map(|f| (f.path, f.hunks))
+                    .collect(),
+                _ => vec![(None, vec![])],
+            };
+            for (header_path, hunks) in groups {
+                // A diff's own headers name its files; the block's label is the fallback.
+                let path = match header_path.or_else(|| block.path.clone()) {
+                    Some(p) => p,
+                    // Unlabelled non-Rust blocks are commentary.
+                    None if !is_patch && block.lang != "rust" => continue,
+                    // Snippets next to a block labelled with the target file are commentary too.
+                    None if !is_patch && labels_default => continue,
+                    None if !is_patch && unlabelled_rust > 1 => {
+                        return Err(ExtractError::Ambiguous {
+                            count: unlabelled_rust,
+                        })
+                    }
+                    None => default_path.to_path_buf(),
+                };
+                // Several patch blocks for one file are applied in order.
+                if let Some(prev) = edits
+                    .iter_mut()
+                    .find(|e| is_patch && e.action == EditAction::Patch && e.path == path)
+                {
+                    prev.hunks.extend(hunks);
+                    continue;
+                }
+                let content = match action {
+                    EditAction::Delete | EditAction::Patch => String::new(),
+                    _ => format!("{}\n", block.body.trim_matches('\n')),
+                };
+                edits.push(FileEdit {
+                    path,
+                    action,
+                    content,
+                    hunks,
+                });
+            }
+        }
+        if edits.is_empty() {
+            let mut langs: Vec<String> = blocks
+                .iter()
+                .map(|b| match b.lang.as_str() {
+                    "" => "untagged".to_string(),
+                    l => l.to_string(),
+                })
+                .collect();
+            langs.sort();
+            langs.dedup();
+            return Err(ExtractError::NoEdits { langs });
+        }
+        Ok(Self { edits })
+    }
+
+    /// New content for `path`, if this set rewrites or creates it.
+    pub fn content_of(&self, path: &Path) -> Option<&str> {
+        self.edits
+            .iter()
+            .find(|e| e.path == path && matches!(e.action, EditAction::Create | EditAction::Modify))
+            .map(|e| e.content.as_str())
+    }
+
+    /// Reject paths outside the repo or the allowed globs, changes to existing files
+    /// outside `editable`, and actions that don't match which files `exists`.
+    pub fn validate(
+        &self,
+        exists: impl Fn(&Path) -> bool,
+        paths: &PathsConfig,
+        editable: &BTreeSet<&PathBuf>,
+    ) -> Result<(), InvalidEdits> {
+        let mut seen = BTreeSet::new();
+        let mut problems = vec![];
+        for e in &self.edits {
+            let p = e.path.display();
+            let escapes = e
+                .path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+            let exists = exists(&e.path);
+            if escapes {
+                problems.push(format!("{p}: must be relative to the repo root"));
+            } else if !paths.is_allowed(&e.path) {
+                problems.push(format!("{p}: outside the allowed paths {:?}", paths.allowed));
+            } else if !seen.insert(&e.path) {
+                problems.push(format!("{p}: edited more than once"));
+            } else if e.action == EditAction::Create && exists {
+                problems.push(format!("{p}: create, but the file already exists"));
+            } else if e.action != EditAction::Create && !exists {
+                problems.push(format!("{p}: {:?}, but the file does not exist", e.action));
+            } else if e.action != EditAction::Create && !editable.contains(&e.path) {
+                let shown: Vec<String> = editable.iter().map(|p| p.display().to_string()).collect();
+                problems.push(format!(
+                    "{p}: {:?}, but you were not shown this file; only {} can be changed",
+                    e.action,
+                    shown.join(", ")
+                ));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidEdits(problems))
+        }
+    }
+}
+
+/// Why an edit set can't be applied, one line per offending edit, rendered so it
+/// can be sent back to the model.
+#[derive(Debug, Clone)]
+pub struct InvalidEdits(pub Vec<String>);
+
+impl fmt::Display for InvalidEdits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid edits:\n  {}", self.0.join("\n  "))
+    }
+}
+
+impl std::error::Error for InvalidEdits {}
+
+/// What patch hunks are applied to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Base {
+    /// The files as they were before the run (Developer candidates).
+    Original,
+    /// The files as they are now (Repair edits on top of a candidate).
+    Current,
+}
+
+/// Contents of the touched files before the run and now; absent files are left out.
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    pub before: BTreeMap<PathBuf, String>,
+    pub after: BTreeMap<PathBuf, String>,
+}
+
+impl Changes {
+    /// One `git apply`-able patch covering every touched file.
+    pub fn diff(&self) -> String {
+        self.paths()
+            .map(|p| {
+                let before = self.before.get(p).map(String::as_str);
+                let after = self.after.get(p).map(String::as_str);
+                unified_diff(p, before, after)
+            })
+            .collect()
+    }
+
+    /// Like [`Self::diff`], leaving out `path`.
+    pub fn diff_except(&self, path: &Path) -> String {
+        let others = Changes {
+            before: self.before.clone().into_iter().filter(|(p, _)| p != path).collect(),
+            after: self.after.clone().into_iter().filter(|(p, _)| p != path).collect(),
+        };
+        others.diff()
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.before
+            .keys()
+            .chain(self.after.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+    }
+}
+
+/// The files a run has touched inside the working tree, with their original
+/// contents, so candidates can be applied atomically, diffed, reset and promoted.
+pub struct Workspace {
+    root: PathBuf,
+    originals: BTreeMap<PathBuf, Option<String>>,
+    /// Existing files, besides the touched ones, that edits may change.
+    editable: BTreeSet<PathBuf>,
+}
+
+impl Workspace {
+    /// Start tracking `tracked` (repo-relative) under `root`.
+    pub fn new(root: &Path, tracked: &[&Path]) -> Self {
+        let mut ws = Self {
+            root: root.to_path_buf(),
+            originals: BTreeMap::new(),
+            editable: BTreeSet::new(),
+        };
+        for rel in tracked {
+            ws.track(rel);
+        }
+        ws
+    }
+
+    fn track(&mut self, rel: &Path) {
+        let abs = self.root.join(rel);
+        self.originals
+            .entry(rel.to_path_buf())
+            .or_insert_with(|| fs::read_to_string(abs).ok());
+    }
+
+    /// Let edits change `rel` as well, e.g. a file the agent was shown.
+    pub fn allow(&mut self, rel: &Path) {
+        self.editable.insert(rel.to_path_buf());
+    }
+
+    /// [`EditSet::validate`] against the files as they are at `base`: besides
+    /// creating files, edits may only change the touched files and those passed to
+    /// [`Self::allow`].
+    pub fn validate(
+        &self,
+        edits: &EditSet,
+        paths: &PathsConfig,
+        base: Base,
+    ) -> Result<(), InvalidEdits> {
+        let exists = |rel: &Path| match (base, self.originals.get(rel)) {
+            (Base::Original, Some(original)) => original.is_some(),
+            _ => self.root.join(rel).is_file(),
+        };
+        let editable = self.originals.keys().chain(&self.editable).collect();
+        edits.validate(exists, paths, &editable)
+    }
+
+    /// Repo-relative paths touched so far.
+    pub fn touched(&self) -> impl Iterator<Item = &PathBuf> {
+        self.originals.keys()
+    }
+
+    /// Validate `edits`, then apply all of them or, if any write fails, none.
+    pub fn apply(
+        &mut self,
+        edits: &EditSet,
+        paths: &PathsConfig,
+        sandbox: &Sandbox,
+        txn: &Transaction,
+    ) -> Result<()> {
+        self.validate(edits, paths, Base::Current)?;
+        let mut applied: Vec<(PathBuf, Option<String>)> = vec![];
+        for e in &edits.edits {
+            self.track(&e.path);
+            let abs = self.root.join(&e.path);
+            let previous = fs::read_to_string(&abs).ok();
+            let res = match e.action {
+                EditAction::Delete => sandbox.remove(&abs, txn),
+                EditAction::Patch => Err(anyhow::anyhow!("patch was not resolved before applying")),
+                _ => sandbox.write(&abs, &e.content, txn),
+            };
+            if let Err(err) = res {
+                for (path, content) in applied.iter().rev() {
+                    let _ = restore(path, content.as_deref(), sandbox, txn);
+                }
+                return Err(err).with_context(|| format!("apply edit to {}", e.path.display()));
+            }
+            applied.push((abs, previous));
+        }
+        Ok(())
+    }
+
+    /// Put every touched file back to its original state, ready for a fresh candidate.
+    pub fn reset(&self, sandbox: &Sandbox, txn: &Transaction) -> Result<()> {
+        for (rel, original) in &self.originals {
+            restore(&self.root.join(rel), original.as_deref(), sandbox, txn)?;
+        }
+        Ok(())
+    }
+
+    /// Put every touched file back to a saved state such as [`Changes::after`];
+    /// touched files missing from `files` are deleted.
+    pub fn restore(
+        &self,
+        files: &BTreeMap<PathBuf, String>,
+        sandbox: &Sandbox,
+        txn: &Transaction,
+    ) -> Result<()> {
+        for rel in self.originals.keys() {
+            let content = files.get(rel).map(String::as_str);
+            restore(&self.root.join(rel), content, sandbox, txn)?;
+        }
+        Ok(())
+    }
+
+    /// Turn every patch in `edits` into a full-file `Modify` by applying its hunks
+    /// to `base`. Nothing is written; every hunk that can't be placed is reported.
+    pub fn resolve(&self, edits: &mut EditSet, base: Base) -> Result<(), HunkFailures> {
+        let mut failures = vec![];
+        for e in edits.edits.iter_mut().filter(|e| e.action == EditAction::Patch) {
+            let content = match (base, self.originals.get(&e.path)) {
+                (Base::Original, Some(original)) => original.clone(),
+                _ => fs::read_to_string(self.root.join(&e.path)).ok(),
+            };
+            let Some(content) = content else {
+                failures.push(FailedHunk {
+                    path: e.path.clone(),
+                    index: 1,
+                    search: String::new(),
+                    reason: "cannot patch a file that does not exist".into(),
+                });
+                continue;
+            };
+            match hunks::apply(&content, &e.hunks) {
+                Ok(patched) => {
+                    e.action = EditAction::Modify;
+                    e.content = patched;
+                    e.hunks.clear();
+                }
+                Err(failed) => failures.extend(failed.into_iter().map(|(i, reason)| FailedHunk {
+                    path: e.path.clone(),
+                    index: i + 1,
+                    search: e.hunks[i].search.clone(),
+                    reason,
+                })),
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(HunkFailures(failures))
+        }
+    }
+
+    /// Diff of every file in `edits` against its original, without touching disk.
+    pub fn preview(&self, edits: &EditSet) -> String {
+        edits
+            .edits
+            .iter()
+            .map(|e| {
+                let before = match self.originals.get(&e.path) {
+                    Some(original) => original.clone(),
+                    None => fs::read_to_string(self.root.join(&e.path)).ok(),
+                };
+                let after = (e.action != EditAction::Delete).then_some(e.content.as_str());
+                unified_diff(&e.path, before.as_deref(), after)
+            })
+            .collect()
+    }
+
+    /// Original and current contents of the touched files.
+    pub fn changes(&self) -> Result<Changes> {
+        let mut changes = Changes::default();
+        for (rel, original) in &self.originals {
+            if let Some(original) = original {
+                changes.before.insert(rel.clone(), original.clone());
+            }
+            let abs = self.root.join(rel);
+            if abs.is_file() {
+                let now = fs::read_to_string(&abs).with_context(|| format!("read {}", abs.display()))?;
+                changes.after.insert(rel.clone(), now);
+            }
+        }
+        Ok(changes)
+    }
+}
+
+fn restore(path: &Path, content: Option<&str>, sandbox: &Sandbox, txn: &Transaction) -> Result<()> {
+    match content {
+        Some(c) => sandbox.write(path, c, txn),
+        None if path.exists() => sandbox.remove(path, txn),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn multi_file_diff_patches_each_file() {
+        let response = "\
+```diff
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1 +1 @@
+-pub fn a() {}
++pub fn a() -> u8 { 0 }
+--- a/src/util.rs
++++ b/src/util.rs
+@@ -1 +1 @@
+-fn b() {}
++fn b() -> u8 { 1 }
+```
+";
+        let set = EditSet::parse(response, Path::new("src/lib.rs")).unwrap();
+        let paths: Vec<_> = set.edits.iter().map(|e| (e.path.clone(), e.action)).collect();
+        assert_eq!(
+            paths,
+            vec![
+                (PathBuf::from("src/lib.rs"), EditAction::Patch),
+                (PathBuf::from("src/util.rs"), EditAction::Patch),
+            ]
+        );
+        assert_eq!(set.edits[1].hunks[0].search, "fn b() {}\n");
+    }
+
+    #[test]
+    fn only_shown_files_may_change() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("src")).unwrap();
+        for f in ["src/lib.rs", "src/util.rs", "src/other.rs"] {
+            fs::write(tmp.path().join(f), "").unwrap();
+        }
+        let paths = PathsConfig::new(vec!["**/*.rs".into()]).unwrap();
+        let mut ws = Workspace::new(tmp.path(), &[Path::new("src/lib.rs")]);
+        ws.allow(Path::new("src/util.rs"));
+        let edit = |path: &str, action| FileEdit {
+            path: PathBuf::from(path),
+            action,
+            content: String::new(),
+            hunks: vec![],
+        };
+
+        let shown = EditSet {
+            edits: vec![
+                edit("src/lib.rs", EditAction::Modify),
+                edit("src/util.rs", EditAction::Delete),
+                edit("src/new.rs", EditAction::Create),
+            ],
+        };
+        assert!(ws.validate(&shown, &paths, Base::Current).is_ok());
+
+        let unseen = EditSet {
+            edits: vec![
+                edit("src/other.rs", EditAction::Modify),
+                edit("src/lib.rs", EditAction::Modify),
+            ],
+        };
+        let err = ws.validate(&unseen, &paths, Base::Current).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid edits:\n  src/other.rs: Modify, but you were not shown this file; \
+             only src/lib.rs, src/util.rs can be changed"
+        );
+        let delete = EditSet {
+            edits: vec![edit("src/other.rs", EditAction::Delete)],
+        };
+        assert!(ws.validate(&delete, &paths, Base::Current).is_err());
+    }
+}
@@ -1,6 +1,35 @@
 use anyhow::{Context, Result};
-use std::process::Stdio;
-use tokio::{io::AsyncReadExt, process::Command};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Mutex,
+};
+use tokio::process::Command;
+
+/// Sandbox roots and the target directory cargo builds into under each.
+static TARGET_DIRS: Mutex<Vec<(PathBuf, PathBuf)>> = Mutex::new(Vec::new());
+
+/// Point cargo invocations made through [`run_cmd`] anywhere under `root` at `dir`.
+pub fn register_target_dir(root: &Path, dir: &Path) {
+    let mut dirs = TARGET_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    dirs.retain(|(r, _)| r != root);
+    dirs.push((root.to_path_buf(), dir.to_path_buf()));
+}
+
+/// Undo [`register_target_dir`] for `root`.
+pub fn unregister_target_dir(root: &Path) {
+    let mut dirs = TARGET_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    dirs.retain(|(r, _)| r != root);
+}
+
+/// The target directory registered for the innermost sandbox containing `cwd`.
+fn target_dir_for(cwd: &Path) -> Option<PathBuf> {
+    let dirs = TARGET_DIRS.lock().unwrap_or_else(|e| e.into_inner());
+    dirs.iter()
+        .filter(|(root, _)| cwd.starts_with(root))
+        .max_by_key(|(root, _)| root.components().count())
+        .map(|(_, dir)| dir.clone())
+}
 
 #[derive(Debug, Clone)]
 pub struct CmdOutput {
@@ -10,32 +39,72 @@ pub struct CmdOutput {
 }
 
 pub async fn run_cmd(cwd: &std::path::Path, program: &str, args: &[&str]) -> Result<CmdOutput> {
+    run_cmd_in(cwd, program, args, target_dir_for(cwd).as_deref()).await
+}
+
+/// [`run_cmd`] with cargo building into `target_dir` instead of the shared directory.
+async fn run_cmd_in(
+    cwd: &std::path::Path,
+    program: &str,
+    args: &[&str],
+    target_dir: Option<&std::path::Path>,
+) -> Result<CmdOutput> {
     let mut cmd = Command::new(program);
     cmd.args(args)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .current_dir(cwd);
-
-    let mut child = cmd.spawn().with_context(|| format!("spawn {}", program))?;
-    let status = child.wait().await?.code().unwrap_or(-1);
-
-    let mut out = String::new();
-    let mut err = String::new();
-
-    if let Some(mut o) = child.stdout.take() {
-        let mut buf = String::new();
-        o.read_to_string(&mut buf).await.ok();
-        out = buf;
-    }
-    if let Some(mut e) = child.stderr.take() {
-        let mut buf = String::new();
-        e.read_to_string(&mut buf).await.ok();
-        err = buf;
+        .current_dir(cwd)
+        .kill_on_drop(true);
+    if let Some(dir) = target_dir {
+        cmd.env("CARGO_TARGET_DIR", dir);
     }
 
+    // Read both pipes while waiting, so a child with more output than the pipe
+    // buffer holds doesn't block forever on a full pipe.
+    let out = cmd
+        .spawn()
+        .with_context(|| format!("spawn {}", program))?
+        .wait_with_output()
+        .await?;
+
     Ok(CmdOutput {
-        status,
-        stdout: out,
-        stderr: err,
+        status: out.status.code().unwrap_or(-1),
+        stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
     })
 }
+
+/// Run a whitespace-separated command line such as `cargo test --all` (no shell quoting).
+pub async fn run_cmdline(cwd: &std::path::Path, cmdline: &str) -> Result<CmdOutput> {
+    run_cmdline_with(cwd, cmdline, &[]).await
+}
+
+/// [`run_cmdline`] with extra arguments appended, e.g. the files to format.
+pub async fn run_cmdline_with(
+    cwd: &std::path::Path,
+    cmdline: &str,
+    extra: &[String],
+) -> Result<CmdOutput> {
+    let mut parts = cmdline.split_whitespace();
+    let program = parts
+        .next()
+        .with_context(|| format!("empty command line `{cmdline}`"))?;
+    let args: Vec<&str> = parts.chain(extra.iter().map(String::as_str)).collect();
+    run_cmd(cwd, program, &args).await
+}
+
+/// [`run_cmdline`] with cargo building into `target_dir`. Copies of one workspace
+/// look identical to cargo, so copies that exist at the same time must not share
+/// a target directory or one may be judged up to date from the other's build.
+pub async fn run_cmdline_in(
+    cwd: &std::path::Path,
+    cmdline: &str,
+    target_dir: &std::path::Path,
+) -> Result<CmdOutput> {
+    let mut parts = cmdline.split_whitespace();
+    let program = parts
+        .next()
+        .with_context(|| format!("empty command line `{cmdline}`"))?;
+    let args: Vec<&str> = parts.collect();
+    run_cmd_in(cwd, program, &args, Some(target_dir)).await
+}
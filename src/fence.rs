@@ -0,0 +1,273 @@
+use std::{fmt, path::PathBuf};
+
+/// A fenced code block from a Markdown model response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeBlock {
+    /// Canonical language (`rust`, `diff`, `delete`, …), lowercased; empty if none.
+    pub lang: String,
+    /// `key=value` words of the info string, quotes stripped.
+    pub attrs: Vec<(String, String)>,
+    /// File named by the info string (`file=`, `path=`, or a bare path) or by a
+    /// header line right above the fence (`src/lib.rs:`, `### File: src/lib.rs`).
+    pub path: Option<PathBuf>,
+    pub body: String,
+    /// 1-based line of the opening fence.
+    pub line: usize,
+}
+
+impl CodeBlock {
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Why no usable edits could be read from a response. The message is written for
+/// the model, so it can be sent back verbatim with a request to try again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtractError {
+    /// No fenced code block at all.
+    NoBlocks,
+    /// A fence opened on `line` is never closed, usually because the response was cut off.
+    Unterminated { line: usize, lang: String },
+    /// Blocks were found, but none is Rust or names a file.
+    NoEdits { langs: Vec<String> },
+    /// Several Rust blocks without a file path and none of them labelled as the target.
+    Ambiguous { count: usize },
+    /// A block could not be read as an edit.
+    Invalid { line: usize, reason: String },
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::NoBlocks => write!(
+                f,
+                "the response contains no fenced code block; return each file in a ```rust file=<path> block"
+            ),
+            ExtractError::Unterminated { line, lang } => write!(
+                f,
+                "the `{}` code block opened on line {line} is never closed (was the response cut off?); \
+                 close every block, and prefer search/replace blocks for large files",
+                if lang.is_empty() { "untagged" } else { lang }
+            ),
+            ExtractError::NoEdits { langs } => write!(
+                f,
+                "found code blocks ({}) but none is Rust or names a file; use ```rust file=<path>",
+                langs.join(", ")
+            ),
+            ExtractError::Ambiguous { count } => write!(
+                f,
+                "found {count} Rust blocks without a file path; label each one with file=<path>"
+            ),
+            ExtractError::Invalid { line, reason } => {
+                write!(f, "the code block on line {line} is invalid: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+/// Canonical name for common spellings of a block language.
+pub fn canonical_lang(lang: &str) -> String {
+    let lang = lang.to_ascii_lowercase();
+    match lang.as_str() {
+        "rs" | "rust2015" | "rust2018" | "rust2021" | "rust2024" => "rust".into(),
+        "patch" | "udiff" => "diff".into(),
+        "toml" | "cargo" => "toml".into(),
+        "rm" | "remove" => "delete".into(),
+        _ => lang,
+    }
+}
+
+/// Parse every fenced block in `text` following CommonMark: fences of three or
+/// more backticks or tildes, indented by at most three spaces, closed by a fence of
+/// the same character that is at least as long. Shorter or different fences
+/// inside a block (nested examples, string literals) are part of its body.
+pub fn parse_blocks(text: &str) -> Result<Vec<CodeBlock>, ExtractError> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        let Some((indent, ch, len, info)) = opening_fence(lines[i]) else {
+            i += 1;
+            continue;
+        };
+        let start = i;
+        let mut body = vec![];
+        i += 1;
+        let mut closed = false;
+        while i < lines.len() {
+            if is_closing_fence(lines[i], ch, len) {
+                closed = true;
+                i += 1;
+                break;
+            }
+            body.push(strip_indent(lines[i], indent));
+            i += 1;
+        }
+        // `rust,ignore` and `{.rust}` style info strings.
+        let lang = info
+            .split_whitespace()
+            .next()
+            .map(|w| w.trim_matches(|c| c == '{' || c == '}' || c == '.'))
+            .and_then(|w| w.split(',').next())
+            .filter(|w| !w.contains('=') && !looks_like_path(w))
+            .map(canonical_lang)
+            .unwrap_or_default();
+        if !closed {
+            return Err(ExtractError::Unterminated {
+                line: start + 1,
+                lang,
+            });
+        }
+        let mut attrs = vec![];
+        let mut path = None;
+        for word in info.split_whitespace().skip(usize::from(!lang.is_empty())) {
+            match word.split_once('=') {
+                Some((k, v)) => {
+                    let v = v.trim_matches(|c| c == '"' || c == '\'');
+                    if matches!(k, "file" | "path" | "title" | "filename") {
+                        path = Some(PathBuf::from(v));
+                    }
+                    attrs.push((k.to_string(), v.to_string()));
+                }
+                None if looks_like_path(word) => path = Some(PathBuf::from(word)),
+                None => {}
+            }
+        }
+        let path = path.or_else(|| {
+            lines[..start]
+                .iter()
+                .rev()
+                .find(|l| !l.trim().is_empty())
+                .and_then(|l| header_path(l))
+        });
+        blocks.push(CodeBlock {
+            lang,
+            attrs,
+            path,
+            body: body.join("\n"),
+            line: start + 1,
+        });
+    }
+    Ok(blocks)
+}
+
+/// `(indent, fence char, fence length, info string)` if `line` opens a block.
+fn opening_fence(line: &str) -> Option<(usize, char, usize, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    let indent = line.len() - trimmed.len();
+    let ch = trimmed.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let len = trimmed.len() - trimmed.trim_start_matches(ch).len();
+    let info = trimmed[len..].trim();
+    // A backtick fence's info string can't contain backticks (that's inline code).
+    if indent > 3 || len < 3 || (ch == '`' && info.contains('`')) {
+        return None;
+    }
+    Some((indent, ch, len, info))
+}
+
+fn is_closing_fence(line: &str, ch: char, len: usize) -> bool {
+    let trimmed = line.trim_start_matches(' ');
+    let indent = line.len() - trimmed.len();
+    let run = trimmed.len() - trimmed.trim_start_matches(ch).len();
+    indent <= 3 && run >= len && trimmed[run..].trim().is_empty()
+}
+
+/// Remove up to `n` leading spaces, as CommonMark does for indented fences.
+fn strip_indent(line: &str, n: usize) -> &str {
+    let spaces = line.len() - line.trim_start_matches(' ').len();
+    &line[spaces.min(n)..]
+}
+
+fn looks_like_path(word: &str) -> bool {
+    !word.contains(char::is_whitespace)
+        && (word.ends_with(".rs") || word.ends_with(".toml"))
+        && !word.starts_with('.')
+}
+
+/// A file path announced on the line above a fence, e.g. `src/lib.rs:`,
+/// `**src/lib.rs**`, `### File: src/lib.rs` or `// src/lib.rs`.
+fn header_path(line: &str) -> Option<PathBuf> {
+    let mut s = line
+        .trim()
+        .trim_start_matches(['#', '/', '-', '*', '>'])
+        .trim();
+    for prefix in ["file:", "path:", "filename:"] {
+        // `get` rather than slicing: the line is model text and may not be ASCII.
+        if s.get(..prefix.len()).is_some_and(|p| p.eq_ignore_ascii_case(prefix)) {
+            s = s[prefix.len()..].trim();
+        }
+    }
+    let s = s.trim_matches(|c| matches!(c, '`' | '*' | '_' | ':' | '"'));
+    looks_like_path(s).then(|| PathBuf::from(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn reads_lang_path_and_body() {
+        let blocks = parse_blocks("Intro\n```rust file=src/lib.rs\nfn a() {}\n```\n").unwrap();
+        assert_eq!(
+            blocks,
+            vec![CodeBlock {
+                lang: "rust".into(),
+                attrs: vec![("file".into(), "src/lib.rs".into())],
+                path: Some(PathBuf::from("src/lib.rs")),
+                body: "fn a() {}".into(),
+                line: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn takes_path_from_header_line() {
+        let blocks = parse_blocks("### File: src/main.rs\n\n```rs\nfn main() {}\n```").unwrap();
+        assert_eq!(blocks[0].lang, "rust");
+        assert_eq!(blocks[0].path, Some(PathBuf::from("src/main.rs")));
+    }
+
+    #[test]
+    fn fence_under_non_ascii_line() {
+        for header in ["Here’s the updated file:", "ÉÉ", "fi€", "→ src/lib.rs"] {
+            let text = format!("{header}\n```\nfn a() {{}}\n```\n");
+            let blocks = parse_blocks(&text).unwrap();
+            assert_eq!(blocks.len(), 1, "{header}");
+            assert_eq!(blocks[0].body, "fn a() {}");
+            assert_eq!(blocks[0].path, None, "{header}");
+        }
+    }
+
+    #[test]
+    fn longer_fence_keeps_inner_fences() {
+        let text = "````rust\nlet s = \"```\";\n```\n````\n";
+        let blocks = parse_blocks(text).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].body, "let s = \"```\";\n```");
+    }
+
+    #[test]
+    fn unterminated_block_is_an_error() {
+        assert_eq!(
+            parse_blocks("text\n```diff\n-a\n+b\n"),
+            Err(ExtractError::Unterminated {
+                line: 2,
+                lang: "diff".into()
+            })
+        );
+    }
+
+    #[test]
+    fn canonical_langs() {
+        assert_eq!(canonical_lang("RS"), "rust");
+        assert_eq!(canonical_lang("patch"), "diff");
+        assert_eq!(canonical_lang("rm"), "delete");
+    }
+}
@@ -0,0 +1,500 @@
+use anyhow::{bail, Result};
+use similar::TextDiff;
+use std::{fmt, path::PathBuf};
+
+/// Windows whose lines are on average at least this similar to a hunk's search
+/// lines are accepted as its location.
+const FUZZY_THRESHOLD: f32 = 0.85;
+
+const SEARCH: &str = "<<<<<<< SEARCH";
+const DIVIDER: &str = "=======";
+const REPLACE: &str = ">>>>>>> REPLACE";
+
+/// Replace `search` with `replace` somewhere in a file. An empty `search` appends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    pub search: String,
+    pub replace: String,
+}
+
+/// Whether a block body uses search/replace markers.
+pub fn is_search_replace(body: &str) -> bool {
+    body.lines().any(|l| l.trim_end() == SEARCH)
+}
+
+/// Parse `<<<<<<< SEARCH` / `=======` / `>>>>>>> REPLACE` blocks.
+pub fn parse_search_replace(body: &str) -> Result<Vec<Hunk>> {
+    let mut hunks = vec![];
+    let mut lines = body.lines();
+    while let Some(line) = lines.next() {
+        if line.trim_end() != SEARCH {
+            continue;
+        }
+        let mut search = vec![];
+        let mut replace = vec![];
+        let mut in_replace = false;
+        let mut closed = false;
+        for l in lines.by_ref() {
+            match l.trim_end() {
+                DIVIDER if !in_replace => in_replace = true,
+                REPLACE if in_replace => {
+                    closed = true;
+                    break;
+                }
+                _ if in_replace => replace.push(l),
+                _ => search.push(l),
+            }
+        }
+        if !closed {
+            bail!(
+                "search/replace block {} is not closed with `{REPLACE}`",
+                hunks.len() + 1
+            );
+        }
+        hunks.push(Hunk {
+            search: join_lines(&search),
+            replace: join_lines(&replace),
+        });
+    }
+    Ok(hunks)
+}
+
+/// The hunks of one file in a unified diff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHunks {
+    /// From the file's `+++ b/<path>` header, if any.
+    pub path: Option<PathBuf>,
+    pub hunks: Vec<Hunk>,
+}
+
+/// Parse the `@@` hunks of a unified diff, grouped by file in the order the
+/// `---`/`+++` headers (or `diff --git` lines) appear. Line numbers are ignored:
+/// hunks are located by their context, like search/replace blocks.
+pub fn parse_unified(body: &str) -> Result<Vec<FileHunks>> {
+    let mut files: Vec<FileHunks> = vec![];
+    let mut current: Option<(Vec<&str>, Vec<&str>)> = None;
+    let flush = |current: &mut Option<(Vec<&str>, Vec<&str>)>, files: &mut Vec<FileHunks>| {
+        if let Some((search, replace)) = current.take() {
+            if files.is_empty() {
+                files.push(FileHunks {
+                    path: None,
+                    hunks: vec![],
+                });
+            }
+            if let Some(file) = files.last_mut() {
+                file.hunks.push(Hunk {
+                    search: join_lines(&search),
+                    replace: join_lines(&replace),
+                });
+            }
+        }
+    };
+    let lines: Vec<&str> = body.lines().collect();
+    let mut after_git = false;
+    for (i, &line) in lines.iter().enumerate() {
+        // Headers first: inside a hunk `--- a/x` would otherwise read as a removed line.
+        // It is a header only when a `+++` line follows.
+        let is_old_header = line.starts_with("--- ")
+            && lines.get(i + 1).is_some_and(|next| next.starts_with("+++ "));
+        if line.starts_with("diff --git") || is_old_header {
+            flush(&mut current, &mut files);
+            // The `---` header right after `diff --git` belongs to the same file.
+            if !(is_old_header && after_git) {
+                files.push(FileHunks {
+                    path: None,
+                    hunks: vec![],
+                });
+            }
+            after_git = !is_old_header;
+            continue;
+        }
+        if i > 0 && line.starts_with("+++ ") && lines[i - 1].starts_with("--- ") {
+            let p = line["+++ ".len()..].trim();
+            if let Some(file) = files.last_mut() {
+                if p != "/dev/null" {
+                    file.path = Some(PathBuf::from(p.strip_prefix("b/").unwrap_or(p)));
+                }
+            }
+            continue;
+        }
+        if line.starts_with("@@") {
+            after_git = false;
+            flush(&mut current, &mut files);
+            current = Some((vec![], vec![]));
+            continue;
+        }
+        let Some((search, replace)) = current.as_mut() else {
+            // `index …`, `new file mode …` and commentary before the first hunk.
+            continue;
+        };
+        match line.chars().next() {
+            Some('-') => search.push(&line[1..]),
+            Some('+') => replace.push(&line[1..]),
+            Some(' ') => {
+                search.push(&line[1..]);
+                replace.push(&line[1..]);
+            }
+            // Models often drop the leading space of blank context lines.
+            None => {
+                search.push("");
+                replace.push("");
+            }
+            Some('\\') => {}
+            Some(_) => bail!("unexpected line in diff hunk: {line:?}"),
+        }
+    }
+    flush(&mut current, &mut files);
+    files.retain(|f| !f.hunks.is_empty());
+    if files.is_empty() {
+        bail!("diff contains no `@@` hunks");
+    }
+    Ok(files)
+}
+
+fn join_lines(lines: &[&str]) -> String {
+    lines.iter().map(|l| format!("{l}\n")).collect()
+}
+
+/// A hunk that could not be placed.
+#[derive(Debug, Clone)]
+pub struct FailedHunk {
+    pub path: PathBuf,
+    /// 1-based position of the hunk among those for `path`.
+    pub index: usize,
+    pub search: String,
+    pub reason: String,
+}
+
+/// Every hunk of an edit set that failed, rendered so it can be sent back to the model.
+#[derive(Debug, Clone, Default)]
+pub struct HunkFailures(pub Vec<FailedHunk>);
+
+impl fmt::Display for HunkFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} edit block(s) could not be applied:", self.0.len())?;
+        for h in &self.0 {
+            writeln!(f, "\n{}, block {}: {}", h.path.display(), h.index, h.reason)?;
+            if !h.search.is_empty() {
+                writeln!(f, "SEARCH text was:\n```\n{}```", h.search)?;
+            }
+        }
+        write!(
+            f,
+            "\nCopy SEARCH text verbatim from the current file, with enough context to be unique, or return the whole file instead."
+        )
+    }
+}
+
+impl std::error::Error for HunkFailures {}
+
+/// Apply `hunks` to `content` in order. Each hunk is located by an exact match,
+/// then by a match that ignores indentation and trailing whitespace, then (for
+/// multi-line hunks) by the most similar window of lines. Returns the new content, or why each failing hunk
+/// (by 0-based index) could not be placed.
+pub fn apply(content: &str, hunks: &[Hunk]) -> Result<String, Vec<(usize, String)>> {
+    let mut out = content.to_string();
+    let mut failed = vec![];
+    for (i, hunk) in hunks.iter().enumerate() {
+        match apply_one(&out, hunk) {
+            Ok(next) => out = next,
+            Err(reason) => failed.push((i, reason)),
+        }
+    }
+    if failed.is_empty() {
+        Ok(out)
+    } else {
+        Err(failed)
+    }
+}
+
+fn apply_one(content: &str, hunk: &Hunk) -> Result<String, String> {
+    if hunk.search.trim().is_empty() {
+        let mut out = content.to_string();
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        out.push_str(&hunk.replace);
+        return Ok(out);
+    }
+
+    match content.matches(hunk.search.as_str()).count() {
+        1 => return Ok(content.replacen(&hunk.search, &hunk.replace, 1)),
+        0 => {}
+        n => {
+            return Err(format!(
+                "SEARCH text matches {n} places; add surrounding lines to make it unique"
+            ))
+        }
+    }
+
+    let lines: Vec<&str> = content.lines().collect();
+    let search: Vec<&str> = hunk.search.lines().collect();
+    let replace: Vec<&str> = hunk.replace.lines().collect();
+    if search.len() > lines.len() {
+        return Err("SEARCH text is longer than the file".into());
+    }
+    let windows = 0..=lines.len() - search.len();
+
+    let loose: Vec<usize> = windows
+        .clone()
+        .filter(|&start| {
+            search
+                .iter()
+                .zip(&lines[start..])
+                .all(|(s, l)| s.trim() == l.trim())
+        })
+        .collect();
+    match loose.as_slice() {
+        [start] => return Ok(splice(&lines, *start, &search, &replace, content)),
+        [] => {}
+        many => {
+            return Err(format!(
+                "SEARCH text matches {} places when whitespace is ignored; add surrounding lines to make it unique",
+                many.len()
+            ))
+        }
+    }
+
+    let mut best: Option<(usize, f32)> = None;
+    let mut tied = false;
+    for start in windows {
+        let ratio = similarity(&lines[start..start + search.len()], &search);
+        match best {
+            Some((_, r)) if ratio < r => {}
+            Some((_, r)) if ratio == r => tied = true,
+            _ => {
+                best = Some((start, ratio));
+                tied = false;
+            }
+        }
+    }
+    match best {
+        // A single line is too little context to place approximately.
+        Some((start, ratio)) if ratio >= FUZZY_THRESHOLD && !tied && search.len() > 1 => {
+            Ok(splice(&lines, start, &search, &replace, content))
+        }
+        Some((start, ratio)) => {
+            let at = match search.len() {
+                1 => format!("line {}", start + 1),
+                n => format!("lines {}-{}", start + 1, start + n),
+            };
+            Err(format!(
+                "SEARCH text not found; closest match is {at} ({:.0}% similar)",
+                ratio * 100.0
+            ))
+        }
+        None => Err("SEARCH text not found".into()),
+    }
+}
+
+/// Mean character-level similarity of aligned lines, ignoring surrounding whitespace.
+fn similarity(window: &[&str], search: &[&str]) -> f32 {
+    let total: f32 = window
+        .iter()
+        .zip(search)
+        .map(|(w, s)| TextDiff::from_chars(w.trim(), s.trim()).ratio())
+        .sum();
+    total / search.len() as f32
+}
+
+/// Replace `search.len()` lines at `start` with `replace`, shifting the replacement
+/// by the indentation difference between the search text and the matched lines.
+fn splice(
+    lines: &[&str],
+    start: usize,
+    search: &[&str],
+    replace: &[&str],
+    content: &str,
+) -> String {
+    // Only ASCII indentation is measured, so the byte offsets below stay on char
+    // boundaries; other whitespace is kept as part of the line.
+    let indent = |s: &str| s.len() - s.trim_start_matches([' ', '\t']).len();
+    let first = search
+        .iter()
+        .position(|s| !s.trim().is_empty())
+        .unwrap_or(0);
+    let found = indent(lines[start + first]) as isize;
+    let wanted = search.get(first).map_or(0, |s| indent(s)) as isize;
+    let shift = found - wanted;
+    let prefix = &lines[start + first][..found.max(0) as usize];
+
+    let mut out: Vec<String> = lines[..start].iter().map(|l| l.to_string()).collect();
+    for r in replace {
+        let line = if r.trim().is_empty() || shift == 0 {
+            r.to_string()
+        } else if shift > 0 {
+            format!("{}{r}", &prefix[..shift as usize])
+        } else {
+            let strip = (-shift as usize).min(indent(r));
+            r[strip..].to_string()
+        };
+        out.push(line);
+    }
+    out.extend(lines[start + search.len()..].iter().map(|l| l.to_string()));
+    let mut text = out.join("\n");
+    if content.ends_with('\n') {
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn hunk(search: &str, replace: &str) -> Hunk {
+        Hunk {
+            search: search.into(),
+            replace: replace.into(),
+        }
+    }
+
+    #[test]
+    fn unified_diff_groups_hunks_by_file() {
+        let diff = "\
+diff --git a/src/a.rs b/src/a.rs
+index 1111111..2222222 100644
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,2 +1,2 @@
+ fn a() {
+-    1
++    2
+diff --git a/src/b.rs b/src/b.rs
+--- a/src/b.rs
++++ b/src/b.rs
+@@ -1 +1 @@
+-fn b() {}
++fn b() -> u8 { 0 }
+@@ -5 +5 @@
+-fn c() {}
++fn c() -> u8 { 1 }
+";
+        assert_eq!(
+            parse_unified(diff).unwrap(),
+            vec![
+                FileHunks {
+                    path: Some(PathBuf::from("src/a.rs")),
+                    hunks: vec![hunk("fn a() {\n    1\n", "fn a() {\n    2\n")],
+                },
+                FileHunks {
+                    path: Some(PathBuf::from("src/b.rs")),
+                    hunks: vec![
+                        hunk("fn b() {}\n", "fn b() -> u8 { 0 }\n"),
+                        hunk("fn c() {}\n", "fn c() -> u8 { 1 }\n"),
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unified_diff_without_git_lines() {
+        let diff = "\
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1 +1 @@
+-let x = 1;
++let x = 2;
+--- a/src/b.rs
++++ b/src/b.rs
+@@ -1 +1 @@
+--- comment
++// comment
+";
+        let files = parse_unified(diff).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, Some(PathBuf::from("src/a.rs")));
+        assert_eq!(files[0].hunks, vec![hunk("let x = 1;\n", "let x = 2;\n")]);
+        assert_eq!(files[1].path, Some(PathBuf::from("src/b.rs")));
+        // A removed line starting with `--` is not a header without a `+++` after it.
+        assert_eq!(files[1].hunks, vec![hunk("-- comment\n", "// comment\n")]);
+    }
+
+    #[test]
+    fn unified_diff_without_headers() {
+        let files = parse_unified("@@ -1 +1 @@\n-a\n+b\n").unwrap();
+        assert_eq!(
+            files,
+            vec![FileHunks {
+                path: None,
+                hunks: vec![hunk("a\n", "b\n")],
+            }]
+        );
+        assert!(parse_unified("--- a/x.rs\n+++ b/x.rs\n").is_err());
+    }
+
+    #[test]
+    fn search_replace_blocks() {
+        let body = "\
+<<<<<<< SEARCH
+fn a() {}
+=======
+fn a() -> u8 { 0 }
+>>>>>>> REPLACE
+<<<<<<< SEARCH
+=======
+fn b() {}
+>>>>>>> REPLACE
+";
+        assert!(is_search_replace(body));
+        assert_eq!(
+            parse_search_replace(body).unwrap(),
+            vec![
+                hunk("fn a() {}\n", "fn a() -> u8 { 0 }\n"),
+                hunk("", "fn b() {}\n"),
+            ]
+        );
+        assert!(parse_search_replace("<<<<<<< SEARCH\nx\n=======\ny\n").is_err());
+    }
+
+    #[test]
+    fn apply_exact_loose_and_append() {
+        let content = "fn main() {\n    let x = 1;\n}\n";
+        let out = apply(
+            content,
+            &[
+                hunk("let x = 1;", "let x = 2;"),
+                // Trailing whitespace only matches loosely.
+                hunk("    let x = 2;  \n}\n", "    let x = 2;\n    drop(x);\n}\n"),
+                hunk("", "fn other() {}\n"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            "fn main() {\n    let x = 2;\n    drop(x);\n}\nfn other() {}\n"
+        );
+    }
+
+    #[test]
+    fn apply_reports_search_text_that_does_not_match() {
+        let content = "fn main() {\n    run();\n}\n\nfn run() {}\n";
+        let failed = apply(content, &[hunk("fn main() {\n    walk();\n    stop();\n}\n", "")])
+            .unwrap_err();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 0);
+        assert!(failed[0].1.starts_with("SEARCH text not found"), "{}", failed[0].1);
+
+        let failed = apply("a\na\n", &[hunk("a\n", "b\n")]).unwrap_err();
+        assert!(failed[0].1.contains("matches 2 places"), "{}", failed[0].1);
+    }
+
+    #[test]
+    fn loose_match_keeps_non_ascii_indentation_whole() {
+        // The file is indented with ideographic spaces, which `trim` ignores.
+        let content = "fn main() {\n\u{3000}\u{3000}let x = 1;\n}\n";
+        let out = apply(content, &[hunk("    let x = 1;\n", "    let x = 2;\n")]).unwrap();
+        assert_eq!(out, "fn main() {\nlet x = 2;\n}\n");
+
+        // The replacement is indented with one, deeper than the matched line.
+        let content = "fn main() {\n  let x = 1;\n}\n";
+        let out = apply(
+            content,
+            &[hunk("    let x = 1;\n", "    let x = 1;\n\u{3000}drop(x);\n")],
+        )
+        .unwrap();
+        assert_eq!(out, "fn main() {\n  let x = 1;\n\u{3000}drop(x);\n}\n");
+    }
+}
@@ -0,0 +1,764 @@
+use anyhow::Result;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use syn::{
+    punctuated::Punctuated, visit::Visit, ImplItem, Item, ItemImpl, TraitItem, Type, UseTree,
+};
+use tracing::warn;
+
+use crate::io_utils::rust_sources;
+
+/// Repo-relative file and 1-based line.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+/// An item with a path such as `my_crate::config::Cli::validate`.
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub kind: &'static str,
+    pub loc: Location,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefKind {
+    /// Named in a `use` declaration.
+    Import,
+    /// Any other path: calls, types, patterns, struct literals, …
+    Path,
+    /// `.name(..)`; the receiver type is unknown, so this matches every method called `name`.
+    MethodByName,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub loc: Location,
+    pub kind: RefKind,
+    /// Path of the enclosing function, if any.
+    pub from: Option<String>,
+}
+
+/// A trait implementation site.
+#[derive(Debug, Clone)]
+pub struct ImplSite {
+    pub self_ty: String,
+    pub loc: Location,
+}
+
+/// Names visible in one module: its own items, `use` aliases and glob imports.
+#[derive(Debug, Default)]
+struct Scope {
+    items: BTreeSet<String>,
+    aliases: BTreeMap<String, Vec<String>>,
+    globs: Vec<Vec<String>>,
+}
+
+/// Every item of every crate under the repo (workspace members included) and
+/// every reference to it, with `use` paths resolved.
+#[derive(Debug, Default)]
+pub struct SymbolIndex {
+    pub defs: BTreeMap<String, Definition>,
+    pub refs: BTreeMap<String, Vec<Reference>>,
+    /// Trait path → its implementations.
+    pub impls: BTreeMap<String, Vec<ImplSite>>,
+    method_calls: BTreeMap<String, Vec<Reference>>,
+    /// Paths into the workspace that no longer name an item, e.g. after a rename.
+    unresolved: BTreeMap<String, Vec<Reference>>,
+    scopes: BTreeMap<String, Scope>,
+    crates: BTreeSet<String>,
+}
+
+/// A parsed source file and the module path of its root.
+struct SourceFile {
+    rel: PathBuf,
+    module: String,
+    ast: syn::File,
+}
+
+impl SymbolIndex {
+    pub fn build(repo: &Path) -> Result<Self> {
+        let mut index = Self::default();
+        let mut crate_names = BTreeMap::new();
+        let mut files = vec![];
+        for path in rust_sources(repo)? {
+            if path.extension().is_none_or(|e| e != "rs") {
+                continue;
+            }
+            let Some((crate_dir, name)) = owning_crate(repo, &path, &mut crate_names) else {
+                continue;
+            };
+            let rel_to_crate = path.strip_prefix(&crate_dir).unwrap_or(&path);
+            let module = module_path(&name, rel_to_crate);
+            let src = fs::read_to_string(&path)?;
+            let rel = path.strip_prefix(repo).unwrap_or(&path).to_path_buf();
+            match syn::parse_file(&src) {
+                Ok(ast) => files.push(SourceFile { rel, module, ast }),
+                Err(e) => warn!("index: skipping {} ({e})", rel.display()),
+            }
+            index.crates.insert(name);
+        }
+
+        let mut impls = vec![];
+        for f in &files {
+            index.collect_defs(&f.ast.items, &f.module, &f.rel, &mut impls);
+        }
+        for (module, rel, imp) in impls {
+            index.add_impl(&module, &rel, imp);
+        }
+        let mut found = Found::default();
+        for f in &files {
+            let mut collector = RefCollector {
+                index: &index,
+                found: &mut found,
+                file: &f.rel,
+                module: f.module.clone(),
+                self_ty: None,
+                current_fn: None,
+            };
+            collector.visit_file(&f.ast);
+        }
+        index.refs = found.refs;
+        index.method_calls = found.method_calls;
+        index.unresolved = found.unresolved;
+        Ok(index)
+    }
+
+    fn define(&mut self, path: String, kind: &'static str, file: &Path, ident: &syn::Ident) {
+        let loc = Location {
+            file: file.to_path_buf(),
+            line: ident.span().start().line,
+        };
+        self.defs.entry(path).or_insert(Definition { kind, loc });
+    }
+
+    fn collect_defs<'a>(
+        &mut self,
+        items: &'a [Item],
+        module: &str,
+        file: &Path,
+        impls: &mut Vec<(String, PathBuf, &'a ItemImpl)>,
+    ) {
+        let scope = self.scopes.entry(module.to_string()).or_default();
+        let mut named = vec![];
+        for item in items {
+            let (ident, kind) = match item {
+                Item::Fn(f) => (&f.sig.ident, "fn"),
+                Item::Struct(s) => (&s.ident, "struct"),
+                Item::Enum(e) => (&e.ident, "enum"),
+                Item::Union(u) => (&u.ident, "union"),
+                Item::Trait(t) => (&t.ident, "trait"),
+                Item::Type(t) => (&t.ident, "type"),
+                Item::Const(c) => (&c.ident, "const"),
+                Item::Static(s) => (&s.ident, "static"),
+                Item::Mod(m) => (&m.ident, "mod"),
+                Item::Use(u) => {
+                    add_use(scope, &u.tree, vec![]);
+                    continue;
+                }
+                Item::Impl(imp) => {
+                    impls.push((module.to_string(), file.to_path_buf(), imp));
+                    continue;
+                }
+                _ => continue,
+            };
+            scope.items.insert(ident.to_string());
+            named.push((item, ident, kind));
+        }
+        for (item, ident, kind) in named {
+            let path = format!("{module}::{ident}");
+            self.define(path.clone(), kind, file, ident);
+            match item {
+                Item::Trait(t) => {
+                    for it in &t.items {
+                        if let TraitItem::Fn(m) = it {
+                            let method = format!("{path}::{}", m.sig.ident);
+                            self.define(method, "trait method", file, &m.sig.ident);
+                        }
+                    }
+                }
+                Item::Mod(m) => {
+                    if let Some((_, items)) = &m.content {
+                        self.collect_defs(items, &path, file, impls);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn add_impl(&mut self, module: &str, file: &Path, imp: &ItemImpl) {
+        let Some(ty_segs) = type_segments(&imp.self_ty) else {
+            return;
+        };
+        let ty_path = self
+            .resolve(module, None, &ty_segs)
+            .map(|p| p.join("::"))
+            .filter(|p| self.defs.contains_key(p))
+            .unwrap_or_else(|| format!("{module}::{}", ty_segs.join("::")));
+        for it in &imp.items {
+            if let ImplItem::Fn(m) = it {
+                let path = format!("{ty_path}::{}", m.sig.ident);
+                self.define(path, "method", file, &m.sig.ident);
+            }
+        }
+        if let Some((_, trait_path, _)) = &imp.trait_ {
+            let segs: Vec<String> = trait_path
+                .segments
+                .iter()
+                .map(|s| s.ident.to_string())
+                .collect();
+            if let Some(full) = self.resolve(module, None, &segs) {
+                let line = trait_path
+                    .segments
+                    .last()
+                    .map_or(0, |s| s.ident.span().start().line);
+                self.impls
+                    .entry(full.join("::"))
+                    .or_default()
+                    .push(ImplSite {
+                        self_ty: ty_path,
+                        loc: Location {
+                            file: file.to_path_buf(),
+                            line,
+                        },
+                    });
+            }
+        }
+    }
+
+    /// Expand `segs` as written in `module` to a full path. Only the first segment
+    /// is looked up; the result is not checked against [`Self::defs`].
+    fn resolve(&self, module: &str, self_ty: Option<&str>, segs: &[String]) -> Option<Vec<String>> {
+        self.resolve_depth(module, self_ty, segs, 0)
+    }
+
+    fn resolve_depth(
+        &self,
+        module: &str,
+        self_ty: Option<&str>,
+        segs: &[String],
+        depth: u8,
+    ) -> Option<Vec<String>> {
+        if depth > 8 {
+            return None;
+        }
+        let (first, rest) = segs.split_first()?;
+        let module_segs = || module.split("::").map(String::from).collect::<Vec<_>>();
+        let mut full = match first.as_str() {
+            "crate" => vec![module.split("::").next()?.to_string()],
+            "self" => module_segs(),
+            "Self" => self_ty?.split("::").map(String::from).collect(),
+            "super" => {
+                let mut base = module_segs();
+                base.pop();
+                let mut rest = rest;
+                while rest.first().is_some_and(|s| s == "super") {
+                    base.pop();
+                    rest = &rest[1..];
+                }
+                base.extend(rest.iter().cloned());
+                return (!base.is_empty()).then_some(base);
+            }
+            name => {
+                let scope = self.scopes.get(module)?;
+                if scope.items.contains(name) {
+                    let mut p = module_segs();
+                    p.push(name.to_string());
+                    p
+                } else if let Some(target) = scope.aliases.get(name) {
+                    self.resolve_depth(module, self_ty, target, depth + 1)?
+                } else if self.crates.contains(name) {
+                    vec![name.to_string()]
+                } else {
+                    scope.globs.iter().find_map(|glob| {
+                        let mut candidate = glob.clone();
+                        candidate.push(name.to_string());
+                        self.resolve_depth(module, self_ty, &candidate, depth + 1)
+                            .filter(|p| self.defs.contains_key(&p.join("::")))
+                    })?
+                }
+            }
+        };
+        full.extend(rest.iter().cloned());
+        Some(full)
+    }
+
+    /// Follow re-exports (`pub use inner::Item;`, `pub use inner::*;`) until `full`
+    /// names a definition or no module along the way re-exports the next segment.
+    fn canonical(&self, mut full: Vec<String>) -> Vec<String> {
+        for _ in 0..8 {
+            if self.defs.contains_key(&full.join("::")) {
+                break;
+            }
+            let reexport = (1..full.len()).rev().find_map(|i| {
+                let module = full[..i].join("::");
+                let scope = self.scopes.get(&module)?;
+                let mut resolved = match scope.aliases.get(&full[i]) {
+                    Some(target) => self.resolve(&module, None, target)?,
+                    None => scope.globs.iter().find_map(|glob| {
+                        let mut candidate = glob.clone();
+                        candidate.push(full[i].clone());
+                        self.resolve(&module, None, &candidate)
+                            .filter(|p| self.defs.contains_key(&p.join("::")))
+                    })?,
+                };
+                resolved.extend(full[i + 1..].iter().cloned());
+                Some(resolved)
+            });
+            match reexport {
+                Some(next) if next != full => full = next,
+                _ => break,
+            }
+        }
+        full
+    }
+
+    /// References to `path`, plus by-name method calls when it is a method.
+    pub fn references_to(&self, path: &str) -> Vec<&Reference> {
+        let mut out: Vec<&Reference> = self.refs.get(path).into_iter().flatten().collect();
+        if matches!(self.defs.get(path), Some(d) if d.kind.ends_with("method")) {
+            let name = path.rsplit("::").next().unwrap_or(path);
+            out.extend(self.method_calls.get(name).into_iter().flatten());
+        }
+        out.sort_by(|a, b| a.loc.cmp(&b.loc));
+        out
+    }
+
+    /// Other files that use or implement items defined in `file`: the files a
+    /// refactor of `file` may have to update.
+    pub fn related_files(&self, file: &Path) -> BTreeSet<PathBuf> {
+        let mut out = BTreeSet::new();
+        for path in self.defs.iter().filter(|(_, d)| d.loc.file == file).map(|(p, _)| p) {
+            let refs = self.references_to(path).into_iter().map(|r| &r.loc);
+            let impls = self.impls.get(path).into_iter().flatten().map(|s| &s.loc);
+            out.extend(refs.chain(impls).map(|loc| loc.file.clone()));
+        }
+        out.remove(file);
+        out
+    }
+
+    /// Callers, users and implementors of the items defined in `file`, for the agents.
+    pub fn render_for(&self, file: &Path, max_chars: usize) -> String {
+        let mut out = format!("References to items defined in {}:\n", file.display());
+        let mut omitted = 0;
+        for (path, def) in self.defs.iter().filter(|(_, d)| d.loc.file == file) {
+            let mut entry = format!("- {} `{path}` (line {})\n", def.kind, def.loc.line);
+            let refs = self.references_to(path);
+            let impls = self.impls.get(path).map(Vec::as_slice).unwrap_or_default();
+            if refs.is_empty() && impls.is_empty() {
+                continue;
+            }
+            for r in refs {
+                entry.push_str(&format!("    {}\n", describe(r)));
+            }
+            for site in impls {
+                entry.push_str(&format!(
+                    "    implemented for `{}` at {}:{}\n",
+                    site.self_ty,
+                    site.loc.file.display(),
+                    site.loc.line
+                ));
+            }
+            if out.len() + entry.len() > max_chars {
+                omitted += 1;
+                continue;
+            }
+            out.push_str(&entry);
+        }
+        if omitted > 0 {
+            out.push_str(&format!("… {omitted} more item(s) omitted\n"));
+        }
+        out
+    }
+
+    /// References in `after` (the index of the edited tree) that still point at
+    /// items this index has but `after` no longer defines, i.e. call sites and
+    /// imports a rename, move or inline forgot to update.
+    pub fn dangling_references(&self, after: &SymbolIndex) -> Vec<String> {
+        let mut out = vec![];
+        for (path, def) in &self.defs {
+            if after.defs.contains_key(path) {
+                continue;
+            }
+            // Paths that reached the item through a glob re-export only resolve here.
+            let stale = after.unresolved.iter().filter(|(p, _)| {
+                let segs = p.split("::").map(String::from).collect();
+                *p == path || self.canonical(segs).join("::") == *path
+            });
+            for r in stale.flat_map(|(_, refs)| refs) {
+                out.push(format!("{} still refers to removed `{path}`", describe(r)));
+            }
+            if def.kind == "method" {
+                let name = path.rsplit("::").next().unwrap_or(path);
+                let still_defined = after.defs.iter().any(|(p, d)| {
+                    d.kind.ends_with("method") && p.rsplit("::").next() == Some(name)
+                });
+                if !still_defined {
+                    for r in after.method_calls.get(name).into_iter().flatten() {
+                        out.push(format!("{} still calls removed `{path}`", describe(r)));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn describe(r: &Reference) -> String {
+    let what = match r.kind {
+        RefKind::Import => "use",
+        RefKind::Path => "ref",
+        RefKind::MethodByName => "method call (by name)",
+    };
+    let from = r
+        .from
+        .as_deref()
+        .map(|f| format!(" in `{f}`"))
+        .unwrap_or_default();
+    format!("{}:{} {what}{from}", r.loc.file.display(), r.loc.line)
+}
+
+/// The package directory and crate name (`-` → `_`) owning `path`.
+fn owning_crate(
+    repo: &Path,
+    path: &Path,
+    cache: &mut BTreeMap<PathBuf, Option<String>>,
+) -> Option<(PathBuf, String)> {
+    for dir in path.ancestors().skip(1) {
+        let name = cache
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| package_name(&dir.join("Cargo.toml")))
+            .clone();
+        if let Some(name) = name {
+            return Some((dir.to_path_buf(), name));
+        }
+        if dir == repo {
+            break;
+        }
+    }
+    None
+}
+
+pub fn package_name(manifest: &Path) -> Option<String> {
+    let table: toml::Table = fs::read_to_string(manifest).ok()?.parse().ok()?;
+    let name = table.get("package")?.get("name")?.as_str()?;
+    Some(name.replace('-', "_"))
+}
+
+/// `src/lib.rs` → `name`, `src/a/mod.rs` → `name::a`, `tests/it.rs` → `name::tests::it`.
+fn module_path(crate_name: &str, rel: &Path) -> String {
+    let rel = rel.to_string_lossy().replace('\\', "/");
+    let rel = rel.trim_end_matches(".rs");
+    let rel = rel.strip_prefix("src/").unwrap_or(rel);
+    let rel = rel.strip_suffix("/mod").unwrap_or(rel);
+    if matches!(rel, "lib" | "main") {
+        return crate_name.to_string();
+    }
+    format!("{crate_name}::{}", rel.replace('/', "::"))
+}
+
+fn add_use(scope: &mut Scope, tree: &UseTree, mut prefix: Vec<String>) {
+    match tree {
+        UseTree::Path(p) => {
+            prefix.push(p.ident.to_string());
+            add_use(scope, &p.tree, prefix);
+        }
+        UseTree::Name(n) if n.ident == "self" => {
+            if let Some(last) = prefix.last().cloned() {
+                scope.aliases.insert(last, prefix);
+            }
+        }
+        UseTree::Name(n) => {
+            prefix.push(n.ident.to_string());
+            scope.aliases.insert(n.ident.to_string(), prefix);
+        }
+        UseTree::Rename(r) => {
+            prefix.push(r.ident.to_string());
+            scope.aliases.insert(r.rename.to_string(), prefix);
+        }
+        UseTree::Glob(_) => scope.globs.push(prefix),
+        UseTree::Group(g) => {
+            for t in &g.items {
+                add_use(scope, t, prefix.clone());
+            }
+        }
+    }
+}
+
+fn type_segments(ty: &Type) -> Option<Vec<String>> {
+    match ty {
+        Type::Path(p) => Some(
+            p.path
+                .segments
+                .iter()
+                .map(|s| s.ident.to_string())
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Found {
+    refs: BTreeMap<String, Vec<Reference>>,
+    method_calls: BTreeMap<String, Vec<Reference>>,
+    unresolved: BTreeMap<String, Vec<Reference>>,
+}
+
+struct RefCollector<'a> {
+    index: &'a SymbolIndex,
+    found: &'a mut Found,
+    file: &'a Path,
+    module: String,
+    self_ty: Option<String>,
+    current_fn: Option<String>,
+}
+
+impl RefCollector<'_> {
+    fn reference(&self, line: usize, kind: RefKind) -> Reference {
+        Reference {
+            loc: Location {
+                file: self.file.to_path_buf(),
+                line,
+            },
+            kind,
+            from: self.current_fn.clone(),
+        }
+    }
+
+    fn record(&mut self, segs: &[String], line: usize, kind: RefKind) {
+        let index = self.index;
+        let Some(full) = index.resolve(&self.module, self.self_ty.as_deref(), segs) else {
+            return;
+        };
+        let full = index.canonical(full);
+        if !full.first().is_some_and(|c| index.crates.contains(c)) {
+            return;
+        }
+        let r = self.reference(line, kind);
+        // Longest prefix naming an item: `Enum::Variant` and `Type::CONST` count as uses of the type.
+        let hit = (2..=full.len())
+            .rev()
+            .map(|k| full[..k].join("::"))
+            .find_map(|p| index.defs.get(&p).map(|d| (p, d)));
+        let exact = matches!(&hit, Some((p, _)) if p.matches("::").count() + 1 == full.len());
+        if !exact {
+            self.found
+                .unresolved
+                .entry(full.join("::"))
+                .or_default()
+                .push(r.clone());
+        }
+        if let Some((path, def)) = hit {
+            if exact || def.kind != "mod" {
+                self.found.refs.entry(path).or_default().push(r);
+            }
+        }
+    }
+
+    fn record_use(&mut self, tree: &UseTree, mut prefix: Vec<String>) {
+        match tree {
+            UseTree::Path(p) => {
+                prefix.push(p.ident.to_string());
+                self.record_use(&p.tree, prefix);
+            }
+            UseTree::Name(n) => {
+                if n.ident != "self" {
+                    prefix.push(n.ident.to_string());
+                }
+                self.record(&prefix, n.ident.span().start().line, RefKind::Import);
+            }
+            UseTree::Rename(r) => {
+                prefix.push(r.ident.to_string());
+                self.record(&prefix, r.ident.span().start().line, RefKind::Import);
+            }
+            UseTree::Glob(_) => {}
+            UseTree::Group(g) => {
+                for t in &g.items {
+                    self.record_use(t, prefix.clone());
+                }
+            }
+        }
+    }
+
+    fn in_fn<F: FnOnce(&mut Self)>(&mut self, path: String, f: F) {
+        let prev = self.current_fn.replace(path);
+        f(self);
+        self.current_fn = prev;
+    }
+}
+
+impl<'ast> Visit<'ast> for RefCollector<'_> {
+    fn visit_item_mod(&mut self, m: &'ast syn::ItemMod) {
+        if m.content.is_none() {
+            return;
+        }
+        let prev = self.module.clone();
+        self.module = format!("{prev}::{}", m.ident);
+        syn::visit::visit_item_mod(self, m);
+        self.module = prev;
+    }
+
+    fn visit_item_impl(&mut self, imp: &'ast ItemImpl) {
+        let ty = type_segments(&imp.self_ty).map(|segs| {
+            self.index
+                .resolve(&self.module, None, &segs)
+                .map(|p| p.join("::"))
+                .unwrap_or_else(|| format!("{}::{}", self.module, segs.join("::")))
+        });
+        let prev = std::mem::replace(&mut self.self_ty, ty);
+        syn::visit::visit_item_impl(self, imp);
+        self.self_ty = prev;
+    }
+
+    fn visit_item_fn(&mut self, f: &'ast syn::ItemFn) {
+        let path = format!("{}::{}", self.module, f.sig.ident);
+        self.in_fn(path, |c| syn::visit::visit_item_fn(c, f));
+    }
+
+    fn visit_impl_item_fn(&mut self, f: &'ast syn::ImplItemFn) {
+        let owner = self.self_ty.clone().unwrap_or_else(|| self.module.clone());
+        let path = format!("{owner}::{}", f.sig.ident);
+        self.in_fn(path, |c| syn::visit::visit_impl_item_fn(c, f));
+    }
+
+    fn visit_item_use(&mut self, u: &'ast syn::ItemUse) {
+        self.record_use(&u.tree, vec![]);
+    }
+
+    fn visit_path(&mut self, p: &'ast syn::Path) {
+        let segs: Vec<String> = p.segments.iter().map(|s| s.ident.to_string()).collect();
+        let line = p.segments.last().map_or(0, |s| s.ident.span().start().line);
+        self.record(&segs, line, RefKind::Path);
+        syn::visit::visit_path(self, p);
+    }
+
+    fn visit_expr_method_call(&mut self, m: &'ast syn::ExprMethodCall) {
+        let r = self.reference(m.method.span().start().line, RefKind::MethodByName);
+        self.found
+            .method_calls
+            .entry(m.method.to_string())
+            .or_default()
+            .push(r);
+        syn::visit::visit_expr_method_call(self, m);
+    }
+
+    /// Expression-like macro arguments (`println!`, `vec!`, `assert_eq!`, …) are indexed too.
+    fn visit_macro(&mut self, m: &'ast syn::Macro) {
+        syn::visit::visit_macro(self, m);
+        let parser = Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated;
+        if let Ok(args) = m.parse_body_with(parser) {
+            for expr in &args {
+                self.visit_expr(expr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn crate_with(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("src")).unwrap();
+        fs::write(
+            tmp.path().join("Cargo.toml"),
+            "[package]\nname = \"demo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        for (path, text) in files {
+            fs::write(tmp.path().join(path), text).unwrap();
+        }
+        tmp
+    }
+
+    const LIB: &str = "\
+pub mod shapes;
+mod user;
+pub mod prelude {
+    pub use crate::shapes::area as compute_area;
+}
+pub use shapes::*;
+";
+
+    const SHAPES: &str = "\
+pub struct Square(pub u32);
+
+pub fn area(s: &Square) -> u32 {
+    s.0 * s.0
+}
+";
+
+    const USER: &str = "\
+use crate::prelude::compute_area;
+use crate::Square;
+
+pub fn run() -> u32 {
+    let big = compute_area(&Square(2));
+    big + crate::area(&Square(1))
+}
+";
+
+    #[test]
+    fn resolves_aliases_globs_and_reexports() {
+        let tmp = crate_with(&[
+            ("src/lib.rs", LIB),
+            ("src/shapes.rs", SHAPES),
+            ("src/user.rs", USER),
+        ]);
+        let index = SymbolIndex::build(tmp.path()).unwrap();
+        let refs: Vec<String> = index
+            .references_to("demo::shapes::area")
+            .into_iter()
+            .map(describe)
+            .collect();
+        assert_eq!(
+            refs,
+            vec![
+                "src/lib.rs:4 use",
+                "src/user.rs:1 use",
+                "src/user.rs:5 ref in `demo::user::run`",
+                // `crate::area` through `pub use shapes::*`.
+                "src/user.rs:6 ref in `demo::user::run`",
+            ]
+        );
+        let square: Vec<String> = index
+            .references_to("demo::shapes::Square")
+            .into_iter()
+            .map(describe)
+            .collect();
+        assert!(square.contains(&"src/user.rs:2 use".to_string()), "{square:?}");
+        assert_eq!(
+            index.related_files(Path::new("src/shapes.rs")),
+            BTreeSet::from([PathBuf::from("src/lib.rs"), PathBuf::from("src/user.rs")])
+        );
+    }
+
+    #[test]
+    fn flags_references_to_a_renamed_item() {
+        let before = crate_with(&[
+            ("src/lib.rs", LIB),
+            ("src/shapes.rs", SHAPES),
+            ("src/user.rs", USER),
+        ]);
+        let renamed = SHAPES.replace("fn area", "fn surface");
+        let after = crate_with(&[
+            ("src/lib.rs", &LIB.replace("area as", "surface as")),
+            ("src/shapes.rs", &renamed),
+            ("src/user.rs", USER),
+        ]);
+        let before = SymbolIndex::build(before.path()).unwrap();
+        let after = SymbolIndex::build(after.path()).unwrap();
+        assert_eq!(
+            before.dangling_references(&after),
+            vec!["src/user.rs:6 ref in `demo::user::run` still refers to removed `demo::shapes::area`"]
+        );
+    }
+}
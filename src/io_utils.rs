@@ -9,8 +9,7 @@ pub fn now_slug() -> String {
     let t = OffsetDateTime::now_utc();
     t.format(&time::format_description::well_known::Rfc3339)
         .unwrap()
-        .replace(':', "*")
-        .replace('+', "*")
+        .replace([':', '+'], "*")
         .replace('-', "_")
 }
 
@@ -29,3 +28,24 @@ pub fn temp_rs_path(original: &Path) -> Result<PathBuf> {
     let tmp = PathBuf::from(format!("/tmp/{}_{}", ts, filename));
     Ok(tmp)
 }
+
+/// Every `.rs` file and `Cargo.toml` under `root`, skipping `target/` and hidden directories.
+pub fn rust_sources(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut out = vec![];
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if path.is_dir() {
+                if name != "target" && !name.starts_with('.') {
+                    stack.push(path);
+                }
+            } else if name.ends_with(".rs") || name == "Cargo.toml" {
+                out.push(path);
+            }
+        }
+    }
+    out.sort();
+    Ok(out)
+}
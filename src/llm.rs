@@ -0,0 +1,185 @@
+use crate::config::{AgentSettings, ApiConfig};
+use anyhow::{bail, Context, Result};
+use async_openai::{
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+        ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessage,
+        ChatCompletionRequestUserMessageContent, CreateChatCompletionRequestArgs, ResponseFormat,
+        ResponseFormatJsonSchema,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Which agent is talking to the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentRole {
+    Developer,
+    Reviewer,
+    Repair,
+    #[serde(rename = "test_writer")]
+    TestWriter,
+}
+
+impl fmt::Display for AgentRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AgentRole::Developer => "developer",
+            AgentRole::Reviewer => "reviewer",
+            AgentRole::Repair => "repair",
+            AgentRole::TestWriter => "test_writer",
+        };
+        f.write_str(s)
+    }
+}
+
+/// JSON schema an answer must follow, sent as `response_format` by backends
+/// that support structured outputs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponseSchema {
+    pub name: String,
+    pub schema: serde_json::Value,
+}
+
+/// A single system + user exchange, as every agent issues it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatRequest {
+    pub role: AgentRole,
+    #[serde(flatten)]
+    pub settings: AgentSettings,
+    pub system: String,
+    pub user: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<ResponseSchema>,
+}
+
+/// Anything that can answer a [`ChatRequest`] with the raw assistant text.
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    async fn complete(&self, req: &ChatRequest) -> Result<String>;
+}
+
+/// Talks to an OpenAI-style chat completions API.
+pub struct OpenAiBackend {
+    client: Client<OpenAIConfig>,
+    structured_output: bool,
+}
+
+impl OpenAiBackend {
+    /// Client for OpenAI itself or any OpenAI-compatible server (Ollama, llama.cpp, vLLM, …).
+    pub fn new(api: &ApiConfig) -> Self {
+        let config = OpenAIConfig::new()
+            .with_api_base(&api.api_base)
+            .with_api_key(&api.api_key);
+        Self {
+            client: Client::with_config(config),
+            structured_output: api.structured_output,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    async fn complete(&self, req: &ChatRequest) -> Result<String> {
+        let settings = &req.settings;
+        let mut args = CreateChatCompletionRequestArgs::default();
+        args.model(&settings.model)
+            .messages([
+                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                    content: ChatCompletionRequestSystemMessageContent::Text(req.system.clone()),
+                    name: None,
+                }),
+                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: ChatCompletionRequestUserMessageContent::Text(req.user.clone()),
+                    name: None,
+                }),
+            ])
+            .temperature(settings.temperature as f32);
+        if let Some(top_p) = settings.top_p {
+            args.top_p(top_p as f32);
+        }
+        if let Some(max_tokens) = settings.max_tokens {
+            args.max_completion_tokens(max_tokens);
+        }
+        if let Some(seed) = settings.seed {
+            args.seed(seed);
+        }
+        if let Some(schema) = req.schema.as_ref().filter(|_| self.structured_output) {
+            args.response_format(ResponseFormat::JsonSchema {
+                json_schema: ResponseFormatJsonSchema {
+                    description: None,
+                    name: schema.name.clone(),
+                    schema: Some(schema.schema.clone()),
+                    strict: Some(true),
+                },
+            });
+        }
+        let request = args.build()?;
+
+        let resp = self.client.chat().create(request).await?;
+        let content = resp
+            .choices
+            .first()
+            .and_then(|c| c.message.content.clone())
+            .unwrap_or_default();
+        Ok(content)
+    }
+}
+
+/// Offline backend serving canned responses from a directory.
+///
+/// The n-th call (1-based) made by an agent is answered with
+/// `<dir>/<role>_<n>.md`, falling back to `<dir>/<role>.md` when no numbered
+/// file exists, e.g. `developer_1.md`, `reviewer.md`, `repair_2.md`.
+pub struct MockBackend {
+    dir: PathBuf,
+    calls: Mutex<HashMap<AgentRole, usize>>,
+}
+
+impl MockBackend {
+    pub fn new(dir: &Path) -> Result<Self> {
+        if !dir.is_dir() {
+            bail!("mock response directory {} does not exist", dir.display());
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            calls: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl LlmBackend for MockBackend {
+    async fn complete(&self, req: &ChatRequest) -> Result<String> {
+        let n = {
+            let mut calls = self.calls.lock().unwrap();
+            let n = calls.entry(req.role).or_insert(0);
+            *n += 1;
+            *n
+        };
+        let numbered = self.dir.join(format!("{}_{}.md", req.role, n));
+        let fallback = self.dir.join(format!("{}.md", req.role));
+        let path = if numbered.exists() {
+            numbered
+        } else {
+            fallback
+        };
+        fs::read_to_string(&path).with_context(|| {
+            format!(
+                "mock: no response for {} call #{} ({})",
+                req.role,
+                n,
+                path.display()
+            )
+        })
+    }
+}
@@ -1,95 +1,429 @@
+mod analysis;
+mod api;
+mod ast;
+mod autofix;
+mod candidates;
+mod characterize;
+mod cassette;
 mod config;
+mod differential;
+mod edits;
 mod exec;
+mod fence;
+mod hunks;
+mod index;
 mod io_utils;
+mod llm;
+mod patch;
 mod prompts;
 mod rag;
+mod report;
+mod sandbox;
+mod txn;
 mod verification;
 mod agents {
     pub mod developer;
     pub mod repair;
     pub mod reviewer;
-    pub use developer::extract_rust_block;
+    pub mod test_writer;
 }
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use clap::Parser;
 use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::{
-    agents::{developer::DeveloperAgent, repair::RepairAgent, reviewer::ReviewerAgent},
-    config::{Cli, RefactorKind},
+    agents::{
+        developer::DeveloperAgent,
+        repair::{RepairAgent, RepairAttempt, RepairMemory},
+        reviewer::{ReviewerAgent, Verdict},
+        test_writer::TestWriterAgent,
+    },
+    api::{ApiChange, ApiSurface},
+    autofix::{changed_regions, clippy_fix_scoped, format_scoped, machine_fixes, Regions},
+    candidates::{sampling, score, CandidateScore},
+    characterize::CharacterizationTests,
+    cassette::{RecordingBackend, ReplayBackend},
+    config::{
+        Cli, Command, Config, ConfigAction, LlmProvider, OnRepairFailure, PathsConfig,
+        VerificationConfig,
+    },
+    differential::DifferentialCheck,
+    edits::{Base, EditSet, InvalidEdits, Workspace},
     exec::run_cmd,
+    fence::ExtractError,
+    hunks::HunkFailures,
+    index::SymbolIndex,
     io_utils::{read_to_string, temp_rs_path, write_string},
+    llm::{LlmBackend, MockBackend, OpenAiBackend},
     rag::load_few_shot,
-    verification::{analyze_repo, cargo_check, cargo_test, clippy_check, fmt_check},
+    report::RunReport,
+    sandbox::{Sandbox, SandboxMode},
+    txn::{report_rollback, RollbackOnDrop, Transaction},
+    analysis::summarize_crate,
+    verification::{clippy_warnings, fmt_check, BuildEvidence, Diagnostic},
 };
 
 #[derive(Debug)]
 enum State {
+    /// Have the TestWriter pin the original behavior before the first candidate.
+    Characterize,
     Develop,
     UserVerify,
     Review,
-    BuildAndTest,
     RepairLoop(u32),
+    /// The Repair loop failed for the given reason; keep the original or the best attempt.
+    GiveUp(String),
     Done,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Logs go to stderr so stdout stays clean for `config show` and `--dry-run` patches.
     tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
         .with_env_filter(
             EnvFilter::from_default_env().add_directive("mantra_rs=info".parse().unwrap()),
         )
         .init();
 
     let cli = Cli::parse();
-    cli.validate()?;
 
-    let repo = cli.repo.canonicalize()?;
-    let file = cli.file.canonicalize()?;
-    let model = cli.model.clone();
+    if let Some(Command::Config {
+        action: ConfigAction::Show,
+    }) = &cli.command
+    {
+        let repo = cli.repo.clone().unwrap_or_else(|| PathBuf::from("."));
+        let config = Config::load(&cli, &repo)?;
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    let config = Config::load(&cli, cli.repo.as_deref().unwrap_or(Path::new(".")))?;
+    cli.validate(&config)?;
+    for src in &config.sources {
+        info!("Loaded config from {}", src.display());
+    }
+
+    let mut llm: Box<dyn LlmBackend> = match (&cli.replay, config.llm.provider) {
+        (Some(dir), _) => Box::new(ReplayBackend::new(dir)?),
+        (None, LlmProvider::Mock) => {
+            Box::new(MockBackend::new(cli.mock_dir.as_deref().unwrap())?)
+        }
+        (None, provider) => {
+            let api = config.llm.api_config()?;
+            info!("LLM endpoint: {:?} at {}", provider, api.api_base);
+            Box::new(OpenAiBackend::new(&api))
+        }
+    };
+    if let Some(dir) = &cli.record {
+        llm = Box::new(RecordingBackend::new(llm, dir)?);
+    }
+
+    let txn = Transaction::new();
+    let _guard = RollbackOnDrop(txn.clone());
+    let mut report = RunReport::default();
+    let result = tokio::select! {
+        res = run(&cli, &config, llm.as_ref(), &txn, &mut report) => res,
+        sig = shutdown_signal() => Err(anyhow!("interrupted by {sig}")),
+    };
+
+    report.log_summary();
+    if let Some(path) = &cli.report {
+        report.write_json(path)?;
+    }
 
-    for tool in ["rust-code-analysis-cli", "cargo", "rustfmt"] {
+    match result {
+        Ok(()) => {
+            txn.commit();
+            Ok(())
+        }
+        Err(e) => {
+            error!("Pipeline failed: {e:#}");
+            if cli.keep_failed {
+                for path in txn.abandon() {
+                    warn!("--keep-failed: leaving modified {}", path.display());
+                }
+            } else {
+                report_rollback(&txn.rollback()?);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Resolves when the user asks us to stop (Ctrl-C, or SIGTERM on Unix).
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            return tokio::select! {
+                _ = tokio::signal::ctrl_c() => "Ctrl-C",
+                _ = term.recv() => "SIGTERM",
+            };
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
+
+/// Drive the Develop → Review (build, test, lint, review) → Repair state machine.
+/// Every write to the user's tree goes through `txn` so it can be undone.
+async fn run(
+    cli: &Cli,
+    config: &Config,
+    llm: &dyn LlmBackend,
+    txn: &Transaction,
+    report: &mut RunReport,
+) -> Result<()> {
+    let repo = cli.repo.as_deref().context("--repo is required")?.canonicalize()?;
+    let file = cli.file.as_deref().context("--file is required")?.canonicalize()?;
+    let refactor_prompt = cli.refactor_prompt.as_deref().unwrap_or_default();
+    let refactor_type = cli.refactor_type.clone().context("--refactor-type is required")?;
+    // Repo-relative so prompts (and recorded cassettes) don't depend on the checkout location.
+    let rel_file = file.strip_prefix(&repo).unwrap_or(&file).to_path_buf();
+    ensure!(
+        config.paths.is_allowed(&rel_file),
+        "{} is outside the allowed paths {:?}",
+        rel_file.display(),
+        config.paths.allowed
+    );
+    let profiles = &config.agents;
+    let verification = &config.verification;
+    info!("Developer settings: {}", profiles.developer);
+    info!("Reviewer settings: {}", profiles.reviewer);
+    info!("Repair settings: {}", profiles.repair);
+    if verification.characterize {
+        info!("TestWriter settings: {}", profiles.test_writer);
+    }
+    report.agents = Some(profiles.clone());
+
+    for tool in ["cargo", "rustfmt"] {
         if which::which(tool).is_err() {
             warn!("Tool `{}` not found on PATH; some checks may fail", tool);
         }
     }
 
+    let mode = match config.sandbox.mode {
+        SandboxMode::None if cli.is_dry_run() => {
+            info!("--dry-run never edits the checkout; using a sandbox despite `--sandbox none`");
+            SandboxMode::Auto
+        }
+        mode => mode,
+    };
+    let mut sandbox = Sandbox::create(&repo, mode, cli.keep_failed).await?;
+    let origin_file = file;
+    let origin_repo = repo;
+    let repo = sandbox.root().to_path_buf();
+    let file = sandbox.map(&origin_file);
+
     let original = read_to_string(&file)?;
-    let analysis = analyze_repo(&repo, &file)
-        .await
-        .unwrap_or_else(|e| format!("analysis failed: {e}"));
+    let max_chars = config.analysis.max_chars;
+    let mut analysis =
+        summarize_crate(&repo, &file, max_chars).unwrap_or_else(|e| format!("analysis failed: {e}"));
+    let index = SymbolIndex::build(&repo).unwrap_or_else(|e| {
+        warn!("Could not index {}: {e:#}", repo.display());
+        SymbolIndex::default()
+    });
+    analysis.push('\n');
+    analysis.push_str(&index.render_for(&rel_file, max_chars));
+    let related = related_sources(&repo, &index, &rel_file, max_chars);
+    // Public API of the library crates before the refactor.
+    let api = ApiSurface::extract(&repo)
+        .map_err(|e| warn!("Could not read the public API; not checking it: {e:#}"))
+        .ok();
 
-    let few_shot = load_few_shot(&cli.rag_dir).unwrap_or_default();
+    let few_shot = load_few_shot(&config.rag.dir, config.rag.max_examples).unwrap_or_default();
 
-    let dev = DeveloperAgent::new(&model);
-    let reviewer = ReviewerAgent::new(&model);
-    let repair = RepairAgent::new(&model);
+    let prompts = &config.prompts;
+    let dev = DeveloperAgent::new(llm, &profiles.developer, prompts.developer.as_deref());
+    let reviewer = ReviewerAgent::new(
+        llm,
+        &profiles.reviewer,
+        prompts.reviewer.as_deref(),
+        verification.max_edit_retries,
+    );
+    let repair = RepairAgent::new(llm, &profiles.repair, prompts.repair.as_deref());
+    let test_writer =
+        TestWriterAgent::new(llm, &profiles.test_writer, prompts.test_writer.as_deref());
 
-    let mut state = State::Develop;
-    let mut candidate_path = temp_rs_path(&file)?;
-    let mut candidate_content = String::new();
-    let mut last_logs = String::new();
-    let mut repairs = 0u32;
+    let mut state = if verification.characterize {
+        State::Characterize
+    } else {
+        State::Develop
+    };
+    let candidate_path = temp_rs_path(&file)?;
+    // Every file the candidate creates, modifies or deletes, relative to the sandbox root.
+    let mut workspace = Workspace::new(&repo, &[&rel_file]);
+    for (path, _) in &related {
+        workspace.allow(path);
+    }
+    let mut candidate = EditSet::default();
+    // The refactor request plus the latest reviewer guidance.
+    let mut dev_prompt = refactor_prompt.to_string();
+    let mut last_build = BuildEvidence::default();
+    let mut revisions = 0;
+    let mut memory = RepairMemory::default();
+    // Tests that passed on the original; they run with every build of a candidate.
+    let mut characterization: Option<CharacterizationTests> = None;
+    let differential = verification
+        .differential
+        .then(|| DifferentialCheck::new(&refactor_type, &rel_file, &original))
+        .flatten();
+    if verification.differential && differential.is_none() {
+        warn!(
+            "--differential applies to extract and inline refactors of a parsable file; not checking"
+        );
+    }
+    // Clippy findings on the original, so only warnings the candidate adds count.
+    let lint_baseline = clippy_warnings(&repo, verification).await.unwrap_or_else(|e| {
+        warn!("Could not lint the original: {e:#}");
+        vec![]
+    });
 
     loop {
         match state {
+            State::Characterize => {
+                info!("TestWriterAgent: writing characterization tests");
+                state = State::Develop;
+                let mut feedback = String::new();
+                let mut retries = 0;
+                loop {
+                    let why = match test_writer
+                        .write(&rel_file, &original, &analysis, refactor_prompt, &feedback)
+                        .await
+                    {
+                        Ok(code) => {
+                            let tests = CharacterizationTests::new(&rel_file, code);
+                            let evidence = collect_evidence(
+                                &repo,
+                                verification,
+                                &[&rel_file],
+                                None,
+                                Some(&tests),
+                                None,
+                                &sandbox,
+                                txn,
+                            )
+                            .await?;
+                            if tests.count > 0 && evidence.passed() {
+                                info!(
+                                    "{} characterization test(s) pass on the original ✅",
+                                    tests.count
+                                );
+                                report.characterization = Some(tests.clone());
+                                characterization = Some(tests);
+                                break;
+                            }
+                            let why = if tests.count == 0 {
+                                "the answer contains no #[test] function".to_string()
+                            } else {
+                                "they do not pass on the unchanged code; fix or drop the failing ones"
+                                    .to_string()
+                            };
+                            format!(
+                                "Your previous tests:\n\n```rust\n{}\n```\n\n{why}:\n\n````\n{}````",
+                                tests.code.trim_end(),
+                                evidence.render()
+                            )
+                        }
+                        Err(e) => match unusable_answer(&e) {
+                            Some(why) => format!("Your previous answer could not be used: {why}"),
+                            None => return Err(e),
+                        },
+                    };
+                    if retries >= verification.max_edit_retries {
+                        warn!(
+                            "No passing characterization tests after {} attempt(s); continuing without them",
+                            retries + 1
+                        );
+                        break;
+                    }
+                    retries += 1;
+                    warn!("No usable characterization tests yet; asking again ({retries})");
+                    feedback = format!("\n### Previous attempt\n\n{why}\n");
+                }
+            }
+            State::Develop if verification.candidates > 1 => {
+                let n = verification.candidates;
+                info!("DeveloperAgent: generating {n} candidates");
+                let settings: Vec<_> = (0..n).map(|i| sampling(&profiles.developer, i)).collect();
+                let runs = settings.iter().zip(1..).map(|(settings, index)| {
+                    let (workspace, dev_prompt) = (&workspace, &dev_prompt);
+                    let (original, analysis, few_shot) = (&original, &analysis, &few_shot);
+                    let related = &related;
+                    let (repo, rel_file, refactor_type) = (&repo, &rel_file, &refactor_type);
+                    async move {
+                        let dev = DeveloperAgent::new(llm, settings, prompts.developer.as_deref());
+                        let edits = match develop(
+                            &dev,
+                            workspace,
+                            rel_file,
+                            original,
+                            related,
+                            analysis,
+                            few_shot,
+                            dev_prompt,
+                            &config.paths,
+                            verification.max_edit_retries,
+                        )
+                        .await
+                        {
+                            Ok(edits) => edits,
+                            Err(e) => {
+                                let why = format!("{e:#}");
+                                return (CandidateScore::failed(index, settings, why), None);
+                            }
+                        };
+                        let kind = refactor_type.clone();
+                        let paths = &config.paths;
+                        match score(index, settings, repo, rel_file, &edits, kind, paths, verification)
+                            .await
+                        {
+                            Ok(s) => (s, Some(edits)),
+                            Err(e) => {
+                                let why = format!("could not be checked: {e:#}");
+                                (CandidateScore::failed(index, settings, why), None)
+                            }
+                        }
+                    }
+                });
+                let mut results = futures::future::join_all(runs).await;
+                results.sort_by_key(|(s, _)| std::cmp::Reverse((s.checked, s.rank())));
+                let mut results = results.into_iter();
+                let Some((best, Some(out))) = results.next() else {
+                    bail!("none of the {n} Developer candidates produced usable edits");
+                };
+                info!("Selected {best}");
+                for (discarded, _) in results {
+                    warn!("Discarded {discarded}");
+                    report.discarded_candidates.push(discarded);
+                }
+                write_string(&candidate_path, out.content_of(&rel_file).unwrap_or(&original))?;
+                candidate = out;
+                state = State::UserVerify;
+            }
             State::Develop => {
                 info!("DeveloperAgent: generating candidate");
-                let out = dev
-                    .generate(
-                        file.to_string_lossy().as_ref(),
-                        &original,
-                        &analysis,
-                        &few_shot,
-                        &cli.refactor_prompt,
-                    )
-                    .await?;
-                write_string(&candidate_path, &out)?;
-                candidate_content = out;
+                let out = develop(
+                    &dev,
+                    &workspace,
+                    &rel_file,
+                    &original,
+                    &related,
+                    &analysis,
+                    &few_shot,
+                    &dev_prompt,
+                    &config.paths,
+                    verification.max_edit_retries,
+                )
+                .await?;
+                write_string(&candidate_path, out.content_of(&rel_file).unwrap_or(&original))?;
+                candidate = out;
                 state = State::UserVerify;
             }
             State::UserVerify => {
@@ -97,21 +431,26 @@ async fn main() -> Result<()> {
                     state = State::Review;
                     continue;
                 }
+                // The live file may already hold an earlier candidate: diff the original.
+                let before = workspace.changes()?.before;
+                let original_path = candidate_path.with_extension("orig.rs");
+                write_string(
+                    &original_path,
+                    before.get(&rel_file).map(String::as_str).unwrap_or(&original),
+                )?;
                 let _ = run_cmd(
                     Path::new("/"),
                     "code",
                     &[
                         "-d",
-                        file.to_str().unwrap(),
+                        original_path.to_str().unwrap(),
                         candidate_path.to_str().unwrap(),
                     ],
                 )
                 .await;
+                eprint!("{}", workspace.preview(&candidate));
                 eprintln!("\nOpen diff above. Apply this change to proceed? [y/N]: ");
-                use std::io::stdin;
-                let mut buf = String::new();
-                stdin().read_line(&mut buf).ok();
-                let ans = buf.trim().to_lowercase();
+                let ans = read_answer().await?;
                 if ans == "y" || ans == "yes" {
                     state = State::Review;
                 } else {
@@ -122,110 +461,332 @@ async fn main() -> Result<()> {
             State::Review => {
                 info!("ReviewerAgent: reviewing candidate");
 
-                // Write candidate to file first so fmt/clippy can check the actual candidate
-                write_string(&file, &candidate_content)?;
+                // fmt/clippy --fix may rewrite any file in the crate; back them all up first.
+                if !sandbox.is_isolated() {
+                    txn.backup_tree(&repo)?;
+                }
 
-                // Run cargo fmt to auto-format the code
-                info!("Running cargo fmt to clean up formatting...");
-                let _ = run_cmd(&repo, "cargo", &["fmt"]).await;
+                // Apply the whole edit set on top of the originals so fmt/clippy check the actual candidate
+                workspace.reset(&sandbox, txn)?;
+                workspace.apply(&candidate, &config.paths, &sandbox, txn)?;
 
-                // Run cargo clippy --fix to auto-fix what it can
-                info!("Running cargo clippy --fix to clean up linting issues...");
-                let _ = run_cmd(&repo, "cargo", &["clippy", "--fix", "--allow-dirty", "--allow-staged"]).await;
+                // Format only the files the refactor touched; collateral edits are reverted.
+                let touched: Vec<PathBuf> = workspace
+                    .touched()
+                    .map(|rel| repo.join(rel))
+                    .filter(|p| p.is_file())
+                    .collect();
+                let scope: Vec<&Path> = touched.iter().map(PathBuf::as_path).collect();
+                info!("Running rustfmt on {} file(s)...", scope.len());
+                report
+                    .reverted
+                    .extend(format_scoped(&repo, &scope, verification).await?);
 
-                // Re-read the file after auto-fixes
-                candidate_content = read_to_string(&file)?;
+                // clippy --fix runs crate-wide, but only fixes inside the refactored lines are kept.
+                let before = workspace.changes()?.before;
+                let mut regions = Regions::new();
+                for path in &touched {
+                    let rel = path.strip_prefix(&repo).unwrap_or(path);
+                    let original = before.get(rel).map(String::as_str).unwrap_or_default();
+                    let formatted = read_to_string(path)?;
+                    regions.insert(path.clone(), changed_regions(original, &formatted));
+                }
+                info!("Running clippy --fix scoped to the refactored lines...");
+                report
+                    .reverted
+                    .extend(clippy_fix_scoped(&repo, &regions, verification).await?);
 
+                // Diff the touched files after auto-fixes
+                let changes = workspace.changes()?;
 
-                let fmt_ok = fmt_check(&repo).await.unwrap_or(false);
-                let clippy_ok = clippy_check(&repo).await.unwrap_or(false);
+                let fmt_ok = fmt_check(&repo, verification, &scope)
+                    .await
+                    .unwrap_or(false);
+
+                // Build, test and lint before the review, so the Reviewer sees the same
+                // evidence the Repair agent would.
+                info!("Building and testing the candidate...");
+                let rel_touched: Vec<&Path> = changes.after.keys().map(PathBuf::as_path).collect();
+                let evidence = collect_evidence(
+                    &repo,
+                    verification,
+                    &rel_touched,
+                    Some(&lint_baseline),
+                    characterization.as_ref(),
+                    differential.as_ref(),
+                    &sandbox,
+                    txn,
+                )
+                .await?;
+                report.diagnostics = evidence.diagnostics.clone();
+                report.divergences = evidence.divergences.clone();
+                report.not_compared = evidence.not_compared.clone();
+                let clippy_ok = evidence.new_lints.as_ref().is_some_and(Vec::is_empty);
+
+                // References elsewhere in the crate that still point at renamed or removed items.
+                let dangling = match SymbolIndex::build(&repo) {
+                    Ok(after) => index.dangling_references(&after),
+                    Err(e) => {
+                        warn!("Could not re-index the candidate: {e:#}");
+                        vec![]
+                    }
+                };
+                let api_changes = api_changes(api.as_ref(), &repo);
+                let api_denied: Vec<String> = if verification.allow_api_change {
+                    vec![]
+                } else {
+                    api_changes.iter().map(ToString::to_string).collect()
+                };
 
                 let verdict = reviewer
                     .review(
-                        cli.refactor_type.clone(),
-                        &original,
-                        &candidate_content,
+                        refactor_type.clone(),
+                        &rel_file,
+                        &changes,
                         &analysis,
                         fmt_ok,
                         clippy_ok,
-                        None,
+                        &dangling,
+                        &api_denied,
+                        &evidence,
                     )
                     .await?;
 
                 info!("Reviewer verdict: {}", verdict.verdict);
                 info!("Reviewer reason: {:?}", verdict.reasons);
-                if verdict.verdict == "revise" {
-                    let mut augmented = cli.refactor_prompt.clone();
+                info!("Reviewer checklist: {:?}", verdict.checklist);
+                report.reviews.push(verdict.clone());
+                let exhausted = revisions >= verification.max_revisions;
+                if verdict.verdict == Verdict::Revise && !exhausted {
+                    revisions += 1;
+                    dev_prompt = refactor_prompt.to_string();
                     if let Some(g) = verdict.patch_guidance {
-                        augmented.push_str("\nReviewer guidance:\n");
-                        augmented.push_str(&g);
+                        dev_prompt.push_str("\nReviewer guidance:\n");
+                        dev_prompt.push_str(&g);
                     }
-                    let out = dev
-                        .generate(
-                            file.to_string_lossy().as_ref(),
-                            &original,
-                            &analysis,
-                            &few_shot,
-                            &augmented,
-                        )
-                        .await?;
-                    write_string(&candidate_path, &out)?;
-                    candidate_content = out;
-                    state = State::UserVerify;
-                } else {
-                    // File already written above, proceed to build and test
-                    state = State::BuildAndTest;
-                }
-            }
-            State::BuildAndTest => {
-                let (ok_check, logs1) = cargo_check(&repo).await?;
-                let (ok_test, logs2) = if ok_check {
-                    cargo_test(&repo).await?
-                } else {
-                    (false, String::new())
-                };
-                last_logs = format!("{}\n{}", logs1, logs2);
-                if ok_check && ok_test {
-                    info!("Build & tests passed ✅");
-                    state = State::Done;
+                    state = State::Develop;
                 } else {
-                    error!("Build/test failed; entering RepairAgent loop");
-                    state = State::RepairLoop(0);
+                    if verdict.verdict == Verdict::Revise {
+                        warn!(
+                            "Reviewer still asks for revisions after {} round(s); keeping the candidate anyway",
+                            revisions
+                        );
+                    }
+                    if evidence.passed() {
+                        info!("Build & tests passed ✅");
+                        state = State::Done;
+                    } else {
+                        error!("Build/test failed; entering RepairAgent loop");
+                        last_build = evidence;
+                        state = State::RepairLoop(0);
+                    }
                 }
             }
             State::RepairLoop(n) => {
-                if n >= cli.max_repairs {
-                    error!(
-                        "Max repair attempts reached. Leaving candidate file at: {}",
-                        file.display()
-                    );
-                    break;
-                }
-                repairs = n + 1;
-                let repaired = repair
-                    .repair(file.to_str().unwrap(), &candidate_content, &last_logs)
+                // Let the compiler fix what it already knows how to before asking the agent.
+                let (fixes, applied) =
+                    machine_fixes(&last_build.diagnostics, &workspace.changes()?.after);
+                if !applied.is_empty() {
+                    for f in &applied {
+                        info!("Compiler fix at {}:{}: {}", f.path.display(), f.line, f.fix);
+                    }
+                    workspace.apply(&fixes, &config.paths, &sandbox, txn)?;
+                    report.applied_fixes.extend(applied);
+                    let after = workspace.changes()?.after;
+                    let rel_touched: Vec<&Path> = after.keys().map(PathBuf::as_path).collect();
+                    last_build = collect_evidence(
+                        &repo,
+                        verification,
+                        &rel_touched,
+                        None,
+                        characterization.as_ref(),
+                        differential.as_ref(),
+                        &sandbox,
+                        txn,
+                    )
                     .await?;
-                write_string(&file, &repaired)?;
-                candidate_content = repaired;
+                    report.diagnostics = last_build.diagnostics.clone();
+                    report.divergences = last_build.divergences.clone();
+                    report.not_compared = last_build.not_compared.clone();
+                    if last_build.passed() {
+                        info!("Compiler suggestions fixed the build ✅");
+                        report.repair_attempts = memory.attempts().to_vec();
+                        state = State::Done;
+                        continue;
+                    }
+                }
 
-                let (ok_check, logs1) = cargo_check(&repo).await?;
-                let (ok_test, logs2) = if ok_check {
-                    cargo_test(&repo).await?
-                } else {
-                    (false, String::new())
+                let repairs = n + 1;
+                let current = workspace.changes()?.after;
+                if n == 0 {
+                    memory.revisit(&current);
+                    let entry = RepairAttempt::new(0, String::new(), &current, &current, &last_build);
+                    memory.record(entry);
+                }
+                if n >= verification.max_repairs {
+                    state = State::GiveUp(format!(
+                        "max repair attempts ({}) reached without a passing build",
+                        verification.max_repairs
+                    ));
+                    continue;
+                }
+                let mut last_logs = last_build.render();
+                if let Some(tests) = &characterization {
+                    last_logs.push('\n');
+                    last_logs.push_str(&tests.explain());
+                }
+                let mut logs = last_logs.clone();
+                let mut retries = 0;
+                let repaired = loop {
+                    let attempt = repair
+                        .repair(&rel_file, &current, &logs, &memory)
+                        .await
+                        .and_then(|mut out| {
+                            workspace
+                                .resolve(&mut out.edits, Base::Current)
+                                .context("RepairAgent: edits do not apply")?;
+                            workspace
+                                .validate(&out.edits, &config.paths, Base::Current)
+                                .context("RepairAgent: edits are not allowed")?;
+                            Ok(out)
+                        });
+                    match attempt {
+                        Ok(out) => break out,
+                        Err(e) => match unusable_answer(&e) {
+                            Some(why) if retries < verification.max_edit_retries => {
+                                retries += 1;
+                                warn!("Repair answer unusable; asking again ({retries}): {why}");
+                                logs = format!(
+                                    "{last_logs}\n\nYour previous answer could not be used: {why}"
+                                );
+                            }
+                            _ => return Err(e),
+                        },
+                    }
                 };
-                last_logs = format!("{}\n{}", logs1, logs2);
+                info!("Repair attempt {}: {}", repairs, repaired.reflection);
+                workspace.apply(&repaired.edits, &config.paths, &sandbox, txn)?;
 
-                if ok_check && ok_test {
+                let after = workspace.changes()?.after;
+                if let Some(earlier) = memory.revisit(&after) {
+                    state = State::GiveUp(format!(
+                        "repair attempt {} reproduces {}; stopping instead of oscillating",
+                        repairs,
+                        match earlier {
+                            0 => "the candidate that entered the repair loop".to_string(),
+                            i => format!("attempt {i}"),
+                        }
+                    ));
+                    continue;
+                }
+
+                let rel_touched: Vec<&Path> = after.keys().map(PathBuf::as_path).collect();
+                let evidence = collect_evidence(
+                    &repo,
+                    verification,
+                    &rel_touched,
+                    None,
+                    characterization.as_ref(),
+                    differential.as_ref(),
+                    &sandbox,
+                    txn,
+                )
+                .await?;
+                report.diagnostics = evidence.diagnostics.clone();
+                report.divergences = evidence.divergences.clone();
+                report.not_compared = evidence.not_compared.clone();
+
+                if evidence.passed() {
                     info!("Repair attempt {} succeeded ✅", repairs);
+                    report.repair_attempts = memory.attempts().to_vec();
                     state = State::Done;
                 } else {
                     warn!("Repair attempt {} failed; retrying…", repairs);
+                    memory.record(RepairAttempt::new(
+                        repairs,
+                        repaired.reflection,
+                        &current,
+                        &after,
+                        &evidence,
+                    ));
+                    last_build = evidence;
                     state = State::RepairLoop(repairs);
                 }
             }
+            State::GiveUp(reason) => {
+                error!("{reason}");
+                report.repair_attempts = memory.attempts().to_vec();
+                let best = memory.best().context("no repair attempt was recorded")?;
+                let name = |number: u32| match number {
+                    0 => "the reviewed candidate".to_string(),
+                    n => format!("attempt {n}"),
+                };
+                for a in memory.attempts() {
+                    let mark = if a.number == best.number { "  <- best" } else { "" };
+                    info!("  {}: {}{mark}", name(a.number), a.score);
+                }
+                let choice = match verification.on_repair_failure {
+                    OnRepairFailure::Ask if !cli.yes => {
+                        eprintln!(
+                            "\nKeep {} ({}) instead of the original files? [y/N]: ",
+                            name(best.number),
+                            best.score
+                        );
+                        let ans = read_answer().await?;
+                        if ans == "y" || ans == "yes" {
+                            OnRepairFailure::Best
+                        } else {
+                            OnRepairFailure::Original
+                        }
+                    }
+                    OnRepairFailure::Ask => OnRepairFailure::Original,
+                    choice => choice,
+                };
+                if choice == OnRepairFailure::Original {
+                    bail!("{reason}; original files kept");
+                }
+
+                workspace.restore(&best.files, &sandbox, txn)?;
+                let kept = format!("{} ({})", name(best.number), best.score);
+                if cli.is_dry_run() {
+                    patch::emit(&workspace.changes()?.diff(), cli.output_patch.as_deref())?;
+                    bail!("{reason}; the patch holds {kept}");
+                }
+                for rel in workspace.touched() {
+                    sandbox.promote(rel, txn)?;
+                }
+                // Keep what was promoted even though the run fails.
+                txn.commit();
+                bail!("{reason}; kept {kept} in {}", origin_repo.display());
+            }
             State::Done => {
-                info!("Refactoring complete. File updated at {}", file.display());
+                let api_changes = api_changes(api.as_ref(), &repo);
+                if !api_changes.is_empty() && !verification.allow_api_change {
+                    let list: Vec<String> = api_changes.iter().map(|c| format!("  {c}")).collect();
+                    bail!(
+                        "The refactor changes the public API; rerun with --allow-api-change to accept:\n{}",
+                        list.join("\n")
+                    );
+                }
+                for change in &api_changes {
+                    info!("Public API change: {change}");
+                }
+                report.api_changes = api_changes;
+                if cli.is_dry_run() {
+                    patch::emit(&workspace.changes()?.diff(), cli.output_patch.as_deref())?;
+                    sandbox.mark_succeeded();
+                    info!("Dry run complete; {} left untouched", origin_repo.display());
+                    break;
+                }
+                for rel in workspace.touched() {
+                    sandbox.promote(rel, txn)?;
+                }
+                sandbox.mark_succeeded();
+                info!(
+                    "Refactoring complete. Updated {} file(s) in {}",
+                    workspace.touched().count(),
+                    origin_repo.display()
+                );
                 break;
             }
         }
@@ -233,3 +794,289 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// How the public API under `repo` differs from `before`; nothing when the
+/// original API could not be read.
+fn api_changes(before: Option<&ApiSurface>, repo: &Path) -> Vec<ApiChange> {
+    let Some(before) = before else {
+        return vec![];
+    };
+    match ApiSurface::extract(repo) {
+        Ok(after) => before.diff(&after),
+        Err(e) => {
+            warn!("Could not read the public API of the candidate: {e:#}");
+            vec![]
+        }
+    }
+}
+
+/// [`BuildEvidence::collect`] with the characterization tests, if any, appended
+/// to their file for the duration of the build. A build that passes then goes
+/// through the differential check, if any.
+#[allow(clippy::too_many_arguments)]
+async fn collect_evidence(
+    repo: &Path,
+    verification: &VerificationConfig,
+    touched: &[&Path],
+    lint_baseline: Option<&[Diagnostic]>,
+    tests: Option<&CharacterizationTests>,
+    differential: Option<&DifferentialCheck>,
+    sandbox: &Sandbox,
+    txn: &Transaction,
+) -> Result<BuildEvidence> {
+    let mut evidence = {
+        let _installed = match tests {
+            Some(tests) => tests.install(repo, sandbox, txn)?,
+            None => None,
+        };
+        BuildEvidence::collect(repo, verification, touched, lint_baseline).await?
+    };
+    if let Some(check) = differential.filter(|_| evidence.passed()) {
+        let comparison = check.run(repo, verification, sandbox, txn).await?;
+        evidence.divergences = comparison.divergences;
+        evidence.not_compared = comparison.not_compared;
+    }
+    Ok(evidence)
+}
+
+/// One line from stdin, trimmed and lowercased. Read on a blocking thread so
+/// Ctrl-C can still interrupt the prompt.
+async fn read_answer() -> Result<String> {
+    let buf = tokio::task::spawn_blocking(|| {
+        let mut buf = String::new();
+        std::io::stdin().read_line(&mut buf).ok();
+        buf
+    })
+    .await?;
+    Ok(buf.trim().to_lowercase())
+}
+
+/// Current text of the files that use items of `rel_file`, smallest first, as far
+/// as `max_chars` allows. The Developer may only edit files it has seen.
+fn related_sources(
+    repo: &Path,
+    index: &SymbolIndex,
+    rel_file: &Path,
+    max_chars: usize,
+) -> Vec<(PathBuf, String)> {
+    let mut files: Vec<(PathBuf, String)> = index
+        .related_files(rel_file)
+        .into_iter()
+        .filter_map(|rel| Some((rel.clone(), read_to_string(&repo.join(&rel)).ok()?)))
+        .collect();
+    files.sort_by_key(|(_, text)| text.len());
+    let mut budget = max_chars;
+    files.retain(|(rel, text)| {
+        let fits = text.len() <= budget;
+        if fits {
+            budget -= text.len();
+        } else {
+            warn!("{} is too large to show the Developer", rel.display());
+        }
+        fits
+    });
+    files.sort();
+    files
+}
+
+/// Ask the Developer for a candidate resolved against the original files. An
+/// answer without usable edits is sent back with the reason, up to `max_retries` times.
+#[allow(clippy::too_many_arguments)]
+async fn develop(
+    dev: &DeveloperAgent<'_>,
+    workspace: &Workspace,
+    rel_file: &Path,
+    original: &str,
+    related: &[(PathBuf, String)],
+    analysis: &str,
+    few_shot: &[String],
+    dev_prompt: &str,
+    paths: &PathsConfig,
+    max_retries: u32,
+) -> Result<EditSet> {
+    let mut prompt = dev_prompt.to_string();
+    let mut retries = 0;
+    loop {
+        let attempt = dev
+            .generate(rel_file, original, related, analysis, few_shot, &prompt)
+            .await
+            .and_then(|mut out| {
+                workspace
+                    .resolve(&mut out, Base::Original)
+                    .context("DeveloperAgent: edits do not apply")?;
+                workspace
+                    .validate(&out, paths, Base::Original)
+                    .context("DeveloperAgent: edits are not allowed")?;
+                Ok(out)
+            });
+        match attempt {
+            Ok(out) => return Ok(out),
+            Err(e) => match unusable_answer(&e) {
+                Some(why) if retries < max_retries => {
+                    retries += 1;
+                    warn!("Developer answer unusable; asking again ({retries}): {why}");
+                    prompt = format!("{dev_prompt}\n\nYour previous answer could not be used: {why}");
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+}
+
+/// Why an agent's answer couldn't be turned into edits, if that is what `e` is;
+/// such answers are worth asking for again.
+fn unusable_answer(e: &anyhow::Error) -> Option<String> {
+    if let Some(e) = e.downcast_ref::<ExtractError>() {
+        return Some(e.to_string());
+    }
+    if let Some(e) = e.downcast_ref::<InvalidEdits>() {
+        return Some(e.to_string());
+    }
+    e.downcast_ref::<HunkFailures>().map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::reviewer::ReviewChecklist;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    const ORIGINAL: &str = "\
+fn process(items: &[u32]) -> u32 {
+    let mut sum = 0;
+    for x in items {
+        if x % 2 == 0 {
+            sum += x * 2;
+        }
+    }
+    sum
+}
+
+fn main() {
+    println!(\"{}\", process(&[1, 2, 3, 4]));
+}
+";
+
+    const ACCEPT: &str = r#"```json
+{"verdict":"accept","reasons":["extracted"],"patch_guidance":null,"checklist":{"refactor_verified":true,"fmt_clean":true,"clippy_clean":true}}
+```"#;
+
+    /// A binary crate under a temporary directory and a mock response directory.
+    fn fixture(responses: &[(&str, &str)]) -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path().join("demo");
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::write(
+            repo.join("Cargo.toml"),
+            "[package]\nname = \"demo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::write(repo.join("src/main.rs"), ORIGINAL).unwrap();
+        let mock = tmp.path().join("mock");
+        fs::create_dir_all(&mock).unwrap();
+        for (name, text) in responses {
+            fs::write(mock.join(name), text).unwrap();
+        }
+        (tmp, repo, mock)
+    }
+
+    async fn pipeline(repo: &Path, mock: &Path, extra: &[&str]) -> (Result<()>, RunReport) {
+        let (repo, file, mock) = (repo.display(), repo.join("src/main.rs"), mock.display());
+        let (repo, file, mock) = (repo.to_string(), file.display().to_string(), mock.to_string());
+        let mut args = vec![
+            "mantra-rs",
+            "--repo",
+            &repo,
+            "--file",
+            &file,
+            "--refactor-type",
+            "extract-method",
+            "--refactor-prompt",
+            "Extract the loop of process into sum_even_doubled",
+            "--llm",
+            "mock",
+            "--mock-dir",
+            &mock,
+            "--sandbox",
+            "copy",
+            "--yes",
+        ];
+        args.extend(extra);
+        let cli = Cli::parse_from(args);
+        let config = Config::load(&cli, cli.repo.as_deref().unwrap()).unwrap();
+        let llm = MockBackend::new(cli.mock_dir.as_deref().unwrap()).unwrap();
+        let txn = Transaction::new();
+        let mut report = RunReport::default();
+        let result = run(&cli, &config, &llm, &txn, &mut report).await;
+        match &result {
+            Ok(()) => txn.commit(),
+            Err(_) => {
+                txn.rollback().unwrap();
+            }
+        }
+        (result, report)
+    }
+
+    #[tokio::test]
+    async fn mock_pipeline_reaches_done() {
+        let developer = "```rust file=src/main.rs
+fn process(items: &[u32]) -> u32 {
+    sum_even_doubled(items)
+}
+
+fn sum_even_doubled(items: &[u32]) -> u32 {
+    items.iter().filter(|x| *x % 2 == 0).map(|x| x * 2).sum()
+}
+
+fn main() {
+    println!(\"{}\", process(&[1, 2, 3, 4]));
+}
+```";
+        // The Reviewer's checklist claims are replaced with what was measured.
+        let reviewer = ACCEPT.replace("true", "false");
+        let (_tmp, repo, mock) =
+            fixture(&[("developer.md", developer), ("reviewer.md", &reviewer)]);
+        let (result, report) = pipeline(&repo, &mock, &[]).await;
+        result.unwrap();
+        let after = fs::read_to_string(repo.join("src/main.rs")).unwrap();
+        assert!(after.contains("fn sum_even_doubled(items: &[u32]) -> u32"), "{after}");
+        assert_eq!(report.reviews.len(), 1);
+        assert_eq!(
+            report.reviews[0].checklist,
+            ReviewChecklist {
+                refactor_verified: true,
+                fmt_clean: true,
+                clippy_clean: true,
+            }
+        );
+        assert!(report.repair_attempts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn mock_pipeline_gives_up_and_keeps_the_original() {
+        let broken = "```rust file=src/main.rs
+fn process(items: &[u32]) -> u32 {
+    sum_even_doubled(items)
+}
+
+fn main() {
+    println!(\"{}\", process(&[1, 2, 3, 4]));
+}
+```";
+        let (_tmp, repo, mock) = fixture(&[
+            ("developer.md", broken),
+            ("reviewer.md", ACCEPT),
+            ("repair.md", broken),
+        ]);
+        let extra = ["--max-repairs", "2", "--on-repair-failure", "original"];
+        let (result, report) = pipeline(&repo, &mock, &extra).await;
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("original files kept"), "{err}");
+        assert_eq!(fs::read_to_string(repo.join("src/main.rs")).unwrap(), ORIGINAL);
+        assert!(!report.repair_attempts.is_empty());
+        assert!(report.diagnostics.iter().any(|d| d.message.contains("sum_even_doubled")));
+        // Clippy can't run on a candidate that doesn't build, whatever the Reviewer says.
+        assert!(!report.reviews[0].checklist.clippy_clean);
+    }
+}
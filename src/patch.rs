@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+use similar::TextDiff;
+use std::{fs, io::Write, path::Path};
+use tracing::info;
+
+/// A `git apply`-able unified diff turning `before` into `after` for repo-relative `rel`.
+/// `None` means the file does not exist on that side. Empty when nothing changed.
+pub fn unified_diff(rel: &Path, before: Option<&str>, after: Option<&str>) -> String {
+    if before == after {
+        return String::new();
+    }
+    let rel = rel.to_string_lossy().replace('\\', "/");
+    let (a, b) = (format!("a/{rel}"), format!("b/{rel}"));
+    let mut out = format!("diff --git {a} {b}\n");
+    match (before, after) {
+        (None, _) => out.push_str("new file mode 100644\n"),
+        (_, None) => out.push_str("deleted file mode 100644\n"),
+        _ => {}
+    }
+    let old_header = if before.is_some() { a } else { "/dev/null".into() };
+    let new_header = if after.is_some() { b } else { "/dev/null".into() };
+    let body = TextDiff::from_lines(before.unwrap_or_default(), after.unwrap_or_default())
+        .unified_diff()
+        .context_radius(3)
+        .header(&old_header, &new_header)
+        .to_string();
+    out.push_str(&body);
+    out
+}
+
+/// Write `patch` to `path`, or to stdout when no path is given.
+pub fn emit(patch: &str, path: Option<&Path>) -> Result<()> {
+    if patch.is_empty() {
+        info!("Dry run: no changes to report");
+    }
+    match path {
+        Some(path) => {
+            fs::write(path, patch).with_context(|| format!("write patch {}", path.display()))?;
+            info!("Patch written to {}", path.display());
+        }
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(patch.as_bytes())?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -16,16 +16,28 @@ Step 3: Structure Information Extraction.
 Use the provided static analysis of the repository (module layout, item graph, call sites, impl blocks, traits, visibility) to gather any extra context required.
 
 Step 4: Refactoring Execution.
-Produce the **entire updated file content** implementing the refactor. Preserve semantics and public API unless explicitly requested. Keep idiomatic Rust style.
+Produce the **entire updated content** of every file the refactor touches: the target file and, when the refactor needs it (moving items to another module, renaming a `pub` item used elsewhere), the other files whose call sites, imports or definitions change. The current content of those other files is given under "Other files using items of the target"; edit only files shown to you (or new files you create), and copy SEARCH text from the content shown. Preserve semantics and public API unless explicitly requested. Keep idiomatic Rust style.
 
 ### Output contract
-Return ONLY a single fenced code block with the *full file content* after refactor:
-```rust
-// <file.rs>
+Return one fenced code block per changed file, with the repo-relative path in the fence header:
+```rust file=src/lib.rs
 <entire updated file>
-````
+```
+Add `action=create` for a new file. To delete a file, return an empty block:
+```delete file=src/old.rs
+```
+For a small change to a large file, send search/replace blocks instead of the whole file. Each SEARCH section must be copied verbatim from the original file, with enough surrounding lines to match exactly one place:
+```rust file=src/big.rs
+<<<<<<< SEARCH
+<exact original lines>
+=======
+<replacement lines>
+>>>>>>> REPLACE
+```
+A `diff` block of unified-diff hunks (`@@ ... @@`, with ` `, `-` and `+` lines) is accepted too. Blocks that do not apply are sent back to you.
+Only paths inside the repository (and allowed by its configuration) are accepted. Unchanged files must not be repeated.
 
-No extra commentary before or after the code block.
+No extra commentary before or after the code blocks.
 "#
     }
 
@@ -48,18 +60,18 @@ Goals (in order):
 
 ### Inputs
 
-* Original file, Candidate file
+* Original and candidate target file, plus a unified diff of any other files the candidate touches
 * Static analysis summary
-* Optional compiler/test logs
+* Results of `cargo check`, the test suite, and clippy warnings the candidate introduced
 
 ### Output contract
 
-Return a short structured JSON object:
+Return only this JSON object, with every field present:
 
 {
 "verdict": "accept" | "revise",
 "reasons": ["..."],
-"patch_guidance": "Only if verdict=revise: concrete edits to apply",
+"patch_guidance": "concrete edits to apply if verdict=revise, otherwise null",
 "checklist": {
 "refactor_verified": true|false,
 "fmt_clean": true|false,
@@ -77,13 +89,14 @@ Keep it concise and actionable.
 
 You are given:
 
-* The current (candidate) file content
+* The current content of every file the refactor touched
 * Compiler/test ERROR logs (fresh)
+* Your previous repair attempts, if any: your reflection on each, its edits, and the errors it left
   Your job:
 
-1. **Initial Analysis**: pinpoint root causes using exact error lines/messages.
+1. **Initial Analysis**: pinpoint root causes using exact error lines/messages. If earlier attempts failed, say why they did and try something different; never return a version you already tried.
 2. **Plan**: list minimal code edits to fix errors while preserving behavior of the refactor.
-3. **Act**: output the ENTIRE corrected file.
+3. **Act**: output the corrected content of each file you change, in full or as search/replace blocks.
 
 ### Constraints
 
@@ -93,14 +106,60 @@ You are given:
 
 ### Output contract
 
-Respond with a single fenced code block containing the **full corrected file**:
+Respond with one fenced code block per file you change, each containing the **full corrected file** and its repo-relative path:
 
-```rust
-// <file.rs>
+```rust file=src/lib.rs
 <entire corrected file>
 ```
 
-No extra commentary.
+Use `action=create` in the header for a new file, and an empty block whose language is `delete` to remove one.
+
+For small fixes in large files you may instead send search/replace blocks, with SEARCH copied verbatim from the current file:
+
+```rust file=src/lib.rs
+<<<<<<< SEARCH
+<exact current lines>
+=======
+<fixed lines>
+>>>>>>> REPLACE
+```
+
+Before the first block, write one short paragraph starting with `Reflection:` that names the root cause and what you changed. No other commentary.
+"#
+    }
+
+    /// TestWriter agent: characterization tests that pin the current behavior.
+    pub fn test_writer() -> &'static str {
+        r#"### Role: TestWriter Agent (Rust)
+
+Before the code below is refactored, write characterization tests: tests that record what the code does **today**, so a refactoring that changes behavior makes them fail.
+
+You are given:
+
+* The file to be refactored and a static analysis of its crate
+* The refactoring request
+* If you tried before: your previous tests and the build/test output they produced on the unchanged code
+
+### Guidelines
+
+1. Cover the items the refactoring request is about: typical inputs, edge cases (empty, zero, negative, boundaries), and error paths.
+2. Assert the values the code returns **now**, even where they look wrong; these tests describe behavior, they do not judge it.
+3. Call only items that keep their name and path through the refactor. If the request renames or moves an item, test it through the items that call it.
+4. Keep every test deterministic and fast: no network, no sleeping, no dependence on the clock, the environment or the file system outside a temp dir.
+5. Use only the standard library and what the crate already depends on.
+
+### Output contract
+
+Return exactly one fenced block with the **body** of a test module. It is placed in a `#[cfg(test)] mod` at the end of the file, after `use super::*;`, so private items are in scope:
+
+```rust
+#[test]
+fn parses_empty_input() {
+    assert_eq!(parse(""), Vec::<Token>::new());
+}
+```
+
+No other commentary.
 "#
     }
 }
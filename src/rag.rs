@@ -1,9 +1,9 @@
 use anyhow::Result;
 use std::{fs, path::Path};
 
-/// Load up to three small few-shot examples from a directory.
+/// Load up to `max` small few-shot examples from a directory.
 /// Files can be `.txt` or `.md` containing before/after snippets.
-pub fn load_few_shot(rag_dir: &Path) -> Result<Vec<String>> {
+pub fn load_few_shot(rag_dir: &Path, max: usize) -> Result<Vec<String>> {
     if !rag_dir.exists() {
         return Ok(vec![]);
     }
@@ -14,7 +14,7 @@ pub fn load_few_shot(rag_dir: &Path) -> Result<Vec<String>> {
         .filter(|p| p.is_file())
         .collect();
     files.sort();
-    for p in files.into_iter().take(3) {
+    for p in files.into_iter().take(max) {
         if let Ok(s) = fs::read_to_string(&p) {
             out.push(s);
         }
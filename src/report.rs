@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+use crate::{
+    agents::{repair::RepairAttempt, reviewer::ReviewVerdict},
+    api::ApiChange,
+    candidates::CandidateScore,
+    characterize::CharacterizationTests,
+    config::AgentProfiles,
+    verification::Diagnostic,
+};
+
+/// A tool edit that was undone because it fell outside the refactor.
+#[derive(Debug, Clone, Serialize)]
+pub struct RevertedChange {
+    /// Repo-relative path.
+    pub path: PathBuf,
+    pub tool: String,
+    pub detail: String,
+}
+
+/// A compiler suggestion applied to the candidate before asking the Repair agent.
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedFix {
+    /// Repo-relative path.
+    pub path: PathBuf,
+    pub line: usize,
+    /// The diagnostic the fix answers, e.g. `warning: unused import: `std::fs``.
+    pub diagnostic: String,
+    /// What the fix does, e.g. `remove the unused import`.
+    pub fix: String,
+}
+
+/// What happened during a run. Summarised in the log at the end and written as
+/// JSON with `--report <path>`.
+#[derive(Debug, Default, Serialize)]
+pub struct RunReport {
+    pub agents: Option<AgentProfiles>,
+    pub reverted: Vec<RevertedChange>,
+    /// Every Reviewer verdict, in order, with its checklist.
+    pub reviews: Vec<ReviewVerdict>,
+    /// Diagnostics of the last build of the candidate, as the agents saw them.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Inputs on which a refactored function of the last build behaved
+    /// differently from the original, under `--differential`.
+    pub divergences: Vec<String>,
+    /// Refactored functions the differential check could not compare, with the
+    /// reason; they were not shown to behave as before.
+    pub not_compared: Vec<String>,
+    /// `MachineApplicable` compiler suggestions applied without an agent.
+    pub applied_fixes: Vec<AppliedFix>,
+    /// Developer candidates that lost to a better one under `--candidates`.
+    pub discarded_candidates: Vec<CandidateScore>,
+    /// Every candidate of the Repair loop that failed, with its score; number 0
+    /// is the reviewed candidate that entered the loop.
+    pub repair_attempts: Vec<RepairAttempt>,
+    /// The TestWriter's tests that passed on the original, under `--characterize`.
+    pub characterization: Option<CharacterizationTests>,
+    /// Public API changes of the final candidate, kept under `--allow-api-change`.
+    pub api_changes: Vec<ApiChange>,
+}
+
+impl RunReport {
+    pub fn log_summary(&self) {
+        if let Some(last) = self.reviews.last() {
+            let c = &last.checklist;
+            info!(
+                "Final review: {} (refactor_verified={} fmt_clean={} clippy_clean={})",
+                last.verdict, c.refactor_verified, c.fmt_clean, c.clippy_clean
+            );
+        }
+        if let Some(tests) = &self.characterization {
+            info!(
+                "{} characterization test(s) from the original checked the candidate",
+                tests.count
+            );
+        }
+        if !self.not_compared.is_empty() {
+            warn!(
+                "Differential check did not compare {} refactored function(s): {}",
+                self.not_compared.len(),
+                self.not_compared.join(", ")
+            );
+        }
+        if !self.api_changes.is_empty() {
+            warn!(
+                "The refactor changes {} public API item(s) (allowed)",
+                self.api_changes.len()
+            );
+        }
+        if !self.applied_fixes.is_empty() {
+            info!(
+                "{} compiler suggestion(s) applied without the Repair agent",
+                self.applied_fixes.len()
+            );
+        }
+        for r in &self.reverted {
+            warn!(
+                "Reverted {} edit to {}: {}",
+                r.tool,
+                r.path.display(),
+                r.detail
+            );
+        }
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("write report {}", path.display()))?;
+        info!("Run report written to {}", path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tempfile::TempDir;
+use tracing::{info, warn};
+
+use crate::{
+    exec::{register_target_dir, run_cmd, unregister_target_dir},
+    txn::Transaction,
+};
+
+/// Where the pipeline edits, formats and builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SandboxMode {
+    /// `worktree` inside a git checkout, `copy` otherwise
+    Auto,
+    /// Temporary copy of `--repo` (without `target/` and `.git/`)
+    Copy,
+    /// Detached `git worktree` of HEAD plus the checkout's uncommitted changes
+    Worktree,
+    /// Edit the user's checkout in place
+    None,
+}
+
+/// A file with text appended by [`Sandbox::append_temporarily`]. Dropping it
+/// puts the file back as it was.
+pub struct Appended {
+    path: PathBuf,
+    content: String,
+}
+
+impl Drop for Appended {
+    fn drop(&mut self) {
+        if let Err(e) = fs::write(&self.path, &self.content) {
+            warn!("Could not restore {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// A scratch copy of the user's repo. Only files explicitly promoted with
+/// [`Sandbox::promote`] ever reach the user's checkout.
+pub struct Sandbox {
+    origin: PathBuf,
+    root: PathBuf,
+    worktree: Option<Worktree>,
+    tmp: Option<TempDir>,
+    target_dir: PathBuf,
+    keep_on_failure: bool,
+    succeeded: bool,
+}
+
+struct Worktree {
+    toplevel: PathBuf,
+    path: PathBuf,
+}
+
+impl Sandbox {
+    /// Set up a sandbox for `repo` (canonical path) according to `mode`.
+    pub async fn create(repo: &Path, mode: SandboxMode, keep_on_failure: bool) -> Result<Self> {
+        let mut sandbox = Sandbox {
+            origin: repo.to_path_buf(),
+            root: repo.to_path_buf(),
+            worktree: None,
+            tmp: None,
+            target_dir: repo.join("target").join("mantra"),
+            keep_on_failure,
+            succeeded: false,
+        };
+        let toplevel = git_toplevel(repo).await;
+        match (mode, toplevel) {
+            (SandboxMode::None, _) => return Ok(sandbox),
+            (SandboxMode::Worktree, Some(toplevel)) => sandbox.init_worktree(&toplevel).await?,
+            (SandboxMode::Auto, Some(toplevel)) => {
+                if let Err(e) = sandbox.init_worktree(&toplevel).await {
+                    warn!("git worktree unavailable ({e:#}); falling back to a copy");
+                    sandbox.init_copy()?;
+                }
+            }
+            (SandboxMode::Worktree, None) => {
+                bail!(
+                    "--sandbox worktree: {} is not inside a git repository",
+                    repo.display()
+                )
+            }
+            (SandboxMode::Copy | SandboxMode::Auto, _) => sandbox.init_copy()?,
+        }
+        // Sandboxes of one repo look identical to cargo, so each builds into its own
+        // directory; an in-place run stays out of the user's own `target/`.
+        if let Some(tmp) = &sandbox.tmp {
+            sandbox.target_dir = tmp.path().join("target");
+        }
+        if std::env::var_os("CARGO_TARGET_DIR").is_none() {
+            register_target_dir(&sandbox.root, &sandbox.target_dir);
+        }
+        info!("Working in sandbox {}", sandbox.root.display());
+        Ok(sandbox)
+    }
+
+    fn init_copy(&mut self) -> Result<()> {
+        let tmp = tempfile::Builder::new().prefix("mantra-").tempdir()?;
+        let root = tmp.path().join("repo");
+        copy_tree(&self.origin, &root)?;
+        self.root = root;
+        self.tmp = Some(tmp);
+        Ok(())
+    }
+
+    async fn init_worktree(&mut self, toplevel: &Path) -> Result<()> {
+        let tmp = tempfile::Builder::new().prefix("mantra-").tempdir()?;
+        let path = tmp.path().join("worktree");
+        let path_str = path.to_string_lossy().to_string();
+        let out = run_cmd(
+            toplevel,
+            "git",
+            &["worktree", "add", "--detach", &path_str, "HEAD"],
+        )
+        .await?;
+        if out.status != 0 {
+            bail!("git worktree add failed: {}", out.stderr.trim());
+        }
+        self.tmp = Some(tmp);
+        self.worktree = Some(Worktree {
+            toplevel: toplevel.to_path_buf(),
+            path: path.clone(),
+        });
+
+        // Carry over uncommitted edits and untracked files so the sandbox matches the
+        // checkout. `--modified` only compares with the index, so staged changes are
+        // listed separately; without them `promote` would write HEAD over them.
+        let unstaged = run_cmd(
+            toplevel,
+            "git",
+            &[
+                "ls-files",
+                "-z",
+                "--modified",
+                "--others",
+                "--exclude-standard",
+            ],
+        )
+        .await?;
+        let staged = run_cmd(
+            toplevel,
+            "git",
+            &["diff", "--cached", "--name-only", "--no-renames", "-z"],
+        )
+        .await?;
+        if unstaged.status != 0 || staged.status != 0 {
+            bail!(
+                "could not list uncommitted changes: {}",
+                format!("{} {}", unstaged.stderr, staged.stderr).trim()
+            );
+        }
+        let listed = format!("{}\0{}", unstaged.stdout, staged.stdout);
+        let mut rels: Vec<&str> = listed.split('\0').filter(|s| !s.is_empty()).collect();
+        rels.sort_unstable();
+        rels.dedup();
+        for rel in rels {
+            let src = toplevel.join(rel);
+            let dst = path.join(rel);
+            if src.is_file() {
+                if let Some(parent) = dst.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::copy(&src, &dst).with_context(|| format!("copy {}", src.display()))?;
+            } else if dst.is_file() {
+                fs::remove_file(&dst)?;
+            }
+        }
+
+        self.root = match self.origin.strip_prefix(toplevel) {
+            Ok(prefix) if !prefix.as_os_str().is_empty() => path.join(prefix),
+            _ => path,
+        };
+        Ok(())
+    }
+
+    /// Directory the pipeline should treat as the repo root.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where cargo builds this sandbox, unless `CARGO_TARGET_DIR` is set.
+    pub fn target_dir(&self) -> &Path {
+        &self.target_dir
+    }
+
+    pub fn is_isolated(&self) -> bool {
+        self.root != self.origin
+    }
+
+    /// Sandbox location of a path inside the user's repo.
+    pub fn map(&self, origin_path: &Path) -> PathBuf {
+        match origin_path.strip_prefix(&self.origin) {
+            Ok(rel) => self.root.join(rel),
+            Err(_) => origin_path.to_path_buf(),
+        }
+    }
+
+    /// Write a file inside the sandbox, creating missing directories. In-place runs
+    /// go through `txn` so the user's file can be restored; sandbox files are disposable.
+    pub fn write(&self, path: &Path, content: &str, txn: &Transaction) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if self.is_isolated() {
+            fs::write(path, content).with_context(|| format!("write {}", path.display()))
+        } else {
+            txn.write(path, content)
+        }
+    }
+
+    /// Append `text` to the file at `path` until the returned guard is dropped,
+    /// e.g. a test module that must not end up in the candidate.
+    pub fn append_temporarily(
+        &self,
+        path: &Path,
+        text: &str,
+        txn: &Transaction,
+    ) -> Result<Appended> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        self.write(path, &format!("{content}{text}"), txn)?;
+        Ok(Appended {
+            path: path.to_path_buf(),
+            content,
+        })
+    }
+
+    /// Delete a file inside the sandbox (through `txn` for in-place runs).
+    pub fn remove(&self, path: &Path, txn: &Transaction) -> Result<()> {
+        if self.is_isolated() {
+            fs::remove_file(path).with_context(|| format!("remove {}", path.display()))
+        } else {
+            txn.remove(path)
+        }
+    }
+
+    /// Copy repo-relative `rel` from the sandbox back to the user's checkout, or
+    /// delete it there if the sandbox copy is gone.
+    pub fn promote(&self, rel: &Path, txn: &Transaction) -> Result<()> {
+        if !self.is_isolated() {
+            return Ok(());
+        }
+        let src = self.root.join(rel);
+        let dst = self.origin.join(rel);
+        if !src.exists() {
+            if dst.exists() {
+                txn.remove(&dst)?;
+                info!("Deleted {} from {}", rel.display(), self.origin.display());
+            }
+            return Ok(());
+        }
+        let content = fs::read_to_string(&src)
+            .with_context(|| format!("read sandbox copy of {}", rel.display()))?;
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        txn.write(&dst, &content)?;
+        info!("Promoted {} to {}", rel.display(), self.origin.display());
+        Ok(())
+    }
+
+    /// Mark the run as successful so the sandbox is removed even with `--keep-failed`.
+    pub fn mark_succeeded(&mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        unregister_target_dir(&self.root);
+        if !self.is_isolated() {
+            return;
+        }
+        if self.keep_on_failure && !self.succeeded {
+            if let Some(tmp) = self.tmp.take() {
+                let kept = tmp.keep();
+                warn!("--keep-failed: sandbox left at {}", kept.display());
+            }
+            return;
+        }
+        if let Some(wt) = &self.worktree {
+            // Synchronous: Drop may run while the runtime is shutting down.
+            let status = std::process::Command::new("git")
+                .arg("worktree")
+                .arg("remove")
+                .arg("--force")
+                .arg(&wt.path)
+                .current_dir(&wt.toplevel)
+                .output();
+            if !matches!(status, Ok(ref o) if o.status.success()) {
+                warn!("could not remove git worktree {}", wt.path.display());
+            }
+        }
+    }
+}
+
+async fn git_toplevel(repo: &Path) -> Option<PathBuf> {
+    let out = run_cmd(repo, "git", &["rev-parse", "--show-toplevel"])
+        .await
+        .ok()?;
+    (out.status == 0).then(|| PathBuf::from(out.stdout.trim()))
+}
+
+/// Recursively copy `src` to `dst`, skipping `target/` and `.git/`.
+fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src).with_context(|| format!("read {}", src.display()))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "target" || name == ".git" {
+            continue;
+        }
+        let from = entry.path();
+        let to = dst.join(&name);
+        let ty = entry.file_type()?;
+        if ty.is_dir() {
+            copy_tree(&from, &to)?;
+        } else if ty.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+            #[cfg(not(unix))]
+            fs::copy(&from, &to)?;
+        } else {
+            fs::copy(&from, &to).with_context(|| format!("copy {}", from.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_copies_build_into_their_own_target_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path().join("demo");
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::write(repo.join("src/lib.rs"), "").unwrap();
+
+        let a = Sandbox::create(&repo, SandboxMode::Copy, false).await.unwrap();
+        let b = Sandbox::create(&repo, SandboxMode::Copy, false).await.unwrap();
+        assert_ne!(a.target_dir(), b.target_dir());
+        assert!(!a.target_dir().starts_with(&repo));
+        if std::env::var_os("CARGO_TARGET_DIR").is_none() {
+            let env = |s: &Sandbox| {
+                let dir = s.root().join("src");
+                async move { run_cmd(&dir, "sh", &["-c", "echo $CARGO_TARGET_DIR"]).await }
+            };
+            let out = env(&a).await.unwrap();
+            assert_eq!(Path::new(out.stdout.trim()), a.target_dir());
+            let out = env(&b).await.unwrap();
+            assert_eq!(Path::new(out.stdout.trim()), b.target_dir());
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+
+use crate::io_utils::rust_sources;
+
+/// Backups of every file the pipeline may modify, so a failed or interrupted
+/// run can put the user's tree back exactly as it was.
+///
+/// Cloning is cheap and shares the same backup set.
+#[derive(Clone, Default)]
+pub struct Transaction {
+    state: Arc<Mutex<TxnState>>,
+}
+
+#[derive(Default)]
+struct TxnState {
+    /// Original bytes per path; `None` means the file did not exist.
+    backups: BTreeMap<PathBuf, Option<Vec<u8>>>,
+    finished: bool,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember the current content of `path` (only the first call per path counts).
+    pub fn backup(&self, path: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.backups.contains_key(path) {
+            return Ok(());
+        }
+        let original = match fs::read(path) {
+            Ok(bytes) => Some(bytes),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("backup {}", path.display())),
+        };
+        state.backups.insert(path.to_path_buf(), original);
+        Ok(())
+    }
+
+    /// Back up every `.rs` file and `Cargo.toml` under `root` before running tools
+    /// such as `rustfmt` or `clippy --fix` that may rewrite them.
+    pub fn backup_tree(&self, root: &Path) -> Result<usize> {
+        let files = rust_sources(root).with_context(|| format!("scan {}", root.display()))?;
+        for path in &files {
+            self.backup(path)?;
+        }
+        Ok(files.len())
+    }
+
+    /// Back up `path`, then overwrite it.
+    pub fn write(&self, path: &Path, content: &str) -> Result<()> {
+        self.backup(path)?;
+        fs::write(path, content).with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Back up `path`, then delete it.
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        self.backup(path)?;
+        fs::remove_file(path).with_context(|| format!("remove {}", path.display()))
+    }
+
+    /// Keep all changes; later rollbacks become no-ops.
+    pub fn commit(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.backups.clear();
+        state.finished = true;
+    }
+
+    /// Keep the changes on disk but stop tracking them (`--keep-failed`).
+    pub fn abandon(&self) -> Vec<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        let changed = changed_paths(&state.backups);
+        state.backups.clear();
+        changed
+    }
+
+    /// Restore every backed-up file whose content changed and delete files the run
+    /// created. Returns the paths that were restored.
+    pub fn rollback(&self) -> Result<Vec<PathBuf>> {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            return Ok(vec![]);
+        }
+        let restored = changed_paths(&state.backups);
+        for path in &restored {
+            match &state.backups[path] {
+                Some(bytes) => {
+                    fs::write(path, bytes).with_context(|| format!("restore {}", path.display()))?
+                }
+                None => {
+                    fs::remove_file(path).with_context(|| format!("remove {}", path.display()))?
+                }
+            }
+        }
+        state.backups.clear();
+        state.finished = true;
+        Ok(restored)
+    }
+}
+
+/// Backed-up paths whose on-disk state no longer matches the backup.
+fn changed_paths(backups: &BTreeMap<PathBuf, Option<Vec<u8>>>) -> Vec<PathBuf> {
+    backups
+        .iter()
+        .filter(|(path, original)| fs::read(path).ok() != **original)
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+/// Log the outcome of [`Transaction::rollback`].
+pub fn report_rollback(restored: &[PathBuf]) {
+    if restored.is_empty() {
+        info!("Rollback: no files needed restoring");
+    }
+    for path in restored {
+        warn!("Rollback: restored {}", path.display());
+    }
+}
+
+/// Restores the tree if dropped while the transaction is still open, e.g. when
+/// unwinding from a panic.
+pub struct RollbackOnDrop(pub Transaction);
+
+impl Drop for RollbackOnDrop {
+    fn drop(&mut self) {
+        match self.0.rollback() {
+            Ok(restored) if !restored.is_empty() => report_rollback(&restored),
+            Ok(_) => {}
+            Err(e) => eprintln!("rollback failed: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn rollback_restores_modified_created_and_deleted_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (modified, created, deleted) = (
+            tmp.path().join("a.rs"),
+            tmp.path().join("b.rs"),
+            tmp.path().join("c.rs"),
+        );
+        fs::write(&modified, "a").unwrap();
+        fs::write(&deleted, "c").unwrap();
+
+        let txn = Transaction::new();
+        txn.write(&modified, "a2").unwrap();
+        // Only the first backup of a path counts.
+        txn.write(&modified, "a3").unwrap();
+        txn.write(&created, "b").unwrap();
+        txn.remove(&deleted).unwrap();
+
+        let restored = txn.rollback().unwrap();
+        assert_eq!(restored, vec![modified.clone(), created.clone(), deleted.clone()]);
+        assert_eq!(fs::read_to_string(&modified).unwrap(), "a");
+        assert!(!created.exists());
+        assert_eq!(fs::read_to_string(&deleted).unwrap(), "c");
+        // A finished transaction rolls back nothing.
+        assert!(txn.rollback().unwrap().is_empty());
+    }
+
+    #[test]
+    fn commit_keeps_changes_and_drop_rolls_back_open_ones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("a.rs");
+        fs::write(&path, "a").unwrap();
+
+        let txn = Transaction::new();
+        txn.write(&path, "kept").unwrap();
+        txn.commit();
+        assert!(txn.rollback().unwrap().is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept");
+
+        let txn = Transaction::new();
+        {
+            let _guard = RollbackOnDrop(txn.clone());
+            txn.write(&path, "lost").unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept");
+    }
+}
@@ -1,69 +1,1180 @@
-use crate::config::RefactorKind;
-use crate::exec::run_cmd;
-use anyhow::{Context, Result};
-use regex::Regex;
+use crate::ast::{FnItem, FnKey, ItemGraph};
+use crate::config::{RefactorKind, VerificationConfig};
+use crate::autofix::formatter_args;
+use crate::exec::{run_cmd, run_cmdline, run_cmdline_in, run_cmdline_with};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use tracing::info;
-use std::path::Path;
-
-/// Run rust-code-analysis-cli to snapshot structure (best-effort).
-pub async fn analyze_repo(repo: &Path, file: &Path) -> Result<String> {
-    let args = [
-        "-m",
-        "functions,modules,structs,impls",
-        "-p",
-        file.to_str().unwrap(),
-    ];
-    let out = run_cmd(repo, "rust-code-analysis-cli", &args)
-        .await
-        .context("rust-code-analysis-cli failed")?;
-    Ok(format!("STDOUT:\n{}\nSTDERR:\n{}", out.stdout, out.stderr))
-}
-
-/// rustfmt --check
-pub async fn fmt_check(repo: &Path) -> Result<bool> {
-    let out = run_cmd(repo, "cargo", &["fmt", "--", "--check"]).await?;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+/// rustfmt --check on the refactored files only (`verification.fmt_check`)
+pub async fn fmt_check(repo: &Path, cfg: &VerificationConfig, files: &[&Path]) -> Result<bool> {
+    let args = formatter_args(repo, &cfg.fmt_check, files);
+    let out = run_cmdline_with(repo, &cfg.fmt_check, &args).await?;
     Ok(out.status == 0)
 }
 
-/// clippy -D warnings
-pub async fn clippy_check(repo: &Path) -> Result<bool> {
-    let out = run_cmd(repo, "cargo", &["clippy", "-q", "--", "-D", "warnings"]).await?;
-    Ok(out.status == 0)
+/// One compiler or clippy message, e.g. `error[E0425]: cannot find value `x``.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// `error`, `warning`, …
+    pub level: String,
+    /// `E0425`, `clippy::needless_return`, …
+    pub code: Option<String>,
+    pub message: String,
+    /// Where the primary span starts, when the message points at code.
+    pub span: Option<Span>,
+    pub suggestions: Vec<Suggestion>,
+    /// The full message as rustc prints it.
+    pub rendered: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A fix rustc or clippy proposes. Its edits go together: apply all or none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Suggestion {
+    pub message: String,
+    /// `MachineApplicable`, `MaybeIncorrect`, `HasPlaceholders` or `Unspecified`.
+    pub applicability: Option<String>,
+    pub edits: Vec<Replacement>,
+}
+
+/// Replace bytes `byte_start..byte_end` of `file` with `text`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Replacement {
+    pub file: PathBuf,
+    pub byte_start: usize,
+    pub byte_end: usize,
+    pub text: String,
+}
+
+impl Diagnostic {
+    /// Identifies a lint across line shifts: level, code, message and file.
+    fn key(&self) -> (String, Option<String>, String, Option<PathBuf>) {
+        (
+            self.level.clone(),
+            self.code.clone(),
+            self.message.clone(),
+            self.span.as_ref().map(|s| s.file.clone()),
+        )
+    }
+
+    /// `error[E0308]: mismatched types at src/main.rs:3:13`
+    pub fn headline(&self) -> String {
+        let code = self.code.as_ref().map(|c| format!("[{c}]")).unwrap_or_default();
+        match &self.span {
+            Some(s) => format!(
+                "{}{code}: {} at {}:{}:{}",
+                self.level,
+                self.message,
+                s.file.display(),
+                s.line,
+                s.column
+            ),
+            None => format!("{}{code}: {}", self.level, self.message),
+        }
+    }
+
+    fn in_files(&self, files: &[&Path]) -> bool {
+        self.span.as_ref().is_some_and(|s| files.contains(&s.file.as_path()))
+    }
+
+    /// Make the paths cargo reported relative to `root` (the workspace root)
+    /// relative to `repo` instead. Files outside `repo` keep an absolute path.
+    fn rebase(&mut self, root: &Path, repo: &Path) {
+        let rebased = |file: &Path| {
+            let abs = root.join(file);
+            abs.strip_prefix(repo).map(Path::to_path_buf).unwrap_or(abs)
+        };
+        if let Some(s) = self.span.as_mut() {
+            s.file = rebased(&s.file);
+        }
+        for e in self.suggestions.iter_mut().flat_map(|s| &mut s.edits) {
+            e.file = rebased(&e.file);
+        }
+    }
+}
+
+/// The root of the workspace `repo` belongs to, which cargo reports spans
+/// against; `None` if `cargo metadata` fails.
+async fn workspace_root(repo: &Path) -> Option<PathBuf> {
+    #[derive(Deserialize)]
+    struct Metadata {
+        workspace_root: PathBuf,
+    }
+    let args = ["metadata", "--format-version", "1", "--no-deps"];
+    let out = run_cmd(repo, "cargo", &args).await.ok()?;
+    if out.status != 0 {
+        return None;
+    }
+    serde_json::from_str::<Metadata>(&out.stdout)
+        .ok()
+        .map(|m| m.workspace_root)
+}
+
+/// `cmdline` with `--message-format=json` added, if it is a cargo command that
+/// compiles; `None` for anything else, whose output is parsed as text.
+fn with_json_messages(cmdline: &str) -> Option<String> {
+    let words: Vec<&str> = cmdline.split_whitespace().collect();
+    if words.first() != Some(&"cargo") {
+        return None;
+    }
+    let sub = words[1..].iter().find(|w| !w.starts_with(['+', '-']))?;
+    if !matches!(*sub, "build" | "b" | "check" | "c" | "clippy" | "test" | "t") {
+        return None;
+    }
+    if let Some(format) = words.iter().find(|w| w.starts_with("--message-format")) {
+        return format.contains("json").then(|| cmdline.to_string());
+    }
+    let mut words = words;
+    let at = words.iter().position(|w| *w == "--").unwrap_or(words.len());
+    words.insert(at, "--message-format=json");
+    Some(words.join(" "))
+}
+
+/// What a cargo build, lint or test command reported.
+struct CargoRun {
+    ok: bool,
+    diagnostics: Vec<Diagnostic>,
+    /// Output that isn't a compiler message, e.g. the test harness's.
+    text: String,
+}
+
+async fn run_cargo(repo: &Path, cmdline: &str, target_dir: Option<&Path>) -> Result<CargoRun> {
+    let mut run = run_cargo_messages(repo, cmdline, target_dir).await?;
+    if let Some(root) = workspace_root(repo).await {
+        let repo = std::fs::canonicalize(repo).unwrap_or_else(|_| repo.to_path_buf());
+        for d in &mut run.diagnostics {
+            d.rebase(&root, &repo);
+        }
+    }
+    Ok(run)
+}
+
+/// [`run_cargo`] with spans as cargo reports them, relative to the workspace root.
+async fn run_cargo_messages(
+    repo: &Path,
+    cmdline: &str,
+    target_dir: Option<&Path>,
+) -> Result<CargoRun> {
+    let run = |cmdline: String| async move {
+        match target_dir {
+            Some(dir) => run_cmdline_in(repo, &cmdline, dir).await,
+            None => run_cmdline(repo, &cmdline).await,
+        }
+    };
+    let Some(json_cmd) = with_json_messages(cmdline) else {
+        let out = run(cmdline.to_string()).await?;
+        let text = format!("{}\n{}", out.stdout, out.stderr);
+        return Ok(CargoRun {
+            ok: out.status == 0,
+            diagnostics: parse_diagnostics(&text),
+            text,
+        });
+    };
+    let out = run(json_cmd).await?;
+    let mut diagnostics = vec![];
+    let mut text = String::new();
+    for line in out.stdout.lines() {
+        match serde_json::from_str::<CargoMessage>(line) {
+            Ok(msg) => {
+                if let Some(m) = msg.message.filter(|_| msg.reason == "compiler-message") {
+                    push_diagnostic(m.into_diagnostic(), &mut diagnostics);
+                }
+            }
+            Err(_) => {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+    // Cargo's own errors (bad manifest, missing dependency) stay on stderr as text.
+    for d in parse_diagnostics(&out.stderr) {
+        push_diagnostic(d, &mut diagnostics);
+    }
+    text.push_str(&out.stderr);
+    Ok(CargoRun {
+        ok: out.status == 0,
+        diagnostics,
+        text,
+    })
+}
+
+/// Keep `d` unless it repeats one already in `out` (the same message is reported
+/// once per target, e.g. for the library and its tests).
+fn push_diagnostic(d: Diagnostic, out: &mut Vec<Diagnostic>) {
+    let summary = d.message.starts_with("could not compile")
+        || d.message.starts_with("aborting due to")
+        || d.message.starts_with("build failed")
+        || d.message.starts_with("test failed, to rerun")
+        || d.message.contains("generated ") && d.message.contains(" warning");
+    let repeat = out
+        .iter()
+        .any(|o| o.key() == d.key() && o.span == d.span);
+    if !summary && !repeat {
+        out.push(d);
+    }
+}
+
+/// A line of `cargo --message-format=json`.
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    message: Option<RustcMessage>,
+}
+
+#[derive(Deserialize)]
+struct RustcMessage {
+    message: String,
+    code: Option<RustcCode>,
+    level: String,
+    spans: Vec<RustcSpan>,
+    children: Vec<RustcMessage>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RustcCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct RustcSpan {
+    file_name: PathBuf,
+    byte_start: usize,
+    byte_end: usize,
+    line_start: usize,
+    column_start: usize,
+    is_primary: bool,
+    suggested_replacement: Option<String>,
+    suggestion_applicability: Option<String>,
+}
+
+impl RustcMessage {
+    fn into_diagnostic(self) -> Diagnostic {
+        let span = self.spans.iter().find(|s| s.is_primary).map(|s| Span {
+            file: s.file_name.clone(),
+            line: s.line_start,
+            column: s.column_start,
+        });
+        let suggestions = std::iter::once(&self)
+            .chain(&self.children)
+            .filter_map(|m| {
+                let edits: Vec<Replacement> = m
+                    .spans
+                    .iter()
+                    .filter_map(|s| {
+                        Some(Replacement {
+                            file: s.file_name.clone(),
+                            byte_start: s.byte_start,
+                            byte_end: s.byte_end,
+                            text: s.suggested_replacement.clone()?,
+                        })
+                    })
+                    .collect();
+                (!edits.is_empty()).then(|| Suggestion {
+                    message: m.message.clone(),
+                    applicability: m.spans.iter().find_map(|s| s.suggestion_applicability.clone()),
+                    edits,
+                })
+            })
+            .collect();
+        Diagnostic {
+            level: self.level,
+            code: self.code.map(|c| c.code),
+            rendered: self
+                .rendered
+                .map(|r| r.trim_end().to_string())
+                .unwrap_or_else(|| self.message.clone()),
+            message: self.message,
+            span,
+            suggestions,
+        }
+    }
+}
+
+/// Split rustc/cargo human-readable output into diagnostics, leaving out the
+/// summary lines (`could not compile`, `generated N warnings`, …). Used for
+/// commands that can't produce JSON and for cargo's own errors.
+pub fn parse_diagnostics(output: &str) -> Vec<Diagnostic> {
+    let mut out: Vec<Diagnostic> = vec![];
+    let mut current: Option<Vec<&str>> = None;
+    for line in output.lines() {
+        let starts = ["error", "warning"].iter().any(|lvl| {
+            line.strip_prefix(lvl)
+                .is_some_and(|rest| rest.starts_with(':') || rest.starts_with('['))
+        });
+        if starts {
+            if let Some(d) = current.take().map(|l| text_diagnostic(&l)) {
+                push_diagnostic(d, &mut out);
+            }
+            current = Some(vec![line]);
+        } else if line.trim().is_empty() {
+            if let Some(d) = current.take().map(|l| text_diagnostic(&l)) {
+                push_diagnostic(d, &mut out);
+            }
+        } else if let Some(lines) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    if let Some(d) = current.take().map(|l| text_diagnostic(&l)) {
+        push_diagnostic(d, &mut out);
+    }
+    out
+}
+
+/// Read the lines of one human-readable diagnostic.
+fn text_diagnostic(lines: &[&str]) -> Diagnostic {
+    let head = lines[0];
+    let (level, rest) = head.split_at(head.find(['[', ':']).unwrap_or(head.len()));
+    let (code, message) = match rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+        Some((code, message)) => (Some(code.to_string()), message),
+        None => (None, rest),
+    };
+    let span = lines.iter().find_map(|l| {
+        let loc = l.trim_start().strip_prefix("--> ")?.trim();
+        let mut parts = loc.rsplitn(3, ':');
+        let column = parts.next()?.parse().ok()?;
+        let line = parts.next()?.parse().ok()?;
+        Some(Span {
+            file: PathBuf::from(parts.next()?),
+            line,
+            column,
+        })
+    });
+    Diagnostic {
+        level: level.to_string(),
+        code,
+        message: message.trim_start_matches(':').trim().to_string(),
+        span,
+        suggestions: vec![],
+        rendered: lines.join("\n").trim_end().to_string(),
+    }
+}
+
+/// `verification.check` alone, building into `target_dir`: whether it passed, and
+/// what it reported.
+pub async fn cargo_check(
+    repo: &Path,
+    cfg: &VerificationConfig,
+    target_dir: &Path,
+) -> Result<(bool, Vec<Diagnostic>)> {
+    let run = run_cargo(repo, &cfg.check, Some(target_dir)).await?;
+    Ok((run.ok, run.diagnostics))
+}
+
+/// Clippy findings on the tree as it is now (`verification.clippy_check`).
+pub async fn clippy_warnings(repo: &Path, cfg: &VerificationConfig) -> Result<Vec<Diagnostic>> {
+    Ok(run_cargo(repo, &cfg.clippy_check, None).await?.diagnostics)
+}
+
+/// `verification.test` restricted to tests whose name contains `filter`: what the
+/// test build reported, and how the tests went.
+pub async fn filtered_tests(
+    repo: &Path,
+    cfg: &VerificationConfig,
+    filter: &str,
+) -> Result<(Vec<Diagnostic>, TestOutcome)> {
+    let mut words: Vec<&str> = cfg.test.split_whitespace().collect();
+    let at = words.iter().position(|w| *w == "--").unwrap_or(words.len());
+    words.insert(at, filter);
+    let run = run_cargo(repo, &words.join(" "), None).await?;
+    let outcome = TestOutcome::parse(run.ok, &run.text);
+    Ok((run.diagnostics, outcome))
+}
+
+/// Outcome of `verification.test`.
+#[derive(Debug, Clone, Default)]
+pub struct TestOutcome {
+    pub passed: bool,
+    /// Failing tests with their panic messages.
+    pub failures: Vec<String>,
+    /// Counts summed over the `test result:` lines. Their timings are left out so
+    /// the rendered evidence, and the prompts built from it, stay reproducible.
+    pub num_passed: usize,
+    pub num_failed: usize,
+    pub num_ignored: usize,
+    pub num_filtered_out: usize,
+}
+
+impl TestOutcome {
+    fn parse(passed: bool, output: &str) -> Self {
+        let summary: Vec<&str> = output
+            .lines()
+            .filter(|l| l.starts_with("test result:"))
+            .collect();
+        // `test result: FAILED. 3 passed; 1 failed; 0 ignored; 0 measured; 2 filtered out; …`
+        let count = |what: &str| -> usize {
+            summary
+                .iter()
+                .flat_map(|l| l.split([';', '.']))
+                .filter_map(|part| part.trim().strip_suffix(what))
+                .filter_map(|n| n.trim().parse::<usize>().ok())
+                .sum()
+        };
+        let mut failures = vec![];
+        let mut lines = output.lines().peekable();
+        while let Some(line) = lines.next() {
+            if let Some(name) = line
+                .strip_prefix("---- ")
+                .and_then(|l| l.strip_suffix(" stdout ----"))
+            {
+                // Newer harnesses put a blank line before the panic message.
+                let panic: Vec<&str> = lines
+                    .by_ref()
+                    .skip_while(|l| l.trim().is_empty())
+                    .take_while(|l| !l.trim().is_empty() && !l.starts_with("stack backtrace:"))
+                    .filter(|l| !l.starts_with("note:"))
+                    .collect();
+                failures.push(format!("{name}: {}", panic.join(" ")));
+            }
+        }
+        Self {
+            passed,
+            failures,
+            num_passed: count(" passed"),
+            num_failed: count(" failed"),
+            num_ignored: count(" ignored"),
+            num_filtered_out: count(" filtered out"),
+        }
+    }
+}
+
+/// What building, linting and testing a candidate showed, focused on what the
+/// candidate is responsible for. [`Self::render`] is the log both the Reviewer and
+/// the Repair agent read.
+#[derive(Debug, Clone, Default)]
+pub struct BuildEvidence {
+    pub check_ok: bool,
+    /// Errors anywhere (the original built, so the candidate caused them) and
+    /// warnings in the touched files, from `verification.check` and the test build.
+    pub diagnostics: Vec<Diagnostic>,
+    /// `None` when the build failed and tests didn't run.
+    pub tests: Option<TestOutcome>,
+    /// Clippy findings the candidate added over the original; `None` if not linted
+    /// (no baseline given, or the build failed).
+    pub new_lints: Option<Vec<Diagnostic>>,
+    /// Inputs on which a refactored function behaves differently from the
+    /// original, from the differential check run on a passing build.
+    pub divergences: Vec<String>,
+    /// Refactored functions the differential check could not compare, with the reason.
+    pub not_compared: Vec<String>,
+    /// Tail of the raw output, kept only when a failure left no diagnostic or
+    /// failing test to show.
+    unexplained: Option<String>,
+}
+
+/// Diagnostics listed per section before the rest is summarised as a count.
+const MAX_LISTED: usize = 15;
+
+/// Lines of raw output kept for a failure nothing could be parsed from.
+const RAW_TAIL_LINES: usize = 40;
+
+impl BuildEvidence {
+    /// Build and test the tree at `repo`, keeping diagnostics relevant to the
+    /// `touched` files. With a `lint_baseline` (clippy on the original), also run
+    /// clippy on a successful build and keep only the findings that are new.
+    pub async fn collect(
+        repo: &Path,
+        cfg: &VerificationConfig,
+        touched: &[&Path],
+        lint_baseline: Option<&[Diagnostic]>,
+    ) -> Result<Self> {
+        let check = run_cargo(repo, &cfg.check, None).await?;
+        let mut diagnostics = check.diagnostics;
+        let (tests, raw) = if check.ok {
+            let test = run_cargo(repo, &cfg.test, None).await?;
+            diagnostics.extend(test.diagnostics);
+            (Some(TestOutcome::parse(test.ok, &test.text)), test.text)
+        } else {
+            (None, check.text)
+        };
+        let mut focused = vec![];
+        for d in diagnostics {
+            if d.level == "error" || d.in_files(touched) {
+                push_diagnostic(d, &mut focused);
+            }
+        }
+
+        let new_lints = match lint_baseline {
+            // Clippy would only repeat the compiler errors.
+            Some(baseline) if check.ok => {
+                let before: Vec<_> = baseline.iter().map(Diagnostic::key).collect();
+                let now = clippy_warnings(repo, cfg).await?;
+                Some(now.into_iter().filter(|d| !before.contains(&d.key())).collect())
+            }
+            _ => None,
+        };
+
+        let mut evidence = Self {
+            check_ok: check.ok,
+            diagnostics: focused,
+            tests,
+            new_lints,
+            divergences: vec![],
+            not_compared: vec![],
+            unexplained: None,
+        };
+        if !evidence.passed() && evidence.failures().is_empty() {
+            let lines: Vec<&str> = raw.lines().collect();
+            let tail = &lines[lines.len().saturating_sub(RAW_TAIL_LINES)..];
+            evidence.unexplained = Some(tail.join("\n"));
+        }
+        Ok(evidence)
+    }
+
+    /// The build compiled, the tests passed and no refactored function diverged.
+    pub fn passed(&self) -> bool {
+        self.check_ok
+            && self.tests.as_ref().is_some_and(|t| t.passed)
+            && self.divergences.is_empty()
+    }
+
+    /// One line per compile error, failing test and divergence, e.g. for a repair log.
+    pub fn failures(&self) -> Vec<String> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.level == "error")
+            .map(Diagnostic::headline)
+            .chain(
+                self.tests
+                    .iter()
+                    .flat_map(|t| &t.failures)
+                    .map(|f| format!("test {f}")),
+            )
+            .chain(self.divergences.iter().map(|d| format!("divergence: {d}")))
+            .collect()
+    }
+
+    pub fn render(&self) -> String {
+        let errors = self.diagnostics.iter().filter(|d| d.level == "error").count();
+        let mut out = format!(
+            "cargo check: {} ({} error(s), {} warning(s))\n",
+            if self.check_ok { "passed" } else { "failed" },
+            errors,
+            self.diagnostics.len() - errors
+        );
+        push_listed(&mut out, &self.diagnostics);
+        match &self.tests {
+            None => out.push_str("tests: not run (build failed)\n"),
+            Some(t) => {
+                out.push_str(&format!(
+                    "tests: {} ({} passed; {} failed; {} ignored; {} filtered out)\n",
+                    if t.passed { "passed" } else { "failed" },
+                    t.num_passed,
+                    t.num_failed,
+                    t.num_ignored,
+                    t.num_filtered_out
+                ));
+                for line in t.failures.iter().take(MAX_LISTED) {
+                    out.push_str(&format!("  {line}\n"));
+                }
+            }
+        }
+        match &self.new_lints {
+            None => {}
+            Some(lints) if lints.is_empty() => {
+                out.push_str("clippy: no new warnings compared to the original\n")
+            }
+            Some(lints) => {
+                out.push_str(&format!(
+                    "clippy: {} new warning(s) compared to the original\n",
+                    lints.len()
+                ));
+                push_listed(&mut out, lints);
+            }
+        }
+        if !self.divergences.is_empty() {
+            out.push_str(&format!(
+                "differential check: {} refactored function(s) behave differently from the original\n",
+                self.divergences.len()
+            ));
+            for d in &self.divergences {
+                out.push_str(&format!("  {d}\n"));
+            }
+        }
+        if !self.not_compared.is_empty() {
+            out.push_str("differential check: not compared (no evidence either way):\n");
+            for f in &self.not_compared {
+                out.push_str(&format!("  {f}\n"));
+            }
+        }
+        if let Some(tail) = &self.unexplained {
+            out.push_str(&format!("no diagnostics were recognised; end of the output:\n{tail}\n"));
+        }
+        out
+    }
+}
+
+fn push_listed(out: &mut String, diagnostics: &[Diagnostic]) {
+    for d in diagnostics.iter().take(MAX_LISTED) {
+        out.push_str(&d.rendered);
+        out.push('\n');
+    }
+    if diagnostics.len() > MAX_LISTED {
+        out.push_str(&format!("  … {} more\n", diagnostics.len() - MAX_LISTED));
+    }
+}
+
+/// Outcome of the local structural check, with a human-readable explanation.
+#[derive(Debug, Clone)]
+pub struct RefactorCheck {
+    pub verified: bool,
+    pub detail: String,
+}
+
+impl RefactorCheck {
+    fn pass(detail: String) -> Self {
+        Self {
+            verified: true,
+            detail,
+        }
+    }
+
+    fn fail(detail: impl Into<String>) -> Self {
+        Self {
+            verified: false,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Check on the parsed item graphs that the structural change expected for
+/// `kind` actually happened between the `original` and `candidate` versions of
+/// the touched files (deleted or not-yet-created files are simply absent).
+pub fn verify_refactor(
+    kind: RefactorKind,
+    original: &BTreeMap<PathBuf, String>,
+    candidate: &BTreeMap<PathBuf, String>,
+) -> RefactorCheck {
+    let before = match ItemGraph::parse_files(original) {
+        Ok(g) => g,
+        Err((path, e)) => {
+            return RefactorCheck::fail(format!("original {} does not parse: {e}", path.display()))
+        }
+    };
+    let after = match ItemGraph::parse_files(candidate) {
+        Ok(g) => g,
+        Err((path, e)) => {
+            return RefactorCheck::fail(format!("candidate {} does not parse: {e}", path.display()))
+        }
+    };
+    let check = match kind {
+        RefactorKind::ExtractMethod => check_extract(&before, &after),
+        RefactorKind::InlineMethod => check_inline(&before, &after),
+        RefactorKind::MoveMethod => check_move(&before, &after),
+        RefactorKind::RenameMethod => check_rename(&before, &after),
+    };
+    if !check.verified {
+        info!("Structural check failed for {:?}: {}", kind, check.detail);
+    }
+    check
 }
 
-pub async fn cargo_check(repo: &Path) -> Result<(bool, String)> {
-    let out = run_cmd(repo, "cargo", &["check"]).await?;
-    let logs = format!("{}\n{}", out.stdout, out.stderr);
-    Ok((out.status == 0, logs))
+fn added<'a>(before: &'a ItemGraph, after: &'a ItemGraph) -> Vec<&'a FnItem> {
+    after
+        .fns
+        .values()
+        .filter(|f| !before.fns.contains_key(&f.key))
+        .collect()
 }
 
-pub async fn cargo_test(repo: &Path) -> Result<(bool, String)> {
-    let out = run_cmd(repo, "cargo", &["test", "--all", "--quiet"]).await?;
-    let logs = format!("{}\n{}", out.stdout, out.stderr);
-    Ok((out.status == 0, logs))
+fn removed<'a>(before: &'a ItemGraph, after: &'a ItemGraph) -> Vec<&'a FnItem> {
+    before
+        .fns
+        .values()
+        .filter(|f| !after.fns.contains_key(&f.key))
+        .collect()
 }
 
-/// Very light heuristic verification per refactor type on the single file.
-pub fn verify_refactor_heuristic(kind: RefactorKind, original: &str, candidate: &str) -> bool {
-    match kind {
-        RefactorKind::ExtractMethod => {
-            // More lenient regex that matches functions anywhere (not just at line start)
-            let re = Regex::new(r"(?m)\bfn\s+\w+\s*\(").unwrap();
-            let orig_count = re.find_iter(original).count();
-            let cand_count = re.find_iter(candidate).count();
-            let n_new = cand_count.saturating_sub(orig_count);
+/// A new fn, now called by a pre-existing fn, that took over statements of that
+/// fn's old body: statements left the caller, they overlap the new body, and none
+/// of the new body's statements copied from the caller is still in it.
+fn check_extract(before: &ItemGraph, after: &ItemGraph) -> RefactorCheck {
+    let new_fns = added(before, after);
+    if new_fns.is_empty() {
+        return RefactorCheck::fail("no new function was introduced");
+    }
+    let mut problems = vec![];
+    for new in &new_fns {
+        for (key, caller) in &after.fns {
+            let Some(old) = before.fns.get(key) else {
+                continue;
+            };
+            let newly_called = caller.calls.contains(&new.key.name) && !old.calls.contains(&new.key.name);
+            if !newly_called {
+                continue;
+            }
+            let left = without(&old.stmts, &caller.stmts);
+            if left.is_empty() {
+                problems.push(format!(
+                    "`{key}` calls `{}` but kept all of its statements",
+                    new.key
+                ));
+                continue;
+            }
+            // Moved as they were, or rewritten (renamed parameters, `self.x` → `x`).
+            let moved_verbatim = left.iter().any(|s| new.stmts.contains(s));
+            if !moved_verbatim && new.idents.is_disjoint(&old.idents) {
+                problems.push(format!(
+                    "`{}` shares no code with the statements removed from `{key}`",
+                    new.key
+                ));
+                continue;
+            }
+            let count = |stmts: &[String], s: &String| stmts.iter().filter(|t| *t == s).count();
+            let duplicated: Vec<&String> = new
+                .stmts
+                .iter()
+                .filter(|s| {
+                    let was = count(&old.stmts, s);
+                    was > 0 && count(&caller.stmts, s) >= was
+                })
+                .collect();
+            if let Some(dup) = duplicated.first() {
+                problems.push(format!(
+                    "`{}` copies `{dup}` from `{key}`, which still contains it",
+                    new.key
+                ));
+                continue;
+            }
+            return RefactorCheck::pass(format!("`{}` extracted from `{key}`", new.key));
+        }
+    }
+    if problems.is_empty() {
+        let names: Vec<String> = new_fns.iter().map(|f| format!("`{}`", f.key)).collect();
+        problems.push(format!(
+            "new function(s) {} are not called from the code they were extracted from",
+            names.join(", ")
+        ));
+    }
+    RefactorCheck::fail(problems.join("; "))
+}
 
-            info!("Failed to verify refactor: {:?}, number of functions did not change after refactoring (from {} to {}).", kind, orig_count, cand_count);
+/// Statements of `before` that are not in `after`, counting repeats.
+fn without(before: &[String], after: &[String]) -> Vec<String> {
+    let mut rest: Vec<&String> = after.iter().collect();
+    before
+        .iter()
+        .filter(|s| match rest.iter().position(|r| r == s) {
+            Some(i) => {
+                rest.swap_remove(i);
+                false
+            }
+            None => true,
+        })
+        .cloned()
+        .collect()
+}
 
-            // For extract method: should have at least one new function
-            // Remove the strict length requirement since refactoring might make code shorter
-            n_new > 0
+/// A removed fn that had callers, none of which (nor any other code) still calls it.
+fn check_inline(before: &ItemGraph, after: &ItemGraph) -> RefactorCheck {
+    let gone = removed(before, after);
+    if gone.is_empty() {
+        return RefactorCheck::fail("no function was removed");
+    }
+    let mut problems = vec![];
+    for f in &gone {
+        let name = &f.key.name;
+        let callers: Vec<_> = before
+            .fns
+            .values()
+            .filter(|c| c.calls.contains(name) && after.fns.contains_key(&c.key))
+            .collect();
+        if callers.is_empty() {
+            problems.push(format!("`{}` was removed but had no call sites to inline", f.key));
+            continue;
+        }
+        let mut remaining: Vec<String> = after
+            .fns
+            .values()
+            .flat_map(|c| {
+                c.call_paths
+                    .iter()
+                    .filter(|p| may_call(p, f, &c.key))
+                    .map(|p| format!("`{p}(..)` in `{}`", c.key))
+            })
+            .collect();
+        // `.name(..)` can't be told apart from a method of the same name on another
+        // type, so a former caller must make fewer such calls than it did.
+        if f.key.container.contains("impl ") {
+            let method = format!(".{name}");
+            for old in &callers {
+                let count = |item: &FnItem| item.call_paths.iter().filter(|p| **p == method).count();
+                let was = count(old);
+                if was > 0 && after.fns.get(&old.key).is_some_and(|c| count(c) >= was) {
+                    remaining.push(format!("`{method}(..)` in `{}`", old.key));
+                }
+            }
         }
-        RefactorKind::InlineMethod => {
-            let re = Regex::new(r"(?m)\bfn\s+\w+\s*\(").unwrap();
-            re.find_iter(candidate).count() < re.find_iter(original).count()
+        if remaining.is_empty() {
+            return RefactorCheck::pass(format!(
+                "`{}` inlined into {} caller(s)",
+                f.key,
+                callers.len()
+            ));
         }
-        RefactorKind::MoveMethod => candidate.len() + 20 < original.len(),
-        RefactorKind::RenameMethod => original != candidate,
+        problems.push(format!(
+            "`{}` was removed but is still called: {}",
+            f.key,
+            remaining.join(", ")
+        ));
+    }
+    RefactorCheck::fail(problems.join("; "))
+}
+
+/// Whether the call `path` (see [`FnItem::call_paths`]), made from `caller`, can
+/// reach the removed `f`. Method calls are handled by counting instead.
+fn may_call(path: &str, f: &FnItem, caller: &FnKey) -> bool {
+    if path.starts_with('.') {
+        return false;
+    }
+    let (qualifier, last) = match path.rsplit_once("::") {
+        Some((q, l)) => (Some(q), l),
+        None => (None, path),
+    };
+    if last != f.key.name {
+        return false;
+    }
+    // `src/a.rs::impl Foo` or `src/a.rs::impl fmt::Display for Foo`: the type is the last word.
+    let container = &f.key.container;
+    let owner = container
+        .rfind("impl ")
+        .and_then(|i| container[i..].rsplit(' ').next())
+        .map(|ty| ty.split('<').next().unwrap_or(ty))
+        .map(|ty| ty.rsplit("::").next().unwrap_or(ty));
+    match (owner, qualifier) {
+        // A free fn: called bare or through a module path.
+        (None, None) => true,
+        (None, Some(q)) => q.rsplit("::").next().is_some_and(|m| {
+            matches!(m, "crate" | "self" | "super") || m.starts_with(char::is_lowercase)
+        }),
+        (Some(_), None) => false,
+        (Some(_), Some("Self")) => caller.container == f.key.container,
+        (Some(owner), Some(q)) => q.rsplit("::").next() == Some(owner),
+    }
+}
+/// A fn that left its impl/module and reappears, with a similar body, in another one.
+fn check_move(before: &ItemGraph, after: &ItemGraph) -> RefactorCheck {
+    let gone = removed(before, after);
+    if gone.is_empty() {
+        return RefactorCheck::fail("no function left its impl or module");
+    }
+    for f in &gone {
+        for moved in after.fns_named(&f.key.name) {
+            if before.fns.contains_key(&moved.key) {
+                continue;
+            }
+            // A body without identifiers (`{}`, `{ 42 }`) shares none; it must match.
+            let shared = f.idents.intersection(&moved.idents).count();
+            let same = if f.idents.is_empty() {
+                f.body == moved.body
+            } else {
+                shared * 2 >= f.idents.len()
+            };
+            if same {
+                return RefactorCheck::pass(format!(
+                    "`{}` moved from `{}` to `{}`",
+                    f.key.name, f.key.container, moved.key.container
+                ));
+            }
+        }
+    }
+    let names: Vec<String> = gone.iter().map(|f| format!("`{}`", f.key)).collect();
+    RefactorCheck::fail(format!(
+        "{} no longer defined and not found in another impl, module or touched file",
+        names.join(", ")
+    ))
+}
+
+/// Removed/added fn pairs in the same container, with every other token of the
+/// file unchanged once the new names are mapped back.
+fn check_rename(before: &ItemGraph, after: &ItemGraph) -> RefactorCheck {
+    let gone = removed(before, after);
+    let new_fns = added(before, after);
+    let mut renames = BTreeMap::new();
+    for old in &gone {
+        let mut same_place = new_fns
+            .iter()
+            .filter(|n| n.key.container == old.key.container);
+        if let (Some(new), None) = (same_place.next(), same_place.next()) {
+            renames.insert(old.key.name.clone(), new.key.name.clone());
+        }
+    }
+    if renames.is_empty() {
+        return RefactorCheck::fail("no function was renamed");
+    }
+    let leftovers: Vec<String> = renames
+        .keys()
+        .filter(|old| after.ident_count(old) > 0)
+        .map(|old| format!("`{old}`"))
+        .collect();
+    if !leftovers.is_empty() {
+        return RefactorCheck::fail(format!(
+            "old name(s) {} still referenced after the rename",
+            leftovers.join(", ")
+        ));
+    }
+    let summary: Vec<String> = renames.iter().map(|(o, n)| format!("`{o}` → `{n}`")).collect();
+    if before.tokens_renamed(&renames) != after.tokens() {
+        return RefactorCheck::fail(format!(
+            "renamed {} but other code changed as well",
+            summary.join(", ")
+        ));
+    }
+    RefactorCheck::pass(format!("renamed {} at every definition and use", summary.join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const ORIGINAL: &str = "
+struct Calc;
+impl Calc {
+    fn run(&self, items: &[u32]) -> u32 {
+        let mut sum = 0;
+        for x in items {
+            if x % 2 == 0 {
+                sum += x * 2;
+            }
+        }
+        println!(\"{sum}\");
+        sum
+    }
+    fn double(&self, x: u32) -> u32 {
+        x * 2
+    }
+    fn twice(&self, x: u32) -> u32 {
+        self.double(x) + self.double(1)
+    }
+}
+fn helper(x: u32) -> u32 {
+    x + 1
+}
+fn main() {
+    println!(\"{}\", helper(2));
+}
+";
+
+    fn check(kind: RefactorKind, candidate: &str) -> RefactorCheck {
+        let path = PathBuf::from("src/main.rs");
+        let before = BTreeMap::from([(path.clone(), ORIGINAL.to_string())]);
+        let after = BTreeMap::from([(path, candidate.to_string())]);
+        verify_refactor(kind, &before, &after)
+    }
+
+    #[test]
+    fn extract_moves_statements_into_a_called_fn() {
+        let candidate = ORIGINAL.replace(
+            "        let mut sum = 0;
+        for x in items {
+            if x % 2 == 0 {
+                sum += x * 2;
+            }
+        }
+",
+            "        let sum = Self::sum_even(items);
+",
+        ) + "
+impl Calc {
+    fn sum_even(items: &[u32]) -> u32 {
+        let mut sum = 0;
+        for x in items {
+            if x % 2 == 0 {
+                sum += x * 2;
+            }
+        }
+        sum
+    }
+}
+";
+        let result = check(RefactorKind::ExtractMethod, &candidate);
+        assert!(result.verified, "{}", result.detail);
+    }
+
+    #[test]
+    fn extract_fails_when_the_statements_stay_behind() {
+        let candidate = ORIGINAL.replace(
+            "        println!(\"{sum}\");",
+            "        println!(\"{sum}\");
+        Self::sum_even(items);",
+        ) + "
+impl Calc {
+    fn sum_even(items: &[u32]) -> u32 {
+        let mut sum = 0;
+        for x in items {
+            if x % 2 == 0 {
+                sum += x * 2;
+            }
+        }
+        sum
+    }
+}
+";
+        let result = check(RefactorKind::ExtractMethod, &candidate);
+        assert!(!result.verified);
+        assert_eq!(
+            result.detail,
+            "`src/main.rs::impl Calc::run` calls `src/main.rs::impl Calc::sum_even` but kept all of its statements"
+        );
+    }
+
+    #[test]
+    fn inline_removes_every_call() {
+        let candidate = ORIGINAL
+            .replace("fn helper(x: u32) -> u32 {\n    x + 1\n}\n", "")
+            .replace("helper(2)", "2 + 1");
+        let result = check(RefactorKind::InlineMethod, &candidate);
+        assert!(result.verified, "{}", result.detail);
+    }
+
+    #[test]
+    fn inline_fails_while_the_removed_fn_is_still_called() {
+        // A method of the same name survives, but `main` still calls the free fn.
+        let candidate = ORIGINAL
+            .replace("fn helper(x: u32) -> u32 {\n    x + 1\n}\n", "")
+            .replace(
+                "    fn double(&self",
+                "    fn helper(&self) -> u32 {\n        1\n    }\n    fn double(&self",
+            );
+        let result = check(RefactorKind::InlineMethod, &candidate);
+        assert!(!result.verified);
+        assert!(
+            result.detail.contains("`helper(..)` in `src/main.rs::main`"),
+            "{}",
+            result.detail
+        );
+
+        let candidate = ORIGINAL.replace(
+            "    fn double(&self, x: u32) -> u32 {\n        x * 2\n    }\n",
+            "",
+        );
+        let result = check(RefactorKind::InlineMethod, &candidate);
+        assert!(!result.verified);
+        assert!(result.detail.contains("`.double(..)`"), "{}", result.detail);
+    }
+
+    #[test]
+    fn reads_json_message_with_suggestion() {
+        let line = r#"{"reason":"compiler-message","message":{"message":"unused variable: `x`","code":{"code":"unused_variables"},"level":"warning","spans":[{"file_name":"app/src/main.rs","byte_start":20,"byte_end":21,"line_start":2,"column_start":9,"is_primary":true,"suggested_replacement":null,"suggestion_applicability":null}],"children":[{"message":"prefix it with an underscore","code":null,"level":"help","spans":[{"file_name":"app/src/main.rs","byte_start":20,"byte_end":21,"line_start":2,"column_start":9,"is_primary":true,"suggested_replacement":"_x","suggestion_applicability":"MachineApplicable"}],"children":[],"rendered":null}],"rendered":"warning: unused variable: `x`\n"}}"#;
+        let msg: CargoMessage = serde_json::from_str(line).unwrap();
+        let mut d = msg.message.unwrap().into_diagnostic();
+        assert_eq!(
+            d.headline(),
+            "warning[unused_variables]: unused variable: `x` at app/src/main.rs:2:9"
+        );
+        assert_eq!(d.rendered, "warning: unused variable: `x`");
+        assert_eq!(d.suggestions.len(), 1);
+        assert_eq!(d.suggestions[0].applicability.as_deref(), Some("MachineApplicable"));
+
+        // Spans are relative to the workspace root; the repo is a member of it.
+        d.rebase(Path::new("/ws"), Path::new("/ws/app"));
+        assert!(d.in_files(&[Path::new("src/main.rs")]));
+        assert_eq!(
+            d.suggestions[0].edits,
+            vec![Replacement {
+                file: PathBuf::from("src/main.rs"),
+                byte_start: 20,
+                byte_end: 21,
+                text: "_x".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn rebase_keeps_files_outside_the_repo_absolute() {
+        let mut d = text_diagnostic(&["warning: unused import", "  --> other/src/lib.rs:1:5"]);
+        d.rebase(Path::new("/ws"), Path::new("/ws/app"));
+        assert_eq!(d.span.unwrap().file, PathBuf::from("/ws/other/src/lib.rs"));
+    }
+
+    #[test]
+    fn parses_text_diagnostics_without_summaries() {
+        let output = "\
+error[E0425]: cannot find value `y` in this scope
+ --> src/main.rs:3:13
+  |
+3 |     let x = y;
+  |             ^ not found in this scope
+
+warning: unused variable: `x`
+ --> src/main.rs:3:9
+
+error: aborting due to 1 previous error
+error: could not compile `app` (bin \"app\") due to 1 previous error
+";
+        let ds = parse_diagnostics(output);
+        let headlines: Vec<String> = ds.iter().map(Diagnostic::headline).collect();
+        assert_eq!(
+            headlines,
+            vec![
+                "error[E0425]: cannot find value `y` in this scope at src/main.rs:3:13",
+                "warning: unused variable: `x` at src/main.rs:3:9",
+            ]
+        );
+        assert!(ds[0].rendered.ends_with("^ not found in this scope"));
+    }
+
+    #[test]
+    fn test_evidence_leaves_out_timings() {
+        let output = |secs: &str| {
+            format!(
+                "running 3 tests\n\
+                 test a ... ok\n\
+                 test b ... FAILED\n\
+                 \n\
+                 ---- b stdout ----\n\
+                 \n\
+                 thread 'b' panicked at src/lib.rs:9:5:\n\
+                 assertion failed: x\n\
+                 \n\
+                 test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 2 filtered out; finished in {secs}s\n"
+            )
+        };
+        let render = |secs: &str| {
+            BuildEvidence {
+                tests: Some(TestOutcome::parse(false, &output(secs))),
+                ..Default::default()
+            }
+            .render()
+        };
+        let rendered = render("0.01");
+        assert_eq!(rendered, render("12.34"));
+        assert!(!rendered.contains("finished in"), "{rendered}");
+        assert!(
+            rendered.contains("tests: failed (1 passed; 1 failed; 1 ignored; 2 filtered out)"),
+            "{rendered}"
+        );
+        assert!(rendered.contains("b: thread 'b' panicked"), "{rendered}");
+    }
+
+    #[test]
+    fn move_of_an_empty_fn_needs_the_same_body() {
+        let before = "struct A;\nstruct B;\nimpl A {\n    fn noop(&self) {}\n}\nimpl B {}\n";
+        let check = |after: &str| {
+            let path = PathBuf::from("src/lib.rs");
+            verify_refactor(
+                RefactorKind::MoveMethod,
+                &BTreeMap::from([(path.clone(), before.to_string())]),
+                &BTreeMap::from([(path, after.to_string())]),
+            )
+        };
+        let moved = check("struct A;\nstruct B;\nimpl A {}\nimpl B {\n    fn noop(&self) {}\n}\n");
+        assert!(moved.verified, "{}", moved.detail);
+        let other = check(
+            "struct A;\nstruct B;\nimpl A {}\nimpl B {\n    fn noop(&self) -> u8 {\n        B::ID\n    }\n}\n",
+        );
+        assert!(!other.verified, "{}", other.detail);
     }
 }